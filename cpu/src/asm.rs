@@ -0,0 +1,677 @@
+use crate::instruction::Instruction;
+use crate::parse;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+// A two pass assembler for the mnemonics in the opcode comments in cpu.rs, producing a ROM that
+// Cpu::load_game can run. The first pass lays out every statement and records where the labels
+// land, the second encodes the instructions now that every label has an address.
+//
+//     ; comments run to the end of the line
+//     SPEED EQU 2               ; constants
+//     start:                    ; labels
+//         LD V0, SPEED
+//         LD I, sprite
+//         DRW V0, V1, 5
+//         JP start
+//     sprite:
+//         DB 0xF0, 0x90, 0xF0   ; data, DW for 16 bit words
+//     INCLUDE "more.asm"        ; other files, relative to this one
+//     ORG 0x300                 ; move on to an address, the gap is filled with zeros
+//
+// Mnemonics and registers are not case sensitive, labels and constants are.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}: {}", self.file, self.line, self.column, self.message)
+    }
+}
+
+impl Error for AsmError {}
+
+// assemble source for a ROM loaded at origin, includes are found relative to the working directory
+pub fn assemble(source: &str, origin: u16) -> Result<Vec<u8>, AsmError> {
+    let mut lines = Vec::new();
+    read_lines(source, Rc::new(String::from("<source>")), Path::new("."), &mut lines, 0)?;
+    Assembler::new(origin).run(&lines)
+}
+
+// assemble a file for a ROM loaded at origin, includes are found relative to the file
+pub fn assemble_file<P: AsRef<Path>>(path: P, origin: u16) -> Result<Vec<u8>, AsmError> {
+    let path = path.as_ref();
+    let name = Rc::new(path.display().to_string());
+    let source = fs::read_to_string(path).map_err(|e| AsmError {
+        file: name.to_string(),
+        line: 0,
+        column: 0,
+        message: e.to_string(),
+    })?;
+    let mut lines = Vec::new();
+    read_lines(&source, name, &base_dir(path), &mut lines, 0)?;
+    Assembler::new(origin).run(&lines)
+}
+
+fn base_dir(path: &Path) -> PathBuf {
+    path.parent().map_or_else(|| PathBuf::from("."), Path::to_path_buf)
+}
+
+// deep enough for any real program, shallow enough to catch a file including itself
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+struct SourceLine {
+    file: Rc<String>,
+    number: usize,
+    tokens: Vec<Token>,
+}
+
+impl SourceLine {
+    fn error(&self, column: usize, message: String) -> AsmError {
+        AsmError {
+            file: self.file.to_string(),
+            line: self.number,
+            column,
+            message,
+        }
+    }
+}
+
+// tokenize source, splicing INCLUDEd files in where they are named
+fn read_lines(
+    source: &str,
+    file: Rc<String>,
+    dir: &Path,
+    lines: &mut Vec<SourceLine>,
+    depth: usize,
+) -> Result<(), AsmError> {
+    for (index, text) in source.lines().enumerate() {
+        let line = SourceLine {
+            file: file.clone(),
+            number: index + 1,
+            tokens: tokenize(text).map_err(|(column, message)| AsmError {
+                file: file.to_string(),
+                line: index + 1,
+                column,
+                message,
+            })?,
+        };
+
+        let include = match line.tokens.first() {
+            Some(Token { kind: TokenKind::Ident(name), .. }) => name.eq_ignore_ascii_case("INCLUDE"),
+            _ => false,
+        };
+        if !include {
+            lines.push(line);
+            continue;
+        }
+
+        let name = match line.tokens.get(1) {
+            Some(Token { kind: TokenKind::Str(name), .. }) if line.tokens.len() == 2 => name.clone(),
+            _ => return Err(line.error(line.tokens[0].column, String::from("INCLUDE expects a quoted file name"))),
+        };
+        if depth >= MAX_INCLUDE_DEPTH {
+            return Err(line.error(line.tokens[1].column, String::from("includes are nested too deeply")));
+        }
+        let path = dir.join(&name);
+        let included = fs::read_to_string(&path)
+            .map_err(|e| line.error(line.tokens[1].column, format!("could not include {}: {}", name, e)))?;
+        let included_name = Rc::new(path.display().to_string());
+        read_lines(&included, included_name, &base_dir(&path), lines, depth + 1)?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TokenKind {
+    Ident(String),
+    Number(i64),
+    Str(String),
+    Comma,
+    Colon,
+    OpenBracket,
+    CloseBracket,
+    Plus,
+    Minus,
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    kind: TokenKind,
+    // 1 based, for error messages
+    column: usize,
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, (usize, String)> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut position = 0;
+    while position < chars.len() {
+        let c = chars[position];
+        let column = position + 1;
+        let single = match c {
+            ',' => Some(TokenKind::Comma),
+            ':' => Some(TokenKind::Colon),
+            '[' => Some(TokenKind::OpenBracket),
+            ']' => Some(TokenKind::CloseBracket),
+            '+' => Some(TokenKind::Plus),
+            '-' => Some(TokenKind::Minus),
+            _ => None,
+        };
+        if let Some(kind) = single {
+            tokens.push(Token { kind, column });
+            position += 1;
+        } else if c == ';' {
+            break;
+        } else if c.is_whitespace() {
+            position += 1;
+        } else if c == '"' {
+            let end = chars[position + 1..]
+                .iter()
+                .position(|c| *c == '"')
+                .ok_or((column, String::from("unterminated string")))?;
+            let value: String = chars[position + 1..position + 1 + end].iter().collect();
+            tokens.push(Token { kind: TokenKind::Str(value), column });
+            position += end + 2;
+        } else if c.is_ascii_alphanumeric() || c == '_' || c == '.' {
+            let len = chars[position..]
+                .iter()
+                .take_while(|c| c.is_ascii_alphanumeric() || **c == '_' || **c == '.')
+                .count();
+            let word: String = chars[position..position + len].iter().collect();
+            let kind = if c.is_ascii_digit() {
+                TokenKind::Number(parse::literal(&word).ok_or((column, format!("invalid number '{}'", word)))?)
+            } else {
+                TokenKind::Ident(word)
+            };
+            tokens.push(Token { kind, column });
+            position += len;
+        } else {
+            return Err((column, format!("unexpected character '{}'", c)));
+        }
+    }
+    Ok(tokens)
+}
+
+// a sum of numbers and symbols, e.g. sprite + 5
+#[derive(Debug, Clone)]
+struct Expr {
+    terms: Vec<(i64, Term)>,
+    column: usize,
+}
+
+#[derive(Debug, Clone)]
+enum Term {
+    Number(i64, usize),
+    Symbol(String, usize),
+}
+
+#[derive(Debug, Clone)]
+enum Operand {
+    Register(u16),
+    I,
+    IndirectI,
+    Dt,
+    St,
+    K,
+    F,
+    Hf,
+    B,
+    R,
+    Long(Expr),
+    Value(Expr),
+    Str(String),
+}
+
+fn parse_expr(tokens: &[Token], line: &SourceLine) -> Result<Expr, AsmError> {
+    let column = tokens.first().map_or(0, |token| token.column);
+    let mut terms = Vec::new();
+    let mut sign = 1;
+    let mut expect_term = true;
+    for token in tokens {
+        match (&token.kind, expect_term) {
+            (TokenKind::Minus, true) => sign = -sign,
+            (TokenKind::Plus, true) => {}
+            (TokenKind::Number(value), true) => {
+                terms.push((sign, Term::Number(*value, token.column)));
+                expect_term = false;
+            }
+            (TokenKind::Ident(name), true) => {
+                terms.push((sign, Term::Symbol(name.clone(), token.column)));
+                expect_term = false;
+            }
+            (TokenKind::Plus, false) => {
+                sign = 1;
+                expect_term = true;
+            }
+            (TokenKind::Minus, false) => {
+                sign = -1;
+                expect_term = true;
+            }
+            _ => return Err(line.error(token.column, String::from("expected a number or a label"))),
+        }
+    }
+    if terms.is_empty() || expect_term {
+        let column = tokens.last().map_or(column, |token| token.column);
+        return Err(line.error(column, String::from("expected a number or a label")));
+    }
+    Ok(Expr { terms, column })
+}
+
+fn parse_operand(tokens: &[Token], line: &SourceLine) -> Result<Operand, AsmError> {
+    let column = tokens.first().map_or(0, |token| token.column);
+    let kinds: Vec<&TokenKind> = tokens.iter().map(|token| &token.kind).collect();
+    match kinds.as_slice() {
+        [] => Err(line.error(column, String::from("missing operand"))),
+        [TokenKind::Str(value)] => Ok(Operand::Str(value.clone())),
+        [TokenKind::OpenBracket, TokenKind::Ident(name), TokenKind::CloseBracket] if name.eq_ignore_ascii_case("I") => {
+            Ok(Operand::IndirectI)
+        }
+        [TokenKind::Ident(name)] if parse::register(name).is_some() => {
+            Ok(Operand::Register(parse::register(name).map_or(0, u16::from)))
+        }
+        [TokenKind::Ident(name)] => match name.to_ascii_uppercase().as_str() {
+            "I" => Ok(Operand::I),
+            "DT" => Ok(Operand::Dt),
+            "ST" => Ok(Operand::St),
+            "K" => Ok(Operand::K),
+            "F" => Ok(Operand::F),
+            "HF" => Ok(Operand::Hf),
+            "B" => Ok(Operand::B),
+            "R" => Ok(Operand::R),
+            _ => Ok(Operand::Value(parse_expr(tokens, line)?)),
+        },
+        [TokenKind::Ident(name), ..] if name.eq_ignore_ascii_case("LONG") => {
+            Ok(Operand::Long(parse_expr(&tokens[1..], line)?))
+        }
+        _ => Ok(Operand::Value(parse_expr(tokens, line)?)),
+    }
+}
+
+enum Statement {
+    Instruction { mnemonic: String, column: usize, operands: Vec<Operand> },
+    Bytes { column: usize, operands: Vec<Operand> },
+    Words { column: usize, operands: Vec<Operand> },
+}
+
+struct Assembler {
+    origin: u16,
+    labels: HashMap<String, i64>,
+    constants: HashMap<String, Expr>,
+}
+
+impl Assembler {
+    fn new(origin: u16) -> Assembler {
+        Assembler {
+            origin,
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+        }
+    }
+
+    fn run(mut self, lines: &[SourceLine]) -> Result<Vec<u8>, AsmError> {
+        // first pass: lay out the statements and give every label an address
+        let mut statements = Vec::new();
+        let mut address = self.origin as i64;
+        for line in lines {
+            let mut tokens = &line.tokens[..];
+
+            if let [Token { kind: TokenKind::Ident(name), column }, Token { kind: TokenKind::Colon, .. }, ..] = tokens {
+                self.define(name, *column, line)?;
+                self.labels.insert(name.clone(), address);
+                tokens = &tokens[2..];
+            }
+
+            let (name, column) = match tokens.first() {
+                Some(Token { kind: TokenKind::Ident(name), column }) => (name.clone(), *column),
+                Some(token) => return Err(line.error(token.column, String::from("expected an instruction"))),
+                None => continue,
+            };
+
+            if let Some(Token { kind: TokenKind::Ident(equ), .. }) = tokens.get(1) {
+                if equ.eq_ignore_ascii_case("EQU") {
+                    self.define(&name, column, line)?;
+                    let value = parse_expr(&tokens[2..], line)?;
+                    self.constants.insert(name, value);
+                    continue;
+                }
+            }
+
+            let operands = split_operands(&tokens[1..])
+                .iter()
+                .map(|operand| parse_operand(operand, line))
+                .collect::<Result<Vec<Operand>, AsmError>>()?;
+            let statement = match name.to_ascii_uppercase().as_str() {
+                "ORG" => {
+                    let target = match operands.as_slice() {
+                        [Operand::Value(expr)] => self.evaluate(expr, line, 0)?,
+                        _ => return Err(line.error(column, String::from("ORG expects an address"))),
+                    };
+                    if target < address {
+                        return Err(line.error(column, format!("ORG can not move back to {:#05X}", target)));
+                    }
+                    if target > 0x10000 {
+                        return Err(line.error(column, format!("ORG {:#X} is past the end of memory", target)));
+                    }
+                    address = target;
+                    continue;
+                }
+                "DB" => Statement::Bytes { column, operands },
+                "DW" => Statement::Words { column, operands },
+                _ => Statement::Instruction { mnemonic: name.to_ascii_uppercase(), column, operands },
+            };
+            let start = address;
+            address += statement_size(&statement);
+            if address > 0x10000 {
+                return Err(line.error(column, String::from("program runs past the end of memory")));
+            }
+            statements.push((line, start, statement));
+        }
+
+        // second pass: encode everything now that all labels are known
+        let mut rom = vec![0u8; (address - self.origin as i64).max(0) as usize];
+        for (line, start, statement) in statements {
+            let bytes = self.encode(&statement, line)?;
+            let offset = (start - self.origin as i64) as usize;
+            rom[offset..offset + bytes.len()].copy_from_slice(&bytes);
+        }
+        Ok(rom)
+    }
+
+    fn define(&self, name: &str, column: usize, line: &SourceLine) -> Result<(), AsmError> {
+        if parse::register(name).is_some() || RESERVED.iter().any(|word| word.eq_ignore_ascii_case(name)) {
+            return Err(line.error(column, format!("'{}' is a reserved word", name)));
+        }
+        if self.labels.contains_key(name) || self.constants.contains_key(name) {
+            return Err(line.error(column, format!("'{}' is already defined", name)));
+        }
+        Ok(())
+    }
+
+    fn evaluate(&self, expr: &Expr, line: &SourceLine, depth: usize) -> Result<i64, AsmError> {
+        let mut total: i64 = 0;
+        for (sign, term) in &expr.terms {
+            let (value, column) = match term {
+                Term::Number(value, column) => (*value, column),
+                Term::Symbol(name, column) => {
+                    let value = if let Some(address) = self.labels.get(name) {
+                        *address
+                    } else if let Some(constant) = self.constants.get(name) {
+                        if depth > MAX_INCLUDE_DEPTH {
+                            return Err(line.error(*column, format!("'{}' is defined in terms of itself", name)));
+                        }
+                        self.evaluate(constant, line, depth + 1)?
+                    } else {
+                        return Err(line.error(*column, format!("undefined label or constant '{}'", name)));
+                    };
+                    (value, column)
+                }
+            };
+            total = value
+                .checked_mul(*sign)
+                .and_then(|value| total.checked_add(value))
+                .ok_or_else(|| line.error(*column, String::from("the expression overflows")))?;
+        }
+        Ok(total)
+    }
+
+    // evaluate expr and check it fits in bits bits, negative bytes are allowed as two's complement
+    fn value(&self, expr: &Expr, bits: u32, line: &SourceLine) -> Result<u16, AsmError> {
+        let value = self.evaluate(expr, line, 0)?;
+        let max = (1i64 << bits) - 1;
+        let min = if bits == 8 { -128 } else { 0 };
+        if value < min || value > max {
+            return Err(line.error(expr.column, format!("{} does not fit in {} bits", value, bits)));
+        }
+        Ok((value & max) as u16)
+    }
+
+    fn encode(&self, statement: &Statement, line: &SourceLine) -> Result<Vec<u8>, AsmError> {
+        match statement {
+            Statement::Bytes { column, operands } => {
+                let mut bytes = Vec::new();
+                for operand in operands {
+                    match operand {
+                        Operand::Str(text) => bytes.extend_from_slice(text.as_bytes()),
+                        Operand::Value(expr) => bytes.push(self.value(expr, 8, line)? as u8),
+                        _ => return Err(line.error(*column, String::from("DB expects numbers or strings"))),
+                    }
+                }
+                Ok(bytes)
+            }
+            Statement::Words { column, operands } => {
+                let mut bytes = Vec::new();
+                for operand in operands {
+                    match operand {
+                        Operand::Value(expr) => bytes.extend_from_slice(&self.value(expr, 16, line)?.to_be_bytes()),
+                        _ => return Err(line.error(*column, String::from("DW expects numbers"))),
+                    }
+                }
+                Ok(bytes)
+            }
+            Statement::Instruction { mnemonic, column, operands } => {
+                if let [Operand::I, Operand::Long(expr)] = operands.as_slice() {
+                    if mnemonic == "LD" {
+                        let address = self.value(expr, 16, line)?.to_be_bytes();
+                        return Ok(vec![0xF0, 0x00, address[0], address[1]]);
+                    }
+                }
+                if !MNEMONICS.contains(&mnemonic.as_str()) {
+                    return Err(line.error(*column, format!("unknown instruction '{}'", mnemonic)));
+                }
+                let opcode = self.encode_instruction(mnemonic, operands, line).map_err(|error| {
+                    error.unwrap_or_else(|| line.error(*column, format!("invalid operands for {}", mnemonic)))
+                })?;
+                Ok(opcode.to_be_bytes().to_vec())
+            }
+        }
+    }
+
+    // Err(None) is a generic operand error, Err(Some) a specific one
+    fn encode_instruction(&self, mnemonic: &str, operands: &[Operand], line: &SourceLine) -> Result<u16, Option<AsmError>> {
+        use Instruction::*;
+        use Operand::*;
+        let value = |expr: &Expr, bits: u32| self.value(expr, bits, line).map_err(Some);
+        let byte = |expr: &Expr| value(expr, 8).map(|byte| byte as u8);
+        let nibble = |expr: &Expr| value(expr, 4).map(|n| n as u8);
+        let reg = |x: &u16| *x as u8;
+
+        let instruction = match (mnemonic, operands) {
+            ("CLS", []) => Clear,
+            ("RET", []) => Return,
+            ("SCR", []) => ScrollRight,
+            ("SCL", []) => ScrollLeft,
+            ("EXIT", []) => Exit,
+            ("LOW", []) => LowRes,
+            ("HIGH", []) => HighRes,
+            ("AUDIO", []) => Audio,
+            ("SCD", [Value(n)]) => ScrollDown(nibble(n)?),
+            ("SCU", [Value(n)]) => ScrollUp(nibble(n)?),
+            ("SYS", [Value(addr)]) => System(value(addr, 12)?),
+            ("JP", [Value(addr)]) => Jump(value(addr, 12)?),
+            ("JP", [Register(0), Value(addr)]) => JumpOffset(value(addr, 12)?),
+            ("CALL", [Value(addr)]) => Call(value(addr, 12)?),
+            ("SE", [Register(x), Value(b)]) => SkipIfEqual { x: reg(x), byte: byte(b)? },
+            ("SNE", [Register(x), Value(b)]) => SkipIfNotEqual { x: reg(x), byte: byte(b)? },
+            ("SE", [Register(x), Register(y)]) => SkipIfRegistersEqual { x: reg(x), y: reg(y) },
+            ("SAVE", [Register(x), Register(y)]) => SaveRange { x: reg(x), y: reg(y) },
+            ("LOAD", [Register(x), Register(y)]) => LoadRange { x: reg(x), y: reg(y) },
+            ("LD", [Register(x), Value(b)]) => Load { x: reg(x), byte: byte(b)? },
+            ("ADD", [Register(x), Value(b)]) => AddByte { x: reg(x), byte: byte(b)? },
+            ("LD", [Register(x), Register(y)]) => Move { x: reg(x), y: reg(y) },
+            ("OR", [Register(x), Register(y)]) => Or { x: reg(x), y: reg(y) },
+            ("AND", [Register(x), Register(y)]) => And { x: reg(x), y: reg(y) },
+            ("XOR", [Register(x), Register(y)]) => Xor { x: reg(x), y: reg(y) },
+            ("ADD", [Register(x), Register(y)]) => Add { x: reg(x), y: reg(y) },
+            ("SUB", [Register(x), Register(y)]) => Sub { x: reg(x), y: reg(y) },
+            // without Vy, shift Vx into itself so the shift quirk makes no difference
+            ("SHR", [Register(x)]) => ShiftRight { x: reg(x), y: reg(x) },
+            ("SHR", [Register(x), Register(y)]) => ShiftRight { x: reg(x), y: reg(y) },
+            ("SUBN", [Register(x), Register(y)]) => SubFrom { x: reg(x), y: reg(y) },
+            ("SHL", [Register(x)]) => ShiftLeft { x: reg(x), y: reg(x) },
+            ("SHL", [Register(x), Register(y)]) => ShiftLeft { x: reg(x), y: reg(y) },
+            ("SNE", [Register(x), Register(y)]) => SkipIfRegistersNotEqual { x: reg(x), y: reg(y) },
+            ("LD", [I, Value(addr)]) => LoadI(value(addr, 12)?),
+            ("RND", [Register(x), Value(b)]) => Random { x: reg(x), byte: byte(b)? },
+            ("DRW", [Register(x), Register(y), Value(n)]) => Draw { x: reg(x), y: reg(y), n: nibble(n)? },
+            ("SKP", [Register(x)]) => SkipIfKey(reg(x)),
+            ("SKNP", [Register(x)]) => SkipIfNotKey(reg(x)),
+            ("PLANE", [Value(n)]) => Plane(value(n, 2)? as u8),
+            ("LD", [Register(x), Dt]) => ReadDelay(reg(x)),
+            ("LD", [Register(x), K]) => WaitKey(reg(x)),
+            ("LD", [Dt, Register(x)]) => SetDelay(reg(x)),
+            ("LD", [St, Register(x)]) => SetSound(reg(x)),
+            ("ADD", [I, Register(x)]) => AddI(reg(x)),
+            ("LD", [F, Register(x)]) => Font(reg(x)),
+            ("LD", [Hf, Register(x)]) => BigFont(reg(x)),
+            ("LD", [B, Register(x)]) => Bcd(reg(x)),
+            ("PITCH", [Register(x)]) => Pitch(reg(x)),
+            ("LD", [IndirectI, Register(x)]) => Store(reg(x)),
+            ("LD", [Register(x), IndirectI]) => Restore(reg(x)),
+            ("LD", [R, Register(x)]) => SaveFlags(reg(x)),
+            ("LD", [Register(x), R]) => LoadFlags(reg(x)),
+            _ => return Err(None),
+        };
+        Ok(instruction.encode())
+    }
+}
+
+const MNEMONICS: [&str; 31] = [
+    "CLS", "RET", "SCR", "SCL", "EXIT", "LOW", "HIGH", "AUDIO", "SCD", "SCU", "SYS", "JP", "CALL", "SE", "SNE",
+    "SAVE", "LOAD", "LD", "ADD", "OR", "AND", "XOR", "SUB", "SHR", "SUBN", "SHL", "RND", "DRW", "SKP", "SKNP",
+    "PLANE",
+];
+
+// operand names that can not be used as labels or constants
+const RESERVED: [&str; 11] = ["I", "DT", "ST", "K", "F", "HF", "B", "R", "LONG", "EQU", "PITCH"];
+
+// split operand tokens on the commas between them
+fn split_operands(tokens: &[Token]) -> Vec<&[Token]> {
+    if tokens.is_empty() {
+        return Vec::new();
+    }
+    tokens.split(|token| token.kind == TokenKind::Comma).collect()
+}
+
+fn statement_size(statement: &Statement) -> i64 {
+    match statement {
+        Statement::Instruction { operands, .. } => match operands.as_slice() {
+            [Operand::I, Operand::Long(_)] => 4,
+            _ => 2,
+        },
+        Statement::Bytes { operands, .. } => operands
+            .iter()
+            .map(|operand| match operand {
+                Operand::Str(text) => text.len() as i64,
+                _ => 1,
+            })
+            .sum(),
+        Statement::Words { operands, .. } => 2 * operands.len() as i64,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm::disassemble_rom;
+    use crate::platform::Platform;
+
+    #[test]
+    fn assembles_instructions() {
+        let rom = assemble("CLS\nLD V3, 0x1F\nDRW V0, V1, 5\nld [i], va\nSHR V2\nLD I, LONG 0x1234", 0x200).unwrap();
+        assert_eq!(rom, vec![0x00, 0xE0, 0x63, 0x1F, 0xD0, 0x15, 0xFA, 0x55, 0x82, 0x26, 0xF0, 0x00, 0x12, 0x34]);
+    }
+
+    #[test]
+    fn labels_constants_and_data() {
+        let source = "
+            COUNT EQU 3
+            start: LD V0, COUNT   ; forward references work too
+                   LD I, sprite
+                   JP start
+            sprite:
+                   DB 0xF0, -1, \"AB\"
+                   DW sprite + 1
+        ";
+        let rom = assemble(source, 0x200).unwrap();
+        assert_eq!(
+            rom,
+            vec![0x60, 0x03, 0xA2, 0x06, 0x12, 0x00, 0xF0, 0xFF, 0x41, 0x42, 0x02, 0x07]
+        );
+    }
+
+    #[test]
+    fn org_pads_with_zeros() {
+        let rom = assemble("CLS\nORG 0x206\nRET", 0x200).unwrap();
+        assert_eq!(rom, vec![0x00, 0xE0, 0, 0, 0, 0, 0x00, 0xEE]);
+    }
+
+    #[test]
+    fn errors_have_line_and_column() {
+        let error = assemble("CLS\n  JP nowhere", 0x200).unwrap_err();
+        assert_eq!((error.line, error.column), (2, 6));
+        assert!(error.message.contains("nowhere"));
+
+        let error = assemble("LD V0, 256", 0x200).unwrap_err();
+        assert_eq!((error.line, error.column), (1, 8));
+
+        let error = assemble("FOO V0", 0x200).unwrap_err();
+        assert_eq!(error.message, "unknown instruction 'FOO'");
+
+        let error = assemble("SKP 3", 0x200).unwrap_err();
+        assert_eq!(error.message, "invalid operands for SKP");
+
+        let error = assemble("a: CLS\na: CLS", 0x200).unwrap_err();
+        assert_eq!(error.line, 2);
+
+        let error = assemble("CLS\nDW 0x7FFFFFFFFFFFFFFF + 1", 0x200).unwrap_err();
+        assert_eq!((error.line, error.column, error.message.as_str()), (2, 25, "the expression overflows"));
+    }
+
+    #[test]
+    fn org_stays_in_memory() {
+        let error = assemble("CLS\nORG 0x20000", 0x200).unwrap_err();
+        assert_eq!((error.line, error.message.as_str()), (2, "ORG 0x20000 is past the end of memory"));
+
+        let error = assemble("ORG 0x7FFFFFFFFFFFFFFF + 0x7FFFFFFFFFFFFFFF", 0x200).unwrap_err();
+        assert_eq!((error.column, error.message.as_str()), (26, "the expression overflows"));
+
+        assert_eq!(assemble("CLS\nORG 0x10000", 0xFFFE).unwrap(), vec![0x00, 0xE0]);
+    }
+
+    #[test]
+    fn includes() {
+        let dir = std::env::temp_dir().join("rusty_chip_asm_includes");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("main.asm"), "CALL sub\nINCLUDE \"sub.asm\"").unwrap();
+        fs::write(dir.join("sub.asm"), "sub: RET").unwrap();
+        fs::write(dir.join("loop.asm"), "INCLUDE \"loop.asm\"").unwrap();
+
+        assert_eq!(assemble_file(dir.join("main.asm"), 0x200).unwrap(), vec![0x22, 0x02, 0x00, 0xEE]);
+        assert!(assemble_file(dir.join("loop.asm"), 0x200).is_err(), "recursive includes are caught");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn round_trips_with_disassembler() {
+        let roms: [&[u8]; 3] = [
+            include_bytes!("../../roms/PONG"),
+            include_bytes!("../../roms/INVADERS"),
+            include_bytes!("../../roms/TETRIS"),
+        ];
+        for rom in roms.iter() {
+            let listing = disassemble_rom(rom, 0x200, Platform::XoChip).to_string();
+            assert_eq!(&assemble(&listing, 0x200).unwrap()[..], *rom);
+        }
+    }
+}
@@ -1,7 +1,18 @@
+use crate::display::Display;
+use crate::error::CpuError;
 use crate::keyboard::Keyboard;
-use crate::font::FONT_SET;
+use crate::font::{BIG_FONT_SET, FONT_SET};
+use crate::instruction::Instruction;
+use crate::platform::Platform;
+use crate::quirks::Quirks;
+use crate::random::{RandomSource, XorShiftRandom};
+use crate::savestate::{self, StateReader, StateWriter};
 use std::fs::File;
 use std::io::Read;
+use std::ops::Range;
+use std::path::Path;
+
+mod block;
 
 
 // constant for the instruction
@@ -9,62 +20,25 @@ use std::io::Read;
 const OP_SIZE: u16 = 2;
 
 // constants
-const SCREEN_WIDTH: usize = 64;
-const SCREEN_HEIGHT: usize = 32;
+// the CHIP-8 display is 64x32, SUPER-CHIP adds a 128x64 hi-res mode
+pub const LORES_WIDTH: usize = 64;
+pub const LORES_HEIGHT: usize = 32;
+pub const HIRES_WIDTH: usize = 128;
+pub const HIRES_HEIGHT: usize = 64;
+// the big font is stored in memory right after the small font
+const BIG_FONT_START_INDEX: usize = 0x50;
+// XO-CHIP pitch register value that plays the audio pattern at 4000 bits per second
+const DEFAULT_PITCH: u8 = 64;
 // first open memory location for loading programs/games
-const MEMORY_START_INDEX: usize = 0x200;
-
-pub struct OpCode {
-    // processed opcodes
-    pub op_1: usize,
-    pub op_2: usize,
-    pub op_3: usize,
-    pub op_4: usize,
-
-    // operation code variables
-    pub nnn: usize,
-    pub kk: u8,
-    pub x: usize,
-    pub y: usize,
-    pub n: usize,
-}
-
-pub fn parse_op_codes_from_word(opcode: u16) -> OpCode {
-    // opcode params
-    // take the op code, mask its position, shift to the 0th place of the instruction
-
-    // x - A 4-bit value, the lower 4 bits of the high byte of the instruction
-    let x = ((opcode & 0x0F00) >> 8) as usize;
-
-    // y - A 4-bit value, the upper 4 bits of the low byte of the instruction
-    let y = ((opcode & 0x00F0) >> 4) as usize;
-
-    // nnn or addr - A 12-bit value, the lowest 12 bits of the instruction
-    let nnn = (opcode & 0x0FFF) as usize;
-
-    // n or nibble - A 4-bit value, the lowest 4 bits of the instruction
-    let n = (opcode & 0x000F) as usize;
-
-    // kk or byte - An 8-bit value, the lowest 8 bits of the instruction
-    let kk = (opcode & 0x00FF) as u8;
-
-    // extract the op code nibbles
-    let op_1 = ((opcode & 0xF000) >> 12) as usize;
-    let op_2 = ((opcode & 0x0F00) >> 8) as usize;
-    let op_3 = ((opcode & 0x00F0) >> 4) as usize;
-    let op_4 = (opcode & 0x000F) as usize;
-
-    return OpCode {
-        x,
-        y,
-        nnn,
-        n,
-        kk,
-        op_1,
-        op_2,
-        op_3,
-        op_4,
-    };
+pub const MEMORY_START_INDEX: usize = 0x200;
+// ETI 660 programs were loaded higher up in memory
+pub const ETI_660_START_INDEX: usize = 0x600;
+
+// the registers from x to y inclusive, counting down if y is before x
+fn register_range(x: usize, y: usize) -> impl Iterator<Item = usize> {
+    let ascending = x <= y;
+    let count = if ascending { y - x } else { x - y } + 1;
+    (0..count).map(move |offset| if ascending { x + offset } else { x - offset })
 }
 
 pub struct Cpu {
@@ -74,15 +48,15 @@ pub struct Cpu {
     // program counter
     pc: u16,
 
-    // memory
-    memory: [u8; 4096],
+    // memory, sized for the platform
+    memory: Vec<u8>,
 
     // registers
     v: [u8; 16],
 
     // peripherals
     pub keyboard: Keyboard,
-    pub display: [[u32; SCREEN_WIDTH]; SCREEN_HEIGHT],
+    pub display: Display,
 
     // program stack
     stack: [u16; 16],
@@ -96,14 +70,63 @@ pub struct Cpu {
     // sound timer
     st: u8,
 
-    // paused waiting for input
-    paused: bool,
-
-    // keyboard target register
-    kt: u8,
+    // how far FX0A has got waiting for a key
+    key_wait: KeyWait,
 
-    // previous keyboard sate
+    // the keys held the last time FX0A looked, so it only sees keys newly pressed
     previous_keys: [bool; 16],
+
+    // interpreter behaviour for ambiguous instructions
+    quirks: Quirks,
+
+    // which instruction set and display the CPU is emulating
+    platform: Platform,
+
+    // SUPER-CHIP 128x64 display mode
+    hires: bool,
+
+    // SUPER-CHIP RPL user flags, saved and loaded by FX75/FX85
+    rpl: [u8; 16],
+
+    // XO-CHIP bitplanes selected for drawing, clearing and scrolling
+    planes: u8,
+
+    // XO-CHIP 1-bit audio pattern, played back while the sound timer is active
+    audio_pattern: [u8; 16],
+
+    // XO-CHIP audio pattern playback pitch
+    pitch: u8,
+
+    // the program ran 00FD and the interpreter should stop
+    exited: bool,
+
+    // a sprite was drawn and the display wait quirk is holding execution until the next tick
+    waiting_for_display: bool,
+
+    // where ROMs are copied into memory
+    load_address: u16,
+
+    // where the program counter starts, usually the load address
+    entry_point: u16,
+
+    // where RND gets its numbers from
+    random: Box<dyn RandomSource>,
+
+    // instructions already decoded, by address, when the cache is on
+    decode_cache: Option<Vec<Option<Instruction>>>,
+
+    // straight-line runs of instructions decoded into blocks, when the block engine is on
+    blocks: Option<block::BlockCache>,
+}
+
+// FX0A holds execution until a key is pressed, or pressed and let go with the key_release quirk
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum KeyWait {
+    Idle,
+    // waiting for a key to go down, the key goes in register x
+    Press { x: u8 },
+    // key went down, waiting for it to be let go
+    Release { x: u8, key: u8 },
 }
 
 enum ProgramCounterChange {
@@ -112,378 +135,941 @@ enum ProgramCounterChange {
     Jump(u16),
 }
 
+// what went wrong executing an instruction, handle_opcode attaches the pc and opcode
+enum Fault {
+    StackOverflow,
+    StackUnderflow,
+    MemoryOutOfBounds(usize),
+    UnknownOpcode,
+}
+
+impl Fault {
+    fn at(self, pc: u16, opcode: u16) -> CpuError {
+        match self {
+            Fault::StackOverflow => CpuError::StackOverflow { pc, opcode },
+            Fault::StackUnderflow => CpuError::StackUnderflow { pc, opcode },
+            Fault::MemoryOutOfBounds(address) => CpuError::MemoryOutOfBounds { pc, opcode, address },
+            Fault::UnknownOpcode => CpuError::UnknownOpcode { pc, opcode },
+        }
+    }
+}
+
+type OpResult = Result<ProgramCounterChange, Fault>;
+
+impl Default for Cpu {
+    fn default() -> Cpu {
+        Cpu::new()
+    }
+}
+
 impl Cpu {
+    // a CHIP-8 CPU with every quirk off, how this emulator behaved before it had quirks
+    // unlike Cpu::with_platform(Platform::Chip8), which has the COSMAC VIP's quirks
     pub fn new() -> Cpu {
+        Cpu::with_quirks(Quirks::default())
+    }
+
+    pub fn with_quirks(quirks: Quirks) -> Cpu {
+        let mut cpu = Cpu::with_platform(Platform::Chip8);
+        cpu.quirks = quirks;
+        cpu
+    }
+
+    // a CPU for the given platform, using the quirks its interpreters were known for
+    // for CHIP-8 those are the COSMAC VIP's, where Cpu::new has every quirk off
+    pub fn with_platform(platform: Platform) -> Cpu {
         let mut cpu = Cpu {
             i: 0,
             pc: MEMORY_START_INDEX as u16,
-            memory: [0; 4096],
+            memory: vec![0; platform.memory_size()],
             v: [0; 16],
-            display: [[0; SCREEN_WIDTH]; SCREEN_HEIGHT],
+            display: Display::new(),
             keyboard: Keyboard::new(),
             stack: [0; 16],
             sp: 0,
             dt: 0,
             st: 0,
-            paused: false,
-            kt: 0,
+            key_wait: KeyWait::Idle,
             previous_keys: [false; 16],
+            quirks: platform.quirks(),
+            waiting_for_display: false,
+            platform,
+            hires: false,
+            rpl: [0; 16],
+            planes: 1,
+            audio_pattern: [0; 16],
+            pitch: DEFAULT_PITCH,
+            exited: false,
+            load_address: MEMORY_START_INDEX as u16,
+            entry_point: MEMORY_START_INDEX as u16,
+            random: Box::new(XorShiftRandom::default()),
+            decode_cache: None,
+            blocks: None,
         };
 
-        for i in 0..FONT_SET.len() {
-            cpu.memory[i] = FONT_SET[i];
-        };
+        cpu.load_fonts();
 
         cpu
     }
 
+    pub fn platform(&self) -> Platform {
+        self.platform
+    }
+
+    // the size of the display in the current mode, in pixels
+    pub fn width(&self) -> usize {
+        if self.hires {
+            HIRES_WIDTH
+        } else {
+            LORES_WIDTH
+        }
+    }
+
+    pub fn height(&self) -> usize {
+        if self.hires {
+            HIRES_HEIGHT
+        } else {
+            LORES_HEIGHT
+        }
+    }
+
+    pub fn is_hires(&self) -> bool {
+        self.hires
+    }
+
+    // XO-CHIP bitplanes currently selected, bit 0 is the first plane
+    pub fn planes(&self) -> u8 {
+        self.planes
+    }
+
+    pub fn delay_timer(&self) -> u8 {
+        self.dt
+    }
+
+    pub fn sound_timer(&self) -> u8 {
+        self.st
+    }
+
+    pub fn audio_pattern(&self) -> &[u8; 16] {
+        &self.audio_pattern
+    }
+
+    // the rate, in bits per second, the audio pattern should be played back at
+    pub fn audio_playback_rate(&self) -> f32 {
+        4000.0 * 2f32.powf((self.pitch as f32 - 64.0) / 48.0)
+    }
+
+    // true once the program has asked the interpreter to exit with 00FD
+    pub fn has_exited(&self) -> bool {
+        self.exited
+    }
+
+    // the address of the next instruction
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    // the index register
+    pub fn i(&self) -> u16 {
+        self.i
+    }
+
+    pub fn registers(&self) -> &[u8; 16] {
+        &self.v
+    }
+
+    // return addresses of the subroutines in progress, innermost last
+    pub fn call_stack(&self) -> &[u16] {
+        &self.stack[..self.sp as usize]
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    // the opcode stored at addr, if it is inside memory
+    pub fn opcode_at(&self, addr: u16) -> Option<u16> {
+        let addr = addr as usize;
+        self.memory
+            .get(addr..addr + 2)
+            .map(|word| (word[0] as u16) << 8 | word[1] as u16)
+    }
+
+    // true while FX0A is holding execution until a key is pressed, or let go with the
+    // key_release quirk, so frontends can show it and stop spinning the host CPU
+    pub fn is_waiting_for_key(&self) -> bool {
+        self.key_wait != KeyWait::Idle
+    }
+
+    // true while a sprite draw is holding execution until the next timer tick
+    pub fn is_waiting_for_display(&self) -> bool {
+        self.waiting_for_display
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
     pub fn reset(&mut self) {
         self.i = 0;
-        self.pc = MEMORY_START_INDEX as u16;
-        self.memory = [0; 4096];
+        self.pc = self.entry_point;
+        self.memory = vec![0; self.platform.memory_size()];
         self.v = [0; 16];
-        self.display = [[0; SCREEN_WIDTH]; SCREEN_HEIGHT];
+        self.display = Display::new();
         self.stack = [0; 16];
         self.sp = 0;
         self.dt = 0;
         self.st = 0;
-        self.paused = false;
-        self.kt = 0;
+        self.key_wait = KeyWait::Idle;
         self.previous_keys = [false; 16];
+        self.waiting_for_display = false;
+        self.hires = false;
+        self.planes = 1;
+        self.audio_pattern = [0; 16];
+        self.pitch = DEFAULT_PITCH;
+        self.exited = false;
+
+        self.load_fonts();
+        self.clear_decode_cache();
+    }
 
-        for i in 0..FONT_SET.len() {
-            self.memory[i] = FONT_SET[i];
+    fn load_fonts(&mut self) {
+        self.memory[..FONT_SET.len()].copy_from_slice(&FONT_SET);
+        self.memory[BIG_FONT_START_INDEX..BIG_FONT_START_INDEX + BIG_FONT_SET.len()]
+            .copy_from_slice(&BIG_FONT_SET);
+    }
+
+    // replace the generator RND draws from, e.g. with a VipRandom
+    pub fn set_random_source(&mut self, random: Box<dyn RandomSource>) {
+        self.random = random;
+    }
+
+    // restart the default generator from seed, so runs can be replayed
+    pub fn seed_random(&mut self, seed: u64) {
+        self.random = Box::new(XorShiftRandom::new(seed));
+    }
+
+    // capture the whole machine, including the platform and quirks, as a versioned save state
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = Vec::new();
+        self.snapshot(&mut state);
+        state
+    }
+
+    // save_state into an existing buffer, so taking a snapshot every frame does not allocate
+    pub fn snapshot(&self, buffer: &mut Vec<u8>) {
+        let mut state = StateWriter::reusing(std::mem::take(buffer));
+        state.u8(self.platform.id());
+        state.u16(self.quirks.to_bits());
+
+        state.u16(self.i);
+        state.u16(self.pc);
+        state.bytes(&self.v);
+        for address in self.stack.iter() {
+            state.u16(*address);
+        }
+        state.u8(self.sp);
+        state.u8(self.dt);
+        state.u8(self.st);
+        state.block(&self.memory);
+
+        state.bool(self.hires);
+        state.u8(self.planes);
+        let pixels = self.display.pixels();
+        state.block(&pixels);
+
+        let (phase, register, key) = match self.key_wait {
+            KeyWait::Idle => (0, 0, 0),
+            KeyWait::Press { x } => (1, x, 0),
+            KeyWait::Release { x, key } => (2, x, key),
         };
+        state.u8(phase);
+        state.u8(register);
+        state.u8(key);
+        for pressed in self.previous_keys.iter().chain(self.keyboard.keys.iter()) {
+            state.bool(*pressed);
+        }
+
+        state.bool(self.waiting_for_display);
+        state.bool(self.exited);
+        state.bytes(&self.rpl);
+        state.bytes(&self.audio_pattern);
+        state.u8(self.pitch);
+        state.u16(self.load_address);
+        state.u16(self.entry_point);
+        state.u8(self.random.id());
+        state.block(&self.random.save());
+        *buffer = state.finish();
     }
 
-    pub fn load_game(&mut self, game: &String) {
-        let mut file = File::open(game).unwrap();
-        let mut data = Vec::<u8>::new();
-        file.read_to_end(&mut data).expect("File not found!");
-        for i in 0..data.len() {
-            self.memory[MEMORY_START_INDEX + i] = data[i];
+    // restore a state from save_state, the CPU is left untouched if the state is invalid
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), CpuError> {
+        let body = savestate::open(state)?;
+        let mut state = StateReader::new(&body);
+        let invalid = |reason: &str| CpuError::InvalidSaveState(String::from(reason));
+
+        let platform = Platform::from_id(state.u8()?).ok_or_else(|| invalid("unknown platform"))?;
+        let quirks = Quirks::from_bits(state.u16()?);
+
+        let i = state.u16()?;
+        let pc = state.u16()?;
+        let mut v = [0; 16];
+        state.fill(&mut v)?;
+        let mut stack = [0; 16];
+        for address in stack.iter_mut() {
+            *address = state.u16()?;
         }
+        let sp = state.u8()?;
+        let dt = state.u8()?;
+        let st = state.u8()?;
+        let memory = state.block()?;
+        if memory.len() != platform.memory_size() || sp as usize > stack.len() {
+            return Err(invalid("memory or stack does not match the platform"));
+        }
+
+        let hires = state.bool()?;
+        let planes = state.u8()?;
+        let pixels = state.block()?;
+        if pixels.len() != HIRES_WIDTH * HIRES_HEIGHT {
+            return Err(invalid("display is the wrong size"));
+        }
+
+        let phase = state.u8()?;
+        let (register, key) = (state.u8()? & 0x0F, state.u8()? & 0x0F);
+        let key_wait = match phase {
+            0 => KeyWait::Idle,
+            1 => KeyWait::Press { x: register },
+            2 => KeyWait::Release { x: register, key },
+            _ => return Err(invalid("unknown key wait")),
+        };
+        let mut keys = [false; 32];
+        for pressed in keys.iter_mut() {
+            *pressed = state.bool()?;
+        }
+
+        let waiting_for_display = state.bool()?;
+        let exited = state.bool()?;
+        let mut rpl = [0; 16];
+        state.fill(&mut rpl)?;
+        let mut audio_pattern = [0; 16];
+        state.fill(&mut audio_pattern)?;
+        let pitch = state.u8()?;
+        let load_address = state.u16()?;
+        let entry_point = state.u16()?;
+        if state.u8()? != self.random.id() {
+            return Err(invalid("saved with a different random source, so the numbers would not replay"));
+        }
+        // the last thing that can fail, so nothing else has changed if it does
+        self.random.restore(state.block()?)?;
+
+        // everything was read, so the state is complete and can be applied
+        self.platform = platform;
+        self.quirks = quirks;
+        self.i = i;
+        self.pc = pc;
+        self.v = v;
+        self.stack = stack;
+        self.sp = sp;
+        self.dt = dt;
+        self.st = st;
+        self.memory = memory.to_vec();
+        self.hires = hires;
+        self.planes = planes;
+        self.display = Display::from_pixels(pixels);
+        self.key_wait = key_wait;
+        self.previous_keys.copy_from_slice(&keys[..16]);
+        self.keyboard.keys.copy_from_slice(&keys[16..]);
+        self.waiting_for_display = waiting_for_display;
+        self.exited = exited;
+        self.rpl = rpl;
+        self.audio_pattern = audio_pattern;
+        self.pitch = pitch;
+        self.load_address = load_address;
+        self.entry_point = entry_point;
+        self.clear_decode_cache();
+        Ok(())
     }
 
-    pub fn execute_cycle(&mut self) {
-        if !self.paused {
-            // fetch instruction
-            let opcode = self.read_word();
+    pub fn load_address(&self) -> u16 {
+        self.load_address
+    }
 
-            // execute instruction
-            self.handle_opcode(opcode);
+    // load ROMs at address and start running them from there, e.g. ETI_660_START_INDEX
+    pub fn set_load_address(&mut self, address: u16) {
+        self.load_address = address;
+        self.set_entry_point(address);
+    }
+
+    pub fn entry_point(&self) -> u16 {
+        self.entry_point
+    }
+
+    // start running from address, for programs that do not begin at their first byte
+    pub fn set_entry_point(&mut self, address: u16) {
+        self.entry_point = address;
+        self.pc = address;
+    }
+
+    pub fn load_game<P: AsRef<Path>>(&mut self, game: P) -> Result<(), CpuError> {
+        self.load_rom_from(File::open(game)?)
+    }
 
-            // if the opcode paused the CPU
-            // do not execute any more of the emulation
-            if self.paused {
-                return;
+    pub fn load_rom_from<R: Read>(&mut self, mut reader: R) -> Result<(), CpuError> {
+        let mut data = Vec::<u8>::new();
+        reader.read_to_end(&mut data)?;
+        self.load_rom_bytes(&data)
+    }
+
+    // copy a ROM into memory at the load address
+    pub fn load_rom_bytes(&mut self, rom: &[u8]) -> Result<(), CpuError> {
+        let start = self.load_address as usize;
+        let max = self.memory.len().saturating_sub(start);
+        if rom.len() > max {
+            return Err(CpuError::RomTooLarge { size: rom.len(), max });
+        }
+        self.memory[start..start + rom.len()].copy_from_slice(rom);
+        self.wrote(start..start + rom.len());
+        Ok(())
+    }
+
+    // run one 60Hz frame: up to instructions_per_frame instructions, then a timer tick
+    // this decides how fast the game runs, instructions_per_frame only decides how fast the CPU is
+    pub fn run_frame(&mut self, instructions_per_frame: usize) -> Result<(), CpuError> {
+        if self.blocks.is_some() {
+            self.run_blocks(instructions_per_frame)?;
+            self.tick_timers();
+            return Ok(());
+        }
+        for _ in 0..instructions_per_frame {
+            // the rest of the frame is idle once a sprite is waiting on the display
+            if self.waiting_for_display || self.exited {
+                break;
             }
+            self.execute_cycle()?;
+        }
+        self.tick_timers();
+        Ok(())
+    }
+
+    // run a single instruction, timers are left for tick_timers
+    pub fn execute_cycle(&mut self) -> Result<(), CpuError> {
+        if self.exited || self.waiting_for_display {
+            return Ok(());
+        }
 
-            // decrement timers
-            self.decrement_timers();
+        if !self.is_waiting_for_key() {
+            // fetch instruction
+            let opcode = self.read_word()?;
+
+            // execute instruction
+            self.handle_opcode(opcode)?;
         } else {
             self.detect_keyboard_change();
         }
+        Ok(())
     }
 
-    fn decrement_timers(&mut self) {
+    // count the delay and sound timers down, this needs to be called at 60Hz
+    // it also stands in for the display refresh a sprite may be waiting on
+    pub fn tick_timers(&mut self) {
         if self.dt > 0 {
             self.dt -= 1;
         }
         if self.st > 0 {
             self.st -= 1;
         }
+        self.waiting_for_display = false;
+        self.random.tick();
     }
 
+    // move FX0A's wait on with the keys pressed and let go since the last look
     fn detect_keyboard_change(&mut self) {
-        // look for changes in the keyboard
-        for i in 0..(self.keyboard.keys.len() - 1) {
-            if self.keyboard.keys[i] != self.previous_keys[i]
-                && self.keyboard.key_is_pressed(i as u8)
-            {
-                self.v[self.kt as usize] = i as u8;
-                self.paused = false;
-                self.kt = 0;
-                self.previous_keys = [false; 16];
+        let keys = self.keyboard.keys;
+        // a key already held when FX0A ran has to be let go and pressed again
+        let pressed = (0..keys.len()).find(|&key| keys[key] && !self.previous_keys[key]).map(|key| key as u8);
+        self.previous_keys = keys;
+
+        match (self.key_wait, pressed) {
+            (KeyWait::Press { x }, Some(key)) if self.quirks.key_release => {
+                self.key_wait = KeyWait::Release { x, key };
             }
+            (KeyWait::Press { x }, Some(key)) => self.finish_key_wait(x, key),
+            (KeyWait::Release { x, key }, _) if !self.keyboard.key_is_pressed(key) => self.finish_key_wait(x, key),
+            _ => {}
         }
     }
 
+    fn finish_key_wait(&mut self, x: u8, key: u8) {
+        self.v[x as usize] = key;
+        self.key_wait = KeyWait::Idle;
+    }
+
     // a word is 16 bits, so we combine two 8 bit chunks of memory to form one word
-    fn read_word(&mut self) -> u16 {
-        (self.memory[self.pc as usize] as u16) << 8 | (self.memory[(self.pc + 1) as usize] as u16)
-    }
-
-    fn handle_opcode(&mut self, opcode: u16) {
-        //println!("opcode: {}", opcode);
-        let op_chunks = parse_op_codes_from_word(opcode);
-        let nibbles = (
-            op_chunks.op_1,
-            op_chunks.op_2,
-            op_chunks.op_3,
-            op_chunks.op_4,
-        );
+    fn read_word(&mut self) -> Result<u16, CpuError> {
+        let pc = self.pc as usize;
+        match self.memory.get(pc..pc + 2) {
+            Some(word) => Ok((word[0] as u16) << 8 | word[1] as u16),
+            // there is no opcode to report, the fetch itself ran off the end of memory
+            None => Err(Fault::MemoryOutOfBounds(pc + 1).at(self.pc, 0)),
+        }
+    }
 
-        // match opcode nibbles to a function that updates the CPU state
-        // after the operation, determine how to update the program counter
-        let pc_action = match nibbles {
-            (0x00, 0x00, 0x0E, 0x00) => self.op_00e0(),
-            (0x00, 0x00, 0x0E, 0x0E) => self.op_00ee(),
-            (0x01, _, _, _) => self.op_1nnn(op_chunks.nnn),
-            (0x02, _, _, _) => self.op_2nnn(op_chunks.nnn),
-            (0x03, _, _, _) => self.op_3xkk(op_chunks.x, op_chunks.kk),
-            (0x04, _, _, _) => self.op_4xkk(op_chunks.x, op_chunks.kk),
-            (0x05, _, _, 0x00) => self.op_5xy0(op_chunks.x, op_chunks.y),
-            (0x06, _, _, _) => self.op_6xkk(op_chunks.x, op_chunks.kk),
-            (0x07, _, _, _) => self.op_7xkk(op_chunks.x, op_chunks.kk),
-            (0x08, _, _, 0x00) => self.op_8xy0(op_chunks.x, op_chunks.y),
-            (0x08, _, _, 0x01) => self.op_8xy1(op_chunks.x, op_chunks.y),
-            (0x08, _, _, 0x02) => self.op_8xy2(op_chunks.x, op_chunks.y),
-            (0x08, _, _, 0x03) => self.op_8xy3(op_chunks.x, op_chunks.y),
-            (0x08, _, _, 0x04) => self.op_8xy4(op_chunks.x, op_chunks.y),
-            (0x08, _, _, 0x05) => self.op_8xy5(op_chunks.x, op_chunks.y),
-            (0x08, _, _, 0x06) => self.op_8x06(op_chunks.x),
-            (0x08, _, _, 0x07) => self.op_8xy7(op_chunks.x, op_chunks.y),
-            (0x08, _, _, 0x0E) => self.op_8x0e(op_chunks.x),
-            (0x09, _, _, 0x00) => self.op_9xy0(op_chunks.x, op_chunks.y),
-            (0x0A, _, _, _) => self.op_annn(op_chunks.nnn),
-            (0x0B, _, _, _) => self.op_bnnn(op_chunks.nnn),
-            (0x0C, _, _, _) => self.op_cxkk(op_chunks.x, op_chunks.kk),
-            (0x0D, _, _, _) => self.op_dxyn(op_chunks.x, op_chunks.y, op_chunks.n),
-            (0x0E, _, 0x09, 0x0E) => self.op_ex9e(op_chunks.x),
-            (0x0E, _, 0x0A, 0x01) => self.op_exa1(op_chunks.x),
-            (0x0F, _, 0x00, 0x07) => self.op_fx07(op_chunks.x),
-            (0x0F, _, 0x00, 0x0A) => self.op_fx0a(op_chunks.x),
-            (0x0F, _, 0x01, 0x05) => self.op_fx15(op_chunks.x),
-            (0x0F, _, 0x01, 0x08) => self.op_fx18(op_chunks.x),
-            (0x0F, _, 0x01, 0x0e) => self.op_fx1e(op_chunks.x),
-            (0x0F, _, 0x02, 0x09) => self.op_fx29(op_chunks.x),
-            (0x0F, _, 0x03, 0x03) => self.op_fx33(op_chunks.x),
-            (0x0F, _, 0x05, 0x05) => self.op_fx55(op_chunks.x),
-            (0x0F, _, 0x06, 0x05) => self.op_fx65(op_chunks.x),
-            _ => ProgramCounterChange::Next,
-        };
+    // the range of len bytes starting at start, if all of it is inside memory
+    fn memory_range(&self, start: usize, len: usize) -> Result<Range<usize>, Fault> {
+        if start + len > self.memory.len() {
+            return Err(Fault::MemoryOutOfBounds(start + len - 1));
+        }
+        Ok(start..start + len)
+    }
+
+    // Keep every instruction once it has been decoded, so a loop is not decoded again each time
+    // round. Anything that writes to memory drops the instructions decoded from what it changed,
+    // so self-modifying code still works.
+    pub fn set_decode_cache(&mut self, enabled: bool) {
+        self.decode_cache = if enabled { Some(vec![None; self.memory.len()]) } else { None };
+    }
+
+    fn clear_decode_cache(&mut self) {
+        if self.decode_cache.is_some() {
+            self.set_decode_cache(true);
+        }
+        self.clear_blocks();
+    }
+
+    // memory in range changed, an instruction starting the byte before it changed too
+    fn wrote(&mut self, range: Range<usize>) {
+        if let Some(cache) = &mut self.decode_cache {
+            for entry in cache[range.start.saturating_sub(1)..range.end].iter_mut() {
+                *entry = None;
+            }
+        }
+        if let Some(blocks) = &mut self.blocks {
+            blocks.invalidate(range);
+        }
+    }
+
+    // the instruction fetched from the pc
+    fn decode(&mut self, opcode: u16) -> Instruction {
+        let platform = self.platform;
+        match &mut self.decode_cache {
+            Some(cache) => *cache[self.pc as usize].get_or_insert_with(|| Instruction::decode_for(opcode, platform)),
+            None => Instruction::decode_for(opcode, platform),
+        }
+    }
+
+    // size in bytes of the instruction at addr
+    pub fn instruction_size(&self, addr: u16) -> u16 {
+        let addr = addr as usize;
+        let long_load = self.platform.has_xo_chip_opcodes()
+            && self.memory.get(addr) == Some(&0xF0)
+            && self.memory.get(addr + 1) == Some(&0x00);
+        if long_load {
+            2 * OP_SIZE
+        } else {
+            OP_SIZE
+        }
+    }
+
+    // move the selected planes of the display by (dx, dy), pixels scrolled in are blank
+    fn scroll(&mut self, dx: isize, dy: isize) {
+        let (width, height) = (self.width(), self.height());
+        let (dx, dy) = if !self.hires && self.quirks.lores_scroll_halved { (dx / 2, dy / 2) } else { (dx, dy) };
+        self.display.scroll(self.planes, dx, dy, width, height);
+    }
+
+    fn handle_opcode(&mut self, opcode: u16) -> Result<(), CpuError> {
+        let instruction = self.decode(opcode);
+        let pc_action = self.dispatch(instruction).map_err(|fault| fault.at(self.pc, opcode))?;
 
         // Update the program counter
         match pc_action {
-            ProgramCounterChange::Next => self.pc += 2,
-            ProgramCounterChange::Skip => self.pc += 2 * OP_SIZE,
+            ProgramCounterChange::Next => self.pc = self.pc.wrapping_add(OP_SIZE),
+            ProgramCounterChange::Skip => {
+                // XO-CHIP's F000 NNNN is twice as long as every other instruction
+                let skipped = self.pc.wrapping_add(OP_SIZE);
+                self.pc = skipped.wrapping_add(self.instruction_size(skipped));
+            }
             ProgramCounterChange::Jump(dest) => self.pc = dest,
         }
+        Ok(())
+    }
+
+    // match the instruction to a function that updates the CPU state
+    // after the operation, determine how to update the program counter
+    fn dispatch(&mut self, instruction: Instruction) -> OpResult {
+        match instruction {
+            Instruction::ScrollDown(n) => self.op_00cn(n as usize),
+            Instruction::ScrollUp(n) => self.op_00dn(n as usize),
+            Instruction::Clear => self.op_00e0(),
+            Instruction::Return => self.op_00ee(),
+            Instruction::ScrollRight => self.op_00fb(),
+            Instruction::ScrollLeft => self.op_00fc(),
+            Instruction::Exit => self.op_00fd(),
+            Instruction::LowRes => self.op_00fe(),
+            Instruction::HighRes => self.op_00ff(),
+            // SYS addr jumped to machine code on the original computers, modern interpreters ignore it
+            Instruction::System(_) => Ok(ProgramCounterChange::Next),
+            Instruction::Jump(nnn) => self.op_1nnn(nnn as usize),
+            Instruction::Call(nnn) => self.op_2nnn(nnn as usize),
+            Instruction::SkipIfEqual { x, byte } => self.op_3xkk(x as usize, byte),
+            Instruction::SkipIfNotEqual { x, byte } => self.op_4xkk(x as usize, byte),
+            Instruction::SkipIfRegistersEqual { x, y } => self.op_5xy0(x as usize, y as usize),
+            Instruction::SaveRange { x, y } => self.op_5xy2(x as usize, y as usize),
+            Instruction::LoadRange { x, y } => self.op_5xy3(x as usize, y as usize),
+            Instruction::Load { x, byte } => self.op_6xkk(x as usize, byte),
+            Instruction::AddByte { x, byte } => self.op_7xkk(x as usize, byte),
+            Instruction::Move { x, y } => self.op_8xy0(x as usize, y as usize),
+            Instruction::Or { x, y } => self.op_8xy1(x as usize, y as usize),
+            Instruction::And { x, y } => self.op_8xy2(x as usize, y as usize),
+            Instruction::Xor { x, y } => self.op_8xy3(x as usize, y as usize),
+            Instruction::Add { x, y } => self.op_8xy4(x as usize, y as usize),
+            Instruction::Sub { x, y } => self.op_8xy5(x as usize, y as usize),
+            Instruction::ShiftRight { x, y } => self.op_8xy6(x as usize, y as usize),
+            Instruction::SubFrom { x, y } => self.op_8xy7(x as usize, y as usize),
+            Instruction::ShiftLeft { x, y } => self.op_8xye(x as usize, y as usize),
+            Instruction::SkipIfRegistersNotEqual { x, y } => self.op_9xy0(x as usize, y as usize),
+            Instruction::LoadI(nnn) => self.op_annn(nnn as usize),
+            Instruction::JumpOffset(nnn) => self.op_bnnn((nnn >> 8) as usize, nnn as usize),
+            Instruction::Random { x, byte } => self.op_cxkk(x as usize, byte),
+            Instruction::Draw { x, y, n } => self.op_dxyn(x as usize, y as usize, n as usize),
+            Instruction::SkipIfKey(x) => self.op_ex9e(x as usize),
+            Instruction::SkipIfNotKey(x) => self.op_exa1(x as usize),
+            Instruction::LoadILong => self.op_f000(),
+            Instruction::Plane(n) => self.op_fn01(n as usize),
+            Instruction::Audio => self.op_f002(),
+            Instruction::ReadDelay(x) => self.op_fx07(x as usize),
+            Instruction::WaitKey(x) => self.op_fx0a(x as usize),
+            Instruction::SetDelay(x) => self.op_fx15(x as usize),
+            Instruction::SetSound(x) => self.op_fx18(x as usize),
+            Instruction::AddI(x) => self.op_fx1e(x as usize),
+            Instruction::Font(x) => self.op_fx29(x as usize),
+            Instruction::BigFont(x) => self.op_fx30(x as usize),
+            Instruction::Bcd(x) => self.op_fx33(x as usize),
+            Instruction::Pitch(x) => self.op_fx3a(x as usize),
+            Instruction::Store(x) => self.op_fx55(x as usize),
+            Instruction::Restore(x) => self.op_fx65(x as usize),
+            Instruction::SaveFlags(x) => self.op_fx75(x as usize),
+            Instruction::LoadFlags(x) => self.op_fx85(x as usize),
+            Instruction::Unknown(_) => Err(Fault::UnknownOpcode),
+        }
+    }
+
+    // SCD nibble
+    fn op_00cn(&mut self, n: usize) -> OpResult {
+        self.scroll(0, n as isize);
+        Ok(ProgramCounterChange::Next)
+    }
+
+    // SCU nibble
+    fn op_00dn(&mut self, n: usize) -> OpResult {
+        self.scroll(0, -(n as isize));
+        Ok(ProgramCounterChange::Next)
     }
 
-    // SYS
-    fn op_00e0(&mut self) -> ProgramCounterChange {
-        self.display = [[0; SCREEN_WIDTH]; SCREEN_HEIGHT];
-        ProgramCounterChange::Next
+    // CLS
+    fn op_00e0(&mut self) -> OpResult {
+        // only the selected planes are cleared
+        self.display.clear(self.planes);
+        Ok(ProgramCounterChange::Next)
     }
 
     // RET
-    fn op_00ee(&mut self) -> ProgramCounterChange {
-        self.sp = self.sp - 1;
-        ProgramCounterChange::Jump(self.stack[self.sp as usize])
+    fn op_00ee(&mut self) -> OpResult {
+        if self.sp == 0 {
+            return Err(Fault::StackUnderflow);
+        }
+        self.sp -= 1;
+        Ok(ProgramCounterChange::Jump(self.stack[self.sp as usize]))
+    }
+
+    // SCR
+    fn op_00fb(&mut self) -> OpResult {
+        self.scroll(4, 0);
+        Ok(ProgramCounterChange::Next)
+    }
+
+    // SCL
+    fn op_00fc(&mut self) -> OpResult {
+        self.scroll(-4, 0);
+        Ok(ProgramCounterChange::Next)
+    }
+
+    // EXIT
+    fn op_00fd(&mut self) -> OpResult {
+        self.exited = true;
+        Ok(ProgramCounterChange::Next)
+    }
+
+    // LOW
+    fn op_00fe(&mut self) -> OpResult {
+        self.hires = false;
+        self.display.mark_dirty();
+        if self.quirks.resolution_keeps_display {
+            return Ok(ProgramCounterChange::Next);
+        }
+        self.op_00e0()
+    }
+
+    // HIGH
+    fn op_00ff(&mut self) -> OpResult {
+        self.hires = true;
+        self.display.mark_dirty();
+        if self.quirks.resolution_keeps_display {
+            return Ok(ProgramCounterChange::Next);
+        }
+        self.op_00e0()
     }
 
     // Jp
-    fn op_1nnn(&mut self, nnn: usize) -> ProgramCounterChange {
-        ProgramCounterChange::Jump(nnn as u16)
+    fn op_1nnn(&mut self, nnn: usize) -> OpResult {
+        Ok(ProgramCounterChange::Jump(nnn as u16))
     }
 
     // CALL
-    fn op_2nnn(&mut self, nnn: usize) -> ProgramCounterChange {
-        self.stack[self.sp as usize] = self.pc + OP_SIZE;
-        self.sp = self.sp + 1;
-        ProgramCounterChange::Jump(nnn as u16)
+    fn op_2nnn(&mut self, nnn: usize) -> OpResult {
+        if self.sp as usize >= self.stack.len() {
+            return Err(Fault::StackOverflow);
+        }
+        self.stack[self.sp as usize] = self.pc.wrapping_add(OP_SIZE);
+        self.sp += 1;
+        Ok(ProgramCounterChange::Jump(nnn as u16))
     }
 
     // SE Vx KK
-    fn op_3xkk(&mut self, x: usize, kk: u8) -> ProgramCounterChange {
+    fn op_3xkk(&mut self, x: usize, kk: u8) -> OpResult {
         if self.v[x] == kk {
-            return ProgramCounterChange::Skip;
+            return Ok(ProgramCounterChange::Skip);
         };
-        return ProgramCounterChange::Next;
+        Ok(ProgramCounterChange::Next)
     }
 
     // SNE Vx kk
-    fn op_4xkk(&mut self, x: usize, kk: u8) -> ProgramCounterChange {
+    fn op_4xkk(&mut self, x: usize, kk: u8) -> OpResult {
         if self.v[x] != kk {
-            return ProgramCounterChange::Skip;
+            return Ok(ProgramCounterChange::Skip);
         };
-        return ProgramCounterChange::Next;
+        Ok(ProgramCounterChange::Next)
     }
 
     // SE Vx Vy
-    fn op_5xy0(&mut self, x: usize, y: usize) -> ProgramCounterChange {
+    fn op_5xy0(&mut self, x: usize, y: usize) -> OpResult {
         if self.v[x] == self.v[y] {
-            return ProgramCounterChange::Skip;
+            return Ok(ProgramCounterChange::Skip);
         };
-        return ProgramCounterChange::Next;
+        Ok(ProgramCounterChange::Next)
+    }
+
+    // SAVE Vx - Vy
+    fn op_5xy2(&mut self, x: usize, y: usize) -> OpResult {
+        // registers can be saved in either order, I is left unchanged
+        let range = self.memory_range(self.i as usize, register_range(x, y).count())?;
+        for (address, register) in range.clone().zip(register_range(x, y)) {
+            self.memory[address] = self.v[register];
+        }
+        self.wrote(range);
+        Ok(ProgramCounterChange::Next)
+    }
+
+    // LOAD Vx - Vy
+    fn op_5xy3(&mut self, x: usize, y: usize) -> OpResult {
+        let range = self.memory_range(self.i as usize, register_range(x, y).count())?;
+        for (address, register) in range.zip(register_range(x, y)) {
+            self.v[register] = self.memory[address];
+        }
+        Ok(ProgramCounterChange::Next)
     }
 
     // LD Vx, byte
-    fn op_6xkk(&mut self, x: usize, kk: u8) -> ProgramCounterChange {
+    fn op_6xkk(&mut self, x: usize, kk: u8) -> OpResult {
         self.v[x] = kk;
-        ProgramCounterChange::Next
+        Ok(ProgramCounterChange::Next)
     }
 
     //ADD Vx, byte
-    fn op_7xkk(&mut self, x: usize, kk: u8) -> ProgramCounterChange {
+    fn op_7xkk(&mut self, x: usize, kk: u8) -> OpResult {
         self.v[x] = self.v[x].wrapping_add(kk);
-        ProgramCounterChange::Next
+        Ok(ProgramCounterChange::Next)
     }
 
     // LD Vx, Vy
-    fn op_8xy0(&mut self, x: usize, y: usize) -> ProgramCounterChange {
+    fn op_8xy0(&mut self, x: usize, y: usize) -> OpResult {
         self.v[x] = self.v[y];
-        ProgramCounterChange::Next
+        Ok(ProgramCounterChange::Next)
     }
 
     // OR Vx, Vy
-    fn op_8xy1(&mut self, x: usize, y: usize) -> ProgramCounterChange {
-        self.v[x] = self.v[x] | self.v[y];
-        ProgramCounterChange::Next
+    fn op_8xy1(&mut self, x: usize, y: usize) -> OpResult {
+        self.v[x] |= self.v[y];
+        if self.quirks.vf_reset {
+            self.v[0xF] = 0;
+        }
+        Ok(ProgramCounterChange::Next)
     }
 
     // AND Vx, Vy
-    fn op_8xy2(&mut self, x: usize, y: usize) -> ProgramCounterChange {
-        self.v[x] = self.v[x] & self.v[y];
-        ProgramCounterChange::Next
+    fn op_8xy2(&mut self, x: usize, y: usize) -> OpResult {
+        self.v[x] &= self.v[y];
+        if self.quirks.vf_reset {
+            self.v[0xF] = 0;
+        }
+        Ok(ProgramCounterChange::Next)
     }
 
     // XOR Vx, Vy
-    fn op_8xy3(&mut self, x: usize, y: usize) -> ProgramCounterChange {
-        self.v[x] = self.v[x] ^ self.v[y];
-        ProgramCounterChange::Next
+    fn op_8xy3(&mut self, x: usize, y: usize) -> OpResult {
+        self.v[x] ^= self.v[y];
+        if self.quirks.vf_reset {
+            self.v[0xF] = 0;
+        }
+        Ok(ProgramCounterChange::Next)
     }
 
     // ADD Vx, Vy
-    fn op_8xy4(&mut self, x: usize, y: usize) -> ProgramCounterChange {
-        match self.v[x].checked_add(self.v[y]) {
-            Some(res) => {
-                self.v[0xF] = 0;
-                self.v[x] = res as u8;
-            }
-            None => {
-                self.v[0xF] = 1;
-                // We need the lower 8 bits of the result, so calculate as a u16 and convert
-                self.v[x] = (self.v[x] as u16 + self.v[y] as u16) as u8;
-            }
-        }
+    fn op_8xy4(&mut self, x: usize, y: usize) -> OpResult {
+        let (res, overflow) = self.v[x].overflowing_add(self.v[y]);
 
-        ProgramCounterChange::Next
+        // Vf is written last, so it holds the carry even when it is also Vx
+        self.v[x] = res;
+        self.v[0xF] = overflow as u8;
+
+        Ok(ProgramCounterChange::Next)
     }
 
     // SUB Vx, Vy
-    fn op_8xy5(&mut self, x: usize, y: usize) -> ProgramCounterChange {
+    fn op_8xy5(&mut self, x: usize, y: usize) -> OpResult {
         let (res, overflow) = self.v[x].overflowing_sub(self.v[y]);
 
+        // only take the 8 bit value
+        self.v[x] = res;
+
         // update Vf to NOT BORROW, meaning true if there was no borrow, false otherwise
         self.v[0xF] = !overflow as u8;
 
-        // only take the 8 bit value
-        self.v[x] = res as u8;
-
-        ProgramCounterChange::Next
+        Ok(ProgramCounterChange::Next)
     }
 
     // SHR Vx {, Vy}
-    fn op_8x06(&mut self, x: usize) -> ProgramCounterChange {
+    fn op_8xy6(&mut self, x: usize, y: usize) -> OpResult {
+        if self.quirks.shift_uses_vy {
+            self.v[x] = self.v[y];
+        }
         // find the bit value of the rightmost bit, convert to bool
-        self.v[0xF] = self.v[x] & 1;
+        let flag = self.v[x] & 1;
         // only take the 8 bit value
-        self.v[x] = (self.v[x] / 2) as u8;
+        self.v[x] /= 2;
+        self.v[0xF] = flag;
 
-        ProgramCounterChange::Next
+        Ok(ProgramCounterChange::Next)
     }
 
     // SUBN Vx, Vy
-    fn op_8xy7(&mut self, x: usize, y: usize) -> ProgramCounterChange {
+    fn op_8xy7(&mut self, x: usize, y: usize) -> OpResult {
         let (res, overflow) = self.v[y].overflowing_sub(self.v[x]);
 
+        // only take the 8 bit value
+        self.v[x] = res;
+
         // update Vf to NOT BORROW, meaning true if there was no borrow, false otherwise
         self.v[0xF] = !overflow as u8;
 
-        // only take the 8 bit value
-        self.v[x] = res as u8;
-
-        ProgramCounterChange::Next
+        Ok(ProgramCounterChange::Next)
     }
 
     // SHL Vx {, Vy}
-    fn op_8x0e(&mut self, x: usize) -> ProgramCounterChange {
+    fn op_8xye(&mut self, x: usize, y: usize) -> OpResult {
+        if self.quirks.shift_uses_vy {
+            self.v[x] = self.v[y];
+        }
         // find the bit value of the leftmost bit (right 7 spaces for 8 bit int), convert to bool
         // if it is a 1, then set Vf to 1, else 0
-        self.v[0xF] = (self.v[x] & (1 << 7)) >> 7;
+        let flag = (self.v[x] & (1 << 7)) >> 7;
         // only take the 8 bit value
         self.v[x] = (self.v[x] as u16 * 2) as u8;
+        self.v[0xF] = flag;
 
-        ProgramCounterChange::Next
+        Ok(ProgramCounterChange::Next)
     }
 
     // SNE Vx, Vy
-    fn op_9xy0(&mut self, x: usize, y: usize) -> ProgramCounterChange {
+    fn op_9xy0(&mut self, x: usize, y: usize) -> OpResult {
         if self.v[x] != self.v[y] {
-            return ProgramCounterChange::Skip;
+            return Ok(ProgramCounterChange::Skip);
         };
-        ProgramCounterChange::Next
+        Ok(ProgramCounterChange::Next)
     }
 
     // LD I, addr
-    fn op_annn(&mut self, nnn: usize) -> ProgramCounterChange {
+    fn op_annn(&mut self, nnn: usize) -> OpResult {
         self.i = nnn as u16;
-        ProgramCounterChange::Next
+        Ok(ProgramCounterChange::Next)
     }
 
     // JP V0, addr
-    fn op_bnnn(&mut self, nnn: usize) -> ProgramCounterChange {
-        ProgramCounterChange::Jump(self.v[0x0] as u16 + nnn as u16)
+    fn op_bnnn(&mut self, x: usize, nnn: usize) -> OpResult {
+        // CHIP-48 read this as BXNN, jumping to XNN + Vx
+        let offset = if self.quirks.jump_uses_vx { self.v[x] } else { self.v[0x0] };
+        Ok(ProgramCounterChange::Jump(offset as u16 + nnn as u16))
     }
 
     // RND Vx, byte
-    fn op_cxkk(&mut self, x: usize, kk: u8) -> ProgramCounterChange {
-        use rand::Rng;
+    fn op_cxkk(&mut self, x: usize, kk: u8) -> OpResult {
         // generate random value between 0-255, max range of u8
-        let rand_bit: u8 = rand::thread_rng().gen();
+        let rand_bit: u8 = self.random.next_byte(&self.memory);
 
         self.v[x] = kk & rand_bit;
 
-        ProgramCounterChange::Next
+        Ok(ProgramCounterChange::Next)
     }
 
     // DRW Vx, Vy, n
-    fn op_dxyn(&mut self, x: usize, y: usize, n: usize) -> ProgramCounterChange {
+    fn op_dxyn(&mut self, x: usize, y: usize, n: usize) -> OpResult {
         // Display n-byte sprite starting at memory location I at (Vx, Vy), set VF = collision.
         // Vf self.v[0xF]
+        let (width, height) = (self.width(), self.height());
 
-        // get the sprite out of memory by borrowing a slice of memory from i to i + n
-        let sprite = &self.memory[self.i as usize..(self.i + n as u16) as usize];
+        // on SUPER-CHIP DXY0 draws a 16x16 sprite, stored as two bytes per row
+        let (sprite_width, rows) = if n == 0 && self.platform.has_super_chip_opcodes() {
+            (16, 16)
+        } else {
+            (8, n)
+        };
+        let bytes_per_row = sprite_width / 8;
 
-        let vx = self.v[x] as usize;
-        let vy = self.v[y] as usize;
+        // the starting position always wraps, only the pixels past the edge are clipped
+        let vx = self.v[x] as usize % width;
+        let vy = self.v[y] as usize % height;
         let mut collision = false;
 
-        // traverse ever memory address (each represents a row)
-        for j in 0..sprite.len() {
-            let row = &sprite[j];
-            // go through each bit of the address
-            for i in 0..8 {
-                // starting with the left most bit, shift the bit all the way right
-                // determine if its a 1 or 0
-                let new_pixel_value: u32 = (row >> (7 - i) & 0x01 ).into();
-                
-                // determine the coordinates for the pixel
-                // and check if it needs to wrap around the display
-                let x_target = (vx + i) % SCREEN_WIDTH;
-                let y_target = (vy + j) % SCREEN_HEIGHT;
-                let old_value = self.display[y_target][x_target];
-
-                // detect collision
-                if old_value == 1 && new_pixel_value == 1{
+        // XO-CHIP draws the sprite once per selected plane, each plane's data follows the last
+        let sprite_len = rows * bytes_per_row;
+        let mut start = self.i as usize;
+        for plane in 0..2 {
+            let plane_bit = 1 << plane;
+            if self.planes & plane_bit == 0 {
+                continue;
+            }
+
+            // get the sprite out of memory by borrowing a slice of memory from i to the end of the sprite
+            let range = self.memory_range(start, sprite_len)?;
+            start += sprite_len;
+            let sprite = &self.memory[range];
+
+            // traverse every row of the sprite
+            for (j, row_bytes) in sprite.chunks(bytes_per_row).enumerate() {
+                if self.quirks.clip_sprites && vy + j >= height {
+                    break;
+                }
+                // 8 pixel wide rows go in the top byte
+                let row = row_bytes.iter().fold(0u16, |row, byte| row << 8 | *byte as u16) << (16 - sprite_width);
+                // the whole row is shifted into place and XORed onto the plane at once
+                let y_target = (vy + j) % height;
+                if self.display.draw_row(plane, vx, y_target, row, width, self.quirks.clip_sprites) {
                     collision = true;
                 }
-
-                // draw value on the display (XOR the current value and the new value)
-                self.display[y_target][x_target] = old_value ^ new_pixel_value;
             }
         }
 
@@ -493,89 +1079,153 @@ impl Cpu {
             self.v[0xF] = 0;
         }
 
-        ProgramCounterChange::Next
+        if self.quirks.display_wait {
+            self.waiting_for_display = true;
+        }
+
+        Ok(ProgramCounterChange::Next)
     }
 
     // SKP Vx
-    fn op_ex9e(&mut self, x: usize) -> ProgramCounterChange {
+    fn op_ex9e(&mut self, x: usize) -> OpResult {
         if self.keyboard.key_is_pressed(self.v[x]) {
             //self.keyboard.un_press_key(self.v[x]);
-            return ProgramCounterChange::Skip;
+            return Ok(ProgramCounterChange::Skip);
         };
-        ProgramCounterChange::Next
+        Ok(ProgramCounterChange::Next)
     }
 
     // SKNP Vx
-    fn op_exa1(&mut self, x: usize) -> ProgramCounterChange {
+    fn op_exa1(&mut self, x: usize) -> OpResult {
         if !self.keyboard.key_is_pressed(self.v[x]) {
-            return ProgramCounterChange::Skip;
+            return Ok(ProgramCounterChange::Skip);
         };
         //self.keyboard.un_press_key(self.v[x]);
-        ProgramCounterChange::Next
+        Ok(ProgramCounterChange::Next)
+    }
+
+    // LD I, long addr
+    fn op_f000(&mut self) -> OpResult {
+        // the address is the word following the instruction
+        let range = self.memory_range(self.pc as usize + OP_SIZE as usize, 2)?;
+        let addr = &self.memory[range];
+        self.i = (addr[0] as u16) << 8 | addr[1] as u16;
+        Ok(ProgramCounterChange::Jump(self.pc.wrapping_add(2 * OP_SIZE)))
+    }
+
+    // PLANE n
+    fn op_fn01(&mut self, n: usize) -> OpResult {
+        self.planes = (n & 0x03) as u8;
+        Ok(ProgramCounterChange::Next)
+    }
+
+    // AUDIO
+    fn op_f002(&mut self) -> OpResult {
+        let range = self.memory_range(self.i as usize, self.audio_pattern.len())?;
+        self.audio_pattern.copy_from_slice(&self.memory[range]);
+        Ok(ProgramCounterChange::Next)
     }
 
     // LD Vx, DT
-    fn op_fx07(&mut self, x: usize) -> ProgramCounterChange {
+    fn op_fx07(&mut self, x: usize) -> OpResult {
         self.v[x] = self.dt;
-        ProgramCounterChange::Next
+        Ok(ProgramCounterChange::Next)
     }
 
     // LD Vx, K
-    fn op_fx0a(&mut self, x: usize) -> ProgramCounterChange {
-        // pause the CPU, and set our target memory location once there is a key press
-        self.paused = true;
-        self.kt = x as u8;
+    fn op_fx0a(&mut self, x: usize) -> OpResult {
+        // pause the CPU, the key goes in Vx once there is a key press
+        self.key_wait = KeyWait::Press { x: x as u8 };
         self.previous_keys = self.keyboard.keys;
 
-        ProgramCounterChange::Next
+        Ok(ProgramCounterChange::Next)
     }
 
     // LD DT, Vx
-    fn op_fx15(&mut self, x: usize) -> ProgramCounterChange {
+    fn op_fx15(&mut self, x: usize) -> OpResult {
         self.dt = self.v[x];
-        ProgramCounterChange::Next
+        Ok(ProgramCounterChange::Next)
     }
 
     // LD ST, Vx
-    fn op_fx18(&mut self, x: usize) -> ProgramCounterChange {
+    fn op_fx18(&mut self, x: usize) -> OpResult {
         self.st = self.v[x];
-        ProgramCounterChange::Next
+        Ok(ProgramCounterChange::Next)
     }
 
     // ADD I, Vx
-    fn op_fx1e(&mut self, x: usize) -> ProgramCounterChange {
-        self.i += self.v[x] as u16;
-        ProgramCounterChange::Next
+    fn op_fx1e(&mut self, x: usize) -> OpResult {
+        // I is only checked against the end of memory when it is used
+        self.i = self.i.wrapping_add(self.v[x] as u16);
+        Ok(ProgramCounterChange::Next)
     }
+
     // LD F, Vx
-    fn op_fx29(&mut self, x: usize) -> ProgramCounterChange {
+    fn op_fx29(&mut self, x: usize) -> OpResult {
         // each sprite is 5 bytes long, the * 5 offsets to x's sprite location
-        self.i = (self.v[x] * 5) as u16;
-        ProgramCounterChange::Next
+        // only the low nibble names a hex digit
+        self.i = (self.v[x] & 0x0F) as u16 * 5;
+        Ok(ProgramCounterChange::Next)
     }
 
     // LD B, Vx
-    fn op_fx33(&mut self, x: usize) -> ProgramCounterChange {
-        self.memory[self.i as usize] = self.v[x] / 100; // max value is 255 so no concern about remainders
-        self.memory[(self.i + 1) as usize] = (self.v[x] / 10) % 10; // divide by 10, take the first digit
-        self.memory[(self.i + 2) as usize] = self.v[x] % 10; // take the first digit
-        ProgramCounterChange::Next
+    fn op_fx33(&mut self, x: usize) -> OpResult {
+        let range = self.memory_range(self.i as usize, 3)?;
+        let digits = &mut self.memory[range.clone()];
+        digits[0] = self.v[x] / 100; // max value is 255 so no concern about remainders
+        digits[1] = (self.v[x] / 10) % 10; // divide by 10, take the first digit
+        digits[2] = self.v[x] % 10; // take the first digit
+        self.wrote(range);
+        Ok(ProgramCounterChange::Next)
     }
 
     // LD [I], Vx
-    fn op_fx55(&mut self, x: usize) -> ProgramCounterChange {
-        for i in 0..x + 1 {
-            self.memory[self.i as usize + i] = self.v[i];
-        };
-        ProgramCounterChange::Next
+    fn op_fx55(&mut self, x: usize) -> OpResult {
+        let range = self.memory_range(self.i as usize, x + 1)?;
+        self.memory[range.clone()].copy_from_slice(&self.v[..x + 1]);
+        self.wrote(range);
+        if self.quirks.load_store_increments_i {
+            self.i = self.i.wrapping_add(x as u16 + 1);
+        }
+        Ok(ProgramCounterChange::Next)
+    }
+
+    // LD HF, Vx
+    fn op_fx30(&mut self, x: usize) -> OpResult {
+        // each big font sprite is 10 bytes long
+        self.i = (BIG_FONT_START_INDEX + (self.v[x] as usize & 0x0F) * 10) as u16;
+        Ok(ProgramCounterChange::Next)
+    }
+
+    // PITCH Vx
+    fn op_fx3a(&mut self, x: usize) -> OpResult {
+        self.pitch = self.v[x];
+        Ok(ProgramCounterChange::Next)
+    }
+
+    // LD R, Vx
+    fn op_fx75(&mut self, x: usize) -> OpResult {
+        // SUPER-CHIP only has 8 flag registers, XO-CHIP has 16
+        let count = (x + 1).min(self.platform.rpl_flags());
+        self.rpl[..count].copy_from_slice(&self.v[..count]);
+        Ok(ProgramCounterChange::Next)
+    }
+
+    // LD Vx, R
+    fn op_fx85(&mut self, x: usize) -> OpResult {
+        let count = (x + 1).min(self.platform.rpl_flags());
+        self.v[..count].copy_from_slice(&self.rpl[..count]);
+        Ok(ProgramCounterChange::Next)
     }
 
     // LD Vx, [I]
-    fn op_fx65(&mut self, x: usize) -> ProgramCounterChange {
-        for i in 0..x + 1 {
-            self.v[i] = self.memory[self.i as usize + i];
-        };
-        ProgramCounterChange::Next
+    fn op_fx65(&mut self, x: usize) -> OpResult {
+        let range = self.memory_range(self.i as usize, x + 1)?;
+        self.v[..x + 1].copy_from_slice(&self.memory[range]);
+        if self.quirks.load_store_increments_i {
+            self.i = self.i.wrapping_add(x as u16 + 1);
+        }
+        Ok(ProgramCounterChange::Next)
     }
 
 }
@@ -583,11 +1233,12 @@ impl Cpu {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::random::VipRandom;
 
     #[test]
     fn opcode_jp() {
         let mut chip: Cpu = Cpu::new();
-        chip.handle_opcode(0x1A2B);
+        chip.handle_opcode(0x1A2B).unwrap();
         assert_eq!(chip.pc, 0x0A2B, "program counter was updated");
     }
 
@@ -597,21 +1248,21 @@ mod tests {
         chip.sp += 1;
         chip.stack[0] = 1234;
 
-        chip.handle_opcode(0x00EE);
+        chip.handle_opcode(0x00EE).unwrap();
         assert_eq!(chip.sp, 0x0000, "stack pointer was updated");
         assert_eq!(chip.pc, 1234, "program counter was updated");
     }
 
     #[test]
     fn opcode_2nnn() {
-        let opcode = 0x2123;
-        let nnn = (opcode & 0x0FFF) as u16;
+        let opcode: u16 = 0x2123;
+        let nnn = opcode & 0x0FFF;
         let mut chip: Cpu = Cpu::new();
-        chip.pc += 10;
+        chip.pc = 8;
         chip.sp += 1;
         chip.stack[10] = 1234;
 
-        chip.handle_opcode(opcode);
+        chip.handle_opcode(opcode).unwrap();
         assert_eq!(chip.stack[1], 10, "stack was updated");
         assert_eq!(chip.sp, 2, "stack pointer was updated");
         assert_eq!(chip.pc, nnn, "program counter was updated");
@@ -621,14 +1272,15 @@ mod tests {
     fn opcode_5xy0() {
         let mut chip: Cpu = Cpu::new();
         let opcode1 = 0x5230;
+        chip.pc = 0;
 
         chip.v[2] = 2;
         chip.v[3] = 2;
-        chip.handle_opcode(opcode1);
+        chip.handle_opcode(opcode1).unwrap();
         assert_eq!(chip.pc, 4, "program counter skipped an instruction");
 
         chip.v[3] = 3;
-        chip.handle_opcode(opcode1);
+        chip.handle_opcode(opcode1).unwrap();
         assert_eq!(chip.pc, 6, "program counter updated correctly");
     }
 
@@ -639,14 +1291,14 @@ mod tests {
         chip.v[1] = 254;
         chip.v[2] = 3;
 
-        chip.handle_opcode(opcode);
+        chip.handle_opcode(opcode).unwrap();
         assert_eq!(chip.v[0xF], 1, "overflow was detected, vf was updated");
         assert_eq!(chip.v[1], 1, "register Vx was updated");
 
         chip.reset();
         chip.v[1] = 251;
         chip.v[2] = 1;
-        chip.handle_opcode(opcode);
+        chip.handle_opcode(opcode).unwrap();
         assert_eq!(chip.v[0xF], 0, "no overflow occurred, vf was updated");
         assert_eq!(chip.v[1], 252, "register Vx was updated");
     }
@@ -658,7 +1310,7 @@ mod tests {
         chip.v[1] = 0;
         chip.v[2] = 1;
 
-        chip.handle_opcode(opcode);
+        chip.handle_opcode(opcode).unwrap();
         assert_eq!(
             chip.v[0xF], 0,
             "overflow was detected, vf was updated to NOT BORROW"
@@ -668,7 +1320,7 @@ mod tests {
         chip.reset();
         chip.v[1] = 3;
         chip.v[2] = 1;
-        chip.handle_opcode(opcode);
+        chip.handle_opcode(opcode).unwrap();
         assert_eq!(
             chip.v[0xF], 1,
             "no overflow occurred, vf was updated to NOT BORROW"
@@ -682,13 +1334,13 @@ mod tests {
         let mut chip: Cpu = Cpu::new();
         chip.v[1] = 5;
 
-        chip.handle_opcode(opcode);
+        chip.handle_opcode(opcode).unwrap();
         assert_eq!(chip.v[0xF], 1, "least significant bit is 1, Vf was updated");
         assert_eq!(chip.v[1], 2, "register Vx was updated");
 
         chip.reset();
         chip.v[1] = 2;
-        chip.handle_opcode(opcode);
+        chip.handle_opcode(opcode).unwrap();
         assert_eq!(chip.v[0xF], 0, "lest significant bit is 0, Vf was updated");
         assert_eq!(chip.v[1], 1, "register Vx was updated");
     }
@@ -700,7 +1352,7 @@ mod tests {
         chip.v[1] = 1;
         chip.v[2] = 0;
 
-        chip.handle_opcode(opcode);
+        chip.handle_opcode(opcode).unwrap();
         assert_eq!(
             chip.v[0xF], 0,
             "overflow was detected, vf was updated to NOT BORROW"
@@ -710,7 +1362,7 @@ mod tests {
         chip.reset();
         chip.v[1] = 1;
         chip.v[2] = 3;
-        chip.handle_opcode(opcode);
+        chip.handle_opcode(opcode).unwrap();
         assert_eq!(
             chip.v[0xF], 1,
             "no overflow occurred, vf was updated to NOT BORROW"
@@ -724,7 +1376,7 @@ mod tests {
         let mut chip: Cpu = Cpu::new();
         chip.v[1] = 128;
 
-        chip.handle_opcode(opcode);
+        chip.handle_opcode(opcode).unwrap();
         assert_eq!(chip.v[0xF], 1, "Most significant bit is 1, Vf was updated");
         assert_eq!(
             chip.v[1], 0,
@@ -733,8 +1385,712 @@ mod tests {
 
         chip.reset();
         chip.v[1] = 2;
-        chip.handle_opcode(opcode);
+        chip.handle_opcode(opcode).unwrap();
         assert_eq!(chip.v[0xF], 0, "most significant bit is 0, Vf was updated");
         assert_eq!(chip.v[1], 4, "register Vx was updated");
     }
+
+    #[test]
+    fn flag_written_after_result() {
+        let mut chip: Cpu = Cpu::new();
+        chip.v[0xF] = 5;
+        chip.v[1] = 3;
+        chip.handle_opcode(0x8F15).unwrap();
+        assert_eq!(chip.v[0xF], 1, "SUB VF, V1 leaves the flag, not the difference");
+
+        chip.v[0xF] = 0xFF;
+        chip.handle_opcode(0x8FF4).unwrap();
+        assert_eq!(chip.v[0xF], 1, "ADD VF, VF leaves the carry");
+    }
+
+    #[test]
+    fn quirk_shift_uses_vy() {
+        let mut chip: Cpu = Cpu::with_quirks(Quirks::cosmac_vip());
+        chip.v[1] = 5;
+        chip.v[2] = 0x81;
+
+        chip.handle_opcode(0x8126).unwrap();
+        assert_eq!(chip.v[1], 0x40, "Vy was shifted into Vx");
+        assert_eq!(chip.v[0xF], 1, "Vf holds the bit shifted out of Vy");
+
+        chip.handle_opcode(0x812e).unwrap();
+        assert_eq!(chip.v[1], 0x02, "Vy was shifted into Vx");
+        assert_eq!(chip.v[0xF], 1, "Vf holds the bit shifted out of Vy");
+    }
+
+    #[test]
+    fn quirk_load_store_increments_i() {
+        let mut chip: Cpu = Cpu::new();
+        chip.i = 0x300;
+        chip.handle_opcode(0xF255).unwrap();
+        assert_eq!(chip.i, 0x300, "I was left alone by default");
+
+        chip.set_quirks(Quirks::cosmac_vip());
+        chip.handle_opcode(0xF255).unwrap();
+        assert_eq!(chip.i, 0x303, "I was moved past the stored registers");
+        chip.handle_opcode(0xF165).unwrap();
+        assert_eq!(chip.i, 0x305, "I was moved past the loaded registers");
+    }
+
+    #[test]
+    fn quirk_jump_uses_vx() {
+        let mut chip: Cpu = Cpu::with_quirks(Quirks::chip48());
+        chip.v[0] = 1;
+        chip.v[2] = 4;
+
+        chip.handle_opcode(0xB230).unwrap();
+        assert_eq!(chip.pc, 0x234, "jumped to XNN + Vx");
+
+        chip.set_quirks(Quirks::modern());
+        chip.handle_opcode(0xB230).unwrap();
+        assert_eq!(chip.pc, 0x231, "jumped to NNN + V0");
+    }
+
+    #[test]
+    fn quirk_vf_reset() {
+        let mut chip: Cpu = Cpu::new();
+        chip.v[0xF] = 1;
+        chip.handle_opcode(0x8121).unwrap();
+        assert_eq!(chip.v[0xF], 1, "Vf was left alone by default");
+
+        chip.set_quirks(Quirks::cosmac_vip());
+        chip.handle_opcode(0x8122).unwrap();
+        assert_eq!(chip.v[0xF], 0, "Vf was reset by the logic operation");
+    }
+
+    #[test]
+    fn quirk_clip_sprites() {
+        let mut chip: Cpu = Cpu::new();
+        chip.i = 0x300;
+        chip.memory[0x300] = 0xFF;
+        chip.v[0] = 60;
+
+        chip.handle_opcode(0xD011).unwrap();
+        assert_eq!(chip.display.pixel(0, 0), 1, "sprite wrapped around the screen");
+
+        chip.reset();
+        chip.set_quirks(Quirks::cosmac_vip());
+        chip.i = 0x300;
+        chip.memory[0x300] = 0xFF;
+        chip.v[0] = 60;
+
+        chip.handle_opcode(0xD011).unwrap();
+        assert_eq!(chip.display.pixel(63, 0), 1, "sprite was drawn up to the edge");
+        assert_eq!(chip.display.pixel(0, 0), 0, "sprite was clipped at the edge");
+    }
+
+    #[test]
+    fn quirk_display_wait() {
+        let mut chip: Cpu = Cpu::with_quirks(Quirks::cosmac_vip());
+        // DRW V0, V0, 1 followed by LD V1, 0x01
+        chip.memory[0x200..0x204].copy_from_slice(&[0xD0, 0x01, 0x61, 0x01]);
+
+        chip.run_frame(10).unwrap();
+        assert_eq!(chip.v[1], 0, "the rest of the frame waited for the display");
+
+        chip.run_frame(10).unwrap();
+        assert_eq!(chip.v[1], 1, "execution resumed after the display refresh");
+    }
+
+    #[test]
+    fn key_skips_use_the_low_nibble() {
+        let mut chip: Cpu = Cpu::new();
+        chip.v[1] = 0xFF;
+
+        chip.pc = 0x200;
+        chip.handle_opcode(0xE19E).unwrap();
+        assert_eq!(chip.pc, 0x202, "key F is up, no skip");
+        chip.handle_opcode(0xE1A1).unwrap();
+        assert_eq!(chip.pc, 0x206, "key F is up, skipped");
+
+        chip.keyboard.press_key(0xF);
+        chip.handle_opcode(0xE19E).unwrap();
+        assert_eq!(chip.pc, 0x20A, "key F is down, skipped");
+        chip.handle_opcode(0xE1A1).unwrap();
+        assert_eq!(chip.pc, 0x20C, "key F is down, no skip");
+    }
+
+    #[test]
+    fn new_has_no_quirks_unlike_the_chip8_platform() {
+        assert_eq!(Cpu::new().quirks(), Quirks::default(), "every quirk off");
+        assert_eq!(Cpu::with_platform(Platform::Chip8).quirks(), Quirks::cosmac_vip());
+        assert_eq!(Cpu::new().platform(), Platform::Chip8, "both are CHIP-8 all the same");
+    }
+
+    #[test]
+    fn super_chip_resolution() {
+        let mut chip: Cpu = Cpu::with_platform(Platform::SuperChip);
+        // clear on switching resolution and scroll by lo-res pixels, see the quirk tests for the rest
+        chip.set_quirks(Quirks::chip48());
+        chip.display.set_pixel(0, 0, 1);
+
+        chip.handle_opcode(0x00FF).unwrap();
+        assert!(chip.is_hires(), "switched to hi-res");
+        assert_eq!((chip.width(), chip.height()), (128, 64), "display is 128x64");
+        assert_eq!(chip.display.pixel(0, 0), 0, "display was cleared");
+
+        chip.handle_opcode(0x00FE).unwrap();
+        assert_eq!((chip.width(), chip.height()), (64, 32), "display is 64x32");
+
+        let mut chip: Cpu = Cpu::new();
+        chip.handle_opcode(0x00FF).unwrap();
+        assert!(!chip.is_hires(), "CHIP-8 ignores the SUPER-CHIP opcodes");
+    }
+
+    #[test]
+    fn version_2_states_still_load() {
+        let mut chip: Cpu = Cpu::with_quirks(Quirks::cosmac_vip());
+        let saved = chip.save_state();
+        chip.set_quirks(Quirks::default());
+
+        // version 2 had a byte for the quirks, and no random source id before its 8 byte state
+        let mut old = saved.clone();
+        old[8] = 2;
+        old.remove(old.len() - 13);
+        old.remove(12);
+        chip.load_state(&old).unwrap();
+        assert_eq!(chip.quirks(), Quirks::cosmac_vip());
+        assert_eq!(chip.save_state(), saved, "upgraded to the same state");
+    }
+
+    #[test]
+    fn version_3_states_still_load() {
+        let mut chip: Cpu = Cpu::new();
+        chip.set_random_source(Box::new(VipRandom::new(0x1234)));
+        let saved = chip.save_state();
+
+        // version 3 had no random source id, before the VIP generator's 2 byte state
+        let mut old = saved.clone();
+        old[8] = 3;
+        assert_eq!(old.remove(old.len() - 7), crate::random::VIP_ID);
+        chip.load_state(&old).unwrap();
+        assert_eq!(chip.save_state(), saved, "upgraded to the same state");
+    }
+
+    #[test]
+    fn quirk_resolution_keeps_display() {
+        let mut chip: Cpu = Cpu::with_platform(Platform::SuperChip);
+        chip.set_quirks(Quirks::chip48());
+        chip.display.set_pixel(3, 3, 1);
+        chip.handle_opcode(0x00FF).unwrap();
+        assert_eq!(chip.display.pixel(3, 3), 0, "switching resolution cleared the display");
+
+        chip.set_quirks(Quirks::super_chip());
+        chip.display.set_pixel(3, 3, 1);
+        chip.handle_opcode(0x00FE).unwrap();
+        assert!(!chip.is_hires());
+        assert_eq!(chip.display.pixel(3, 3), 1, "switching resolution left the display alone");
+    }
+
+    #[test]
+    fn quirk_lores_scroll_halved() {
+        let mut chip: Cpu = Cpu::with_platform(Platform::SuperChip);
+        chip.set_quirks(Quirks::chip48());
+        chip.display.set_pixel(0, 0, 1);
+        chip.handle_opcode(0x00C2).unwrap();
+        chip.handle_opcode(0x00FB).unwrap();
+        assert_eq!(chip.display.pixel(4, 2), 1, "scrolled by lo-res pixels");
+
+        chip.set_quirks(Quirks::super_chip());
+        chip.handle_opcode(0x00C2).unwrap();
+        chip.handle_opcode(0x00FB).unwrap();
+        assert_eq!(chip.display.pixel(6, 3), 1, "scrolled by half as many lo-res pixels");
+        chip.handle_opcode(0x00C1).unwrap();
+        assert_eq!(chip.display.pixel(6, 3), 1, "half a pixel rounded down to nothing");
+
+        chip.handle_opcode(0x00FF).unwrap();
+        chip.display.set_pixel(0, 0, 1);
+        chip.handle_opcode(0x00C2).unwrap();
+        assert_eq!(chip.display.pixel(0, 2), 1, "hi-res scrolls are not halved");
+    }
+
+    #[test]
+    fn super_chip_scroll() {
+        let mut chip: Cpu = Cpu::with_platform(Platform::SuperChip);
+        // clear on switching resolution and scroll by lo-res pixels, see the quirk tests for the rest
+        chip.set_quirks(Quirks::chip48());
+        chip.display.set_pixel(4, 0, 1);
+
+        chip.handle_opcode(0x00C2).unwrap();
+        assert_eq!(chip.display.pixel(4, 2), 1, "scrolled down 2 pixels");
+        assert_eq!(chip.display.pixel(4, 0), 0, "blank row scrolled in");
+
+        chip.handle_opcode(0x00FB).unwrap();
+        assert_eq!(chip.display.pixel(8, 2), 1, "scrolled right 4 pixels");
+
+        chip.handle_opcode(0x00FC).unwrap();
+        chip.handle_opcode(0x00FC).unwrap();
+        assert_eq!(chip.display.pixel(0, 2), 1, "scrolled left 8 pixels");
+        assert_eq!(chip.display.pixel(8, 2), 0, "old pixel was scrolled away");
+    }
+
+    #[test]
+    fn super_chip_big_sprite() {
+        let mut chip: Cpu = Cpu::with_platform(Platform::SuperChip);
+        // wrap sprites instead of clipping them
+        chip.set_quirks(Quirks::default());
+        chip.handle_opcode(0x00FF).unwrap();
+        chip.i = 0x300;
+        chip.memory[0x300] = 0x80;
+        chip.memory[0x301] = 0x01;
+        chip.memory[0x31F] = 0x01;
+        chip.v[0] = 120;
+
+        chip.handle_opcode(0xD010).unwrap();
+        assert_eq!(chip.display.pixel(120, 0), 1, "left edge of the first row");
+        assert_eq!(chip.display.pixel(127, 0), 0, "first byte only has its top bit set");
+        assert_eq!(chip.display.pixel(7, 0), 1, "right edge of the first row wrapped");
+        assert_eq!(chip.display.pixel(7, 15), 1, "right edge of the last row wrapped");
+        assert_eq!(chip.v[0xF], 0, "no collision");
+    }
+
+    #[test]
+    fn super_chip_big_font() {
+        let mut chip: Cpu = Cpu::with_platform(Platform::SuperChip);
+        chip.v[3] = 2;
+        chip.handle_opcode(0xF330).unwrap();
+        assert_eq!(chip.i, 0x50 + 20, "I points at the big 2");
+        assert_eq!(chip.memory[chip.i as usize], 0x3E, "big font is loaded");
+    }
+
+    #[test]
+    fn super_chip_rpl_flags() {
+        let mut chip: Cpu = Cpu::with_platform(Platform::SuperChip);
+        chip.v[0] = 7;
+        chip.v[1] = 9;
+        chip.handle_opcode(0xF175).unwrap();
+        chip.v[0] = 0;
+        chip.v[1] = 0;
+
+        chip.handle_opcode(0xF185).unwrap();
+        assert_eq!((chip.v[0], chip.v[1]), (7, 9), "registers were restored");
+    }
+
+    #[test]
+    fn super_chip_exit() {
+        let mut chip: Cpu = Cpu::with_platform(Platform::SuperChip);
+        chip.memory[0x200..0x204].copy_from_slice(&[0x00, 0xFD, 0x61, 0x01]);
+
+        chip.execute_cycle().unwrap();
+        chip.execute_cycle().unwrap();
+        assert!(chip.has_exited(), "the program exited");
+        assert_eq!(chip.v[1], 0, "no instructions ran after exiting");
+    }
+
+    #[test]
+    fn xo_chip_memory() {
+        let chip: Cpu = Cpu::with_platform(Platform::XoChip);
+        assert_eq!(chip.memory.len(), 0x10000, "XO-CHIP has 64 KiB of memory");
+    }
+
+    #[test]
+    fn xo_chip_long_load() {
+        let mut chip: Cpu = Cpu::with_platform(Platform::XoChip);
+        chip.memory[0x200..0x204].copy_from_slice(&[0xF0, 0x00, 0xAB, 0xCD]);
+
+        chip.execute_cycle().unwrap();
+        assert_eq!(chip.i, 0xABCD, "I was loaded with a 16 bit address");
+        assert_eq!(chip.pc, 0x204, "program counter moved past the address");
+
+        // SE V0, 0 skips the whole long load
+        chip.pc = 0x1FE;
+        chip.memory[0x1FE..0x200].copy_from_slice(&[0x30, 0x00]);
+        chip.execute_cycle().unwrap();
+        assert_eq!(chip.pc, 0x204, "skipped both words of the long load");
+    }
+
+    #[test]
+    fn xo_chip_register_range() {
+        let mut chip: Cpu = Cpu::with_platform(Platform::XoChip);
+        chip.i = 0x300;
+        chip.v[2] = 1;
+        chip.v[3] = 2;
+        chip.v[4] = 3;
+
+        chip.handle_opcode(0x5242).unwrap();
+        assert_eq!(&chip.memory[0x300..0x303], &[1, 2, 3], "V2 - V4 were saved");
+        assert_eq!(chip.i, 0x300, "I was left unchanged");
+
+        chip.handle_opcode(0x5422).unwrap();
+        assert_eq!(&chip.memory[0x300..0x303], &[3, 2, 1], "V4 - V2 were saved in reverse");
+
+        chip.handle_opcode(0x5A83).unwrap();
+        assert_eq!((chip.v[0xA], chip.v[0x9], chip.v[0x8]), (3, 2, 1), "VA - V8 were loaded");
+    }
+
+    #[test]
+    fn xo_chip_planes() {
+        let mut chip: Cpu = Cpu::with_platform(Platform::XoChip);
+        chip.i = 0x300;
+        chip.memory[0x300] = 0x80;
+        chip.memory[0x301] = 0xC0;
+
+        // draw on both planes, the second plane's row follows the first
+        chip.handle_opcode(0xF301).unwrap();
+        chip.handle_opcode(0xD011).unwrap();
+        assert_eq!(chip.display.pixel(0, 0), 3, "pixel is set on both planes");
+        assert_eq!(chip.display.pixel(1, 0), 2, "pixel is only set on the second plane");
+
+        // clearing the first plane leaves the second alone
+        chip.handle_opcode(0xF101).unwrap();
+        chip.handle_opcode(0x00E0).unwrap();
+        assert_eq!(chip.display.pixel(0, 0), 2, "first plane was cleared");
+
+        chip.handle_opcode(0xF201).unwrap();
+        chip.handle_opcode(0xD011).unwrap();
+        assert_eq!(chip.v[0xF], 1, "collision on the second plane");
+    }
+
+    #[test]
+    fn xo_chip_audio() {
+        let mut chip: Cpu = Cpu::with_platform(Platform::XoChip);
+        chip.i = 0x300;
+        chip.memory[0x300..0x310].copy_from_slice(&[0xAA; 16]);
+        chip.v[1] = 112;
+
+        chip.handle_opcode(0xF002).unwrap();
+        chip.handle_opcode(0xF13A).unwrap();
+        assert_eq!(chip.audio_pattern(), &[0xAA; 16], "pattern was copied into the buffer");
+        assert_eq!(chip.audio_playback_rate(), 8000.0, "pitch raised by an octave");
+    }
+
+    #[test]
+    fn error_stack_underflow() {
+        let mut chip: Cpu = Cpu::new();
+        match chip.handle_opcode(0x00EE) {
+            Err(CpuError::StackUnderflow { pc, opcode }) => {
+                assert_eq!((pc, opcode), (0x200, 0x00EE), "fault records the pc and opcode");
+            }
+            other => panic!("expected a stack underflow, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn error_stack_overflow() {
+        let mut chip: Cpu = Cpu::new();
+        for _ in 0..16 {
+            chip.handle_opcode(0x2200).unwrap();
+        }
+        match chip.handle_opcode(0x2200) {
+            Err(CpuError::StackOverflow { .. }) => {}
+            other => panic!("expected a stack overflow, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn error_memory_out_of_bounds() {
+        let mut chip: Cpu = Cpu::new();
+        chip.i = 0xFFE;
+        match chip.handle_opcode(0xD005) {
+            Err(CpuError::MemoryOutOfBounds { address, .. }) => {
+                assert_eq!(address, 0x1002, "fault records the last address the sprite needed");
+            }
+            other => panic!("expected an out of bounds access, got {:?}", other),
+        }
+
+        chip.i = 0xFFF;
+        chip.handle_opcode(0xF21E).unwrap();
+        assert!(chip.handle_opcode(0xF255).is_err(), "FX55 can not store past the end of memory");
+
+        chip.pc = 0xFFF;
+        assert!(chip.execute_cycle().is_err(), "fetch can not read past the end of memory");
+    }
+
+    #[test]
+    fn error_unknown_opcode() {
+        let mut chip: Cpu = Cpu::new();
+        match chip.handle_opcode(0x5121) {
+            Err(CpuError::UnknownOpcode { opcode, .. }) => assert_eq!(opcode, 0x5121),
+            other => panic!("expected an unknown opcode, got {:?}", other),
+        }
+        chip.handle_opcode(0x0123).unwrap();
+        assert_eq!(chip.pc, 0x202, "SYS addr is ignored");
+    }
+
+    #[test]
+    fn opcode_fx29_ignores_high_nibble() {
+        let mut chip: Cpu = Cpu::new();
+        chip.v[1] = 0xFA;
+        chip.handle_opcode(0xF129).unwrap();
+        assert_eq!(chip.i, 0x0A * 5, "I points at the font sprite for A");
+    }
+
+    #[test]
+    fn error_rom_too_large() {
+        let path = std::env::temp_dir().join("rusty_chip_rom_too_large.ch8");
+        std::fs::write(&path, vec![0; 4096]).unwrap();
+
+        let mut chip: Cpu = Cpu::new();
+        let result = chip.load_game(&path);
+        std::fs::remove_file(&path).unwrap();
+        match result {
+            Err(CpuError::RomTooLarge { size, max }) => assert_eq!((size, max), (4096, 3584)),
+            other => panic!("expected the rom to be too large, got {:?}", other),
+        }
+
+        match chip.load_game("roms/DOES_NOT_EXIST") {
+            Err(CpuError::Io(_)) => {}
+            other => panic!("expected an io error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn load_rom_bytes() {
+        let mut chip: Cpu = Cpu::new();
+        chip.load_rom_bytes(&[0x61, 0x05]).unwrap();
+        assert_eq!(&chip.memory[0x200..0x202], &[0x61, 0x05], "rom was loaded at 0x200");
+
+        chip.execute_cycle().unwrap();
+        assert_eq!(chip.v[1], 5, "rom was run from 0x200");
+
+        assert!(chip.load_rom_bytes(&[0; 3584]).is_ok(), "rom fills memory exactly");
+    }
+
+    #[test]
+    fn load_rom_from_reader() {
+        let mut chip: Cpu = Cpu::new();
+        chip.load_rom_from(&[0x12, 0x34][..]).unwrap();
+        assert_eq!(&chip.memory[0x200..0x202], &[0x12, 0x34], "rom was read from the reader");
+    }
+
+    #[test]
+    fn load_address() {
+        let mut chip: Cpu = Cpu::new();
+        chip.set_load_address(ETI_660_START_INDEX as u16);
+        chip.load_rom_bytes(&[0x62, 0x07]).unwrap();
+        assert_eq!(&chip.memory[0x600..0x602], &[0x62, 0x07], "rom was loaded at 0x600");
+
+        chip.execute_cycle().unwrap();
+        assert_eq!(chip.v[2], 7, "rom was run from 0x600");
+
+        chip.reset();
+        assert_eq!(chip.pc, 0x600, "reset returns to the entry point");
+
+        match chip.load_rom_bytes(&[0; 3000]) {
+            Err(CpuError::RomTooLarge { max, .. }) => assert_eq!(max, 2560),
+            other => panic!("expected the rom to be too large, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn entry_point() {
+        let mut chip: Cpu = Cpu::new();
+        chip.set_entry_point(0x202);
+        chip.load_rom_bytes(&[0x00, 0x00, 0x63, 0x01]).unwrap();
+
+        chip.execute_cycle().unwrap();
+        assert_eq!(chip.v[3], 1, "execution started at the entry point");
+    }
+
+    #[test]
+    fn timers_tick_once_per_frame() {
+        let mut chip: Cpu = Cpu::new();
+        // LD V0, 10; LD DT, V0; then loop forever
+        chip.load_rom_bytes(&[0x60, 0x0A, 0xF0, 0x15, 0x12, 0x04]).unwrap();
+
+        for _ in 0..20 {
+            chip.execute_cycle().unwrap();
+        }
+        assert_eq!(chip.delay_timer(), 10, "instructions do not count the timers down");
+
+        chip.run_frame(20).unwrap();
+        assert_eq!(chip.delay_timer(), 9, "a frame ticks the timers once");
+
+        chip.tick_timers();
+        assert_eq!(chip.delay_timer(), 8, "timers can be ticked on their own");
+    }
+
+    #[test]
+    fn seeded_random_is_reproducible() {
+        let mut first: Cpu = Cpu::new();
+        let mut second: Cpu = Cpu::new();
+        first.seed_random(1234);
+        second.seed_random(1234);
+
+        let mut first_values = Vec::new();
+        let mut second_values = Vec::new();
+        for _ in 0..32 {
+            first.handle_opcode(0xC0FF).unwrap();
+            second.handle_opcode(0xC0FF).unwrap();
+            first_values.push(first.v[0]);
+            second_values.push(second.v[0]);
+        }
+        assert_eq!(first_values, second_values, "same seed, same numbers");
+        assert!(first_values.iter().any(|value| *value != first_values[0]), "numbers vary");
+
+        first.handle_opcode(0xC00F).unwrap();
+        assert_eq!(first.v[0] & 0xF0, 0, "RND masks the number with kk");
+    }
+
+    #[test]
+    fn vip_random_reads_interpreter_page() {
+        let mut chip: Cpu = Cpu::new();
+        chip.set_random_source(Box::new(VipRandom::new(0x0001)));
+        chip.memory[0x101] = 0x10;
+        chip.memory[0x102] = 0x05;
+
+        chip.handle_opcode(0xC0FF).unwrap();
+        assert_eq!(chip.v[0], 0x10, "added the byte at 0x100 + low seed to the high seed");
+        chip.handle_opcode(0xC0FF).unwrap();
+        assert_eq!(chip.v[0], 0x20, "the high seed accumulates");
+
+        chip.tick_timers();
+        chip.handle_opcode(0xC0FF).unwrap();
+        assert_eq!(chip.v[0], 0x25, "the low seed advanced with the timer tick");
+    }
+
+    #[test]
+    fn save_state_round_trip() {
+        let mut chip: Cpu = Cpu::with_platform(Platform::SuperChip);
+        chip.seed_random(99);
+        // LD V0, 0x0A; LD I, 0x300; DRW V0, V0, 5; CALL 0x20A; LD V1, 1; RND V2, 0xFF; JP 0x20C
+        chip.load_rom_bytes(&[
+            0x60, 0x0A, 0xA3, 0x00, 0xD0, 0x05, 0x22, 0x0A, 0x61, 0x01, 0xC2, 0xFF, 0x12, 0x0C,
+        ])
+        .unwrap();
+        chip.memory[0x300..0x305].copy_from_slice(&[0xF0, 0x90, 0xF0, 0x90, 0xF0]);
+        chip.run_frame(4).unwrap();
+        chip.keyboard.press_key(0xA);
+        let saved = chip.save_state();
+
+        let mut restored: Cpu = Cpu::new();
+        restored.load_state(&saved).unwrap();
+        assert_eq!(restored.platform(), Platform::SuperChip, "platform was restored");
+        assert_eq!(restored.quirks(), Quirks::super_chip(), "quirks were restored");
+        assert_eq!(restored.pc, chip.pc, "program counter was restored");
+        assert_eq!(restored.sp, 1, "stack pointer was restored");
+        assert_eq!(restored.stack[0], 0x208, "stack was restored");
+        assert_eq!(restored.memory, chip.memory, "memory was restored");
+        assert_eq!(restored.display.pixel(10, 10), 1, "display was restored");
+        assert!(restored.keyboard.key_is_pressed(0xA), "keyboard was restored");
+
+        chip.run_frame(3).unwrap();
+        restored.run_frame(3).unwrap();
+        assert_eq!(restored.v, chip.v, "random numbers replay the same after restoring");
+        assert_eq!(restored.save_state(), chip.save_state(), "the machines are identical");
+    }
+
+    #[test]
+    fn load_state_rejects_invalid_states() {
+        let mut chip: Cpu = Cpu::new();
+        let saved = chip.save_state();
+
+        assert!(chip.load_state(b"not a save state").is_err(), "magic header is checked");
+
+        let mut future = saved.clone();
+        future[8] = 0xFF;
+        assert!(chip.load_state(&future).is_err(), "newer versions are rejected");
+
+        chip.v[0] = 42;
+        assert!(chip.load_state(&saved[..saved.len() / 2]).is_err(), "truncated states are rejected");
+        assert_eq!(chip.v[0], 42, "a failed load leaves the CPU untouched");
+
+        let mut vip: Cpu = Cpu::new();
+        vip.set_random_source(Box::new(VipRandom::new(7)));
+        match chip.load_state(&vip.save_state()) {
+            Err(CpuError::InvalidSaveState(reason)) => assert!(reason.contains("random source"), "{}", reason),
+            other => panic!("a state from another random source loaded: {:?}", other),
+        }
+        assert_eq!(chip.v[0], 42);
+
+        let mut wrong_size = saved.clone();
+        let len = wrong_size.len();
+        wrong_size[len - 12] = 2;
+        wrong_size.truncate(len - 6);
+        assert!(chip.load_state(&wrong_size).is_err(), "a random state of the wrong size is rejected");
+        assert_eq!(chip.v[0], 42);
+    }
+
+    #[test]
+    fn wait_for_key_sees_every_key() {
+        let mut chip: Cpu = Cpu::new();
+        // LD V3, K
+        chip.load_rom_bytes(&[0xF3, 0x0A]).unwrap();
+        chip.execute_cycle().unwrap();
+        assert!(chip.is_waiting_for_key());
+        chip.keyboard.press_key(0xF);
+        chip.execute_cycle().unwrap();
+        assert!(!chip.is_waiting_for_key(), "key F finished the wait");
+        assert_eq!(chip.v[3], 0xF);
+    }
+
+    #[test]
+    fn wait_for_key_needs_a_new_press() {
+        let mut chip: Cpu = Cpu::new();
+        chip.load_rom_bytes(&[0xF3, 0x0A]).unwrap();
+        chip.keyboard.press_key(0x2);
+        chip.execute_cycle().unwrap();
+        chip.execute_cycle().unwrap();
+        assert!(chip.is_waiting_for_key(), "the key was already down");
+
+        chip.keyboard.un_press_key(0x2);
+        chip.execute_cycle().unwrap();
+        chip.keyboard.press_key(0x2);
+        chip.execute_cycle().unwrap();
+        assert!(!chip.is_waiting_for_key(), "pressed again");
+        assert_eq!(chip.v[3], 0x2);
+    }
+
+    #[test]
+    fn quirk_key_release() {
+        let mut chip: Cpu = Cpu::with_quirks(Quirks::cosmac_vip());
+        chip.load_rom_bytes(&[0xF3, 0x0A]).unwrap();
+        chip.execute_cycle().unwrap();
+        chip.keyboard.press_key(0x7);
+        chip.execute_cycle().unwrap();
+        chip.keyboard.press_key(0x9);
+        chip.execute_cycle().unwrap();
+        assert!(chip.is_waiting_for_key(), "still waiting for the key to be let go");
+
+        chip.keyboard.un_press_key(0x7);
+        chip.execute_cycle().unwrap();
+        assert!(!chip.is_waiting_for_key(), "let go");
+        assert_eq!(chip.v[3], 0x7, "the first key pressed, not the last");
+    }
+
+    #[test]
+    fn version_1_states_still_load() {
+        let mut chip: Cpu = Cpu::with_quirks(Quirks::cosmac_vip());
+        chip.load_rom_bytes(&[0xF3, 0x0A]).unwrap();
+        chip.execute_cycle().unwrap();
+        let saved = chip.save_state();
+
+        // version 1 had no byte for the key waiting to be let go, a byte for the quirks and no
+        // random source id
+        let key_wait = 10 + 58 + 4 + chip.memory.len() + 2 + 4 + HIRES_WIDTH * HIRES_HEIGHT;
+        let mut old = saved.clone();
+        old[8] = 1;
+        old.remove(old.len() - 13);
+        old.remove(key_wait + 2);
+        old.remove(12);
+
+        let mut restored: Cpu = Cpu::new();
+        restored.load_state(&old).unwrap();
+        assert_eq!(restored.save_state(), saved, "upgraded to the same state");
+        assert!(restored.is_waiting_for_key());
+    }
+
+    #[test]
+    fn decode_cache_sees_self_modifying_code() {
+        let mut chip: Cpu = Cpu::new();
+        chip.set_decode_cache(true);
+        // LD I, 0x20B; LD V0, 7; JP 0x20A; LD [I], V0; JP 0x20A; LD V1, 5; JP 0x206
+        chip.load_rom_bytes(&[
+            0xA2, 0x0B, 0x60, 0x07, 0x12, 0x0A, 0xF0, 0x55, 0x12, 0x0A, 0x61, 0x05, 0x12, 0x06,
+        ])
+        .unwrap();
+        for _ in 0..4 {
+            chip.execute_cycle().unwrap();
+        }
+        assert_eq!(chip.v[1], 5, "the instruction at 0x20A ran once");
+        for _ in 0..4 {
+            chip.execute_cycle().unwrap();
+        }
+        assert_eq!(chip.v[1], 7, "writing its second byte dropped the decoded instruction");
+
+        chip.reset();
+        chip.load_rom_bytes(&[0x62, 0x03]).unwrap();
+        chip.execute_cycle().unwrap();
+        assert_eq!(chip.v[2], 3, "loading a rom drops everything decoded before");
+    }
 }
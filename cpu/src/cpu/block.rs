@@ -0,0 +1,322 @@
+use super::{Cpu, OP_SIZE};
+use crate::error::CpuError;
+use crate::instruction::Instruction;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::rc::Rc;
+
+// The block engine, a faster way through run_frame for long batch runs.
+// A straight-line run of instructions, up to the next jump, skip, call, draw or anything else that
+// needs the interpreter, is decoded once into a list of micro-ops and kept by its start address.
+// Running a block skips the fetch, decode and program counter update of every instruction in it.
+// Instructions without a micro-op of their own run through the interpreter's handler for them, so
+// both paths share one implementation of every instruction. An instruction that writes to memory
+// ends its block, and the write drops every block decoded from the bytes it changed.
+
+// the longest block, past this a new block starts
+const MAX_BLOCK_LEN: usize = 64;
+
+#[derive(Clone, Copy)]
+enum MicroOp {
+    // 6XKK
+    Load { x: usize, byte: u8 },
+    // 7XKK
+    AddByte { x: usize, byte: u8 },
+    // 8XY0
+    Move { x: usize, y: usize },
+    // ANNN
+    LoadI(u16),
+    // any other straight-line instruction, run by its interpreter handler
+    Handler(Instruction),
+}
+
+struct Block {
+    start: u16,
+    ops: Vec<MicroOp>,
+    // the instruction after the ops ended the block and is left to the interpreter
+    ends_in_interpreter: bool,
+    // the memory the block was decoded from, including the instruction that ended it
+    source: Range<usize>,
+}
+
+pub(super) struct BlockCache {
+    blocks: HashMap<u16, Rc<Block>>,
+    // how many blocks were decoded from each byte of memory, so most writes skip the search
+    coverage: Vec<u16>,
+}
+
+impl BlockCache {
+    pub(super) fn new(memory_size: usize) -> BlockCache {
+        BlockCache { blocks: HashMap::new(), coverage: vec![0; memory_size] }
+    }
+
+    fn insert(&mut self, block: Rc<Block>) {
+        for count in &mut self.coverage[block.source.clone()] {
+            *count += 1;
+        }
+        self.blocks.insert(block.start, block);
+    }
+
+    // memory in range changed, drop the blocks decoded from any of it
+    pub(super) fn invalidate(&mut self, range: Range<usize>) {
+        // an instruction starting the byte before the range changed too
+        let range = range.start.saturating_sub(1)..range.end;
+        if self.coverage[range.clone()].iter().all(|count| *count == 0) {
+            return;
+        }
+        let coverage = &mut self.coverage;
+        self.blocks.retain(|_, block| {
+            let overlaps = block.source.start < range.end && range.start < block.source.end;
+            if overlaps {
+                for count in &mut coverage[block.source.clone()] {
+                    *count -= 1;
+                }
+            }
+            !overlaps
+        });
+    }
+}
+
+impl Cpu {
+    // Run run_frame through cached blocks of straight-line code instead of an instruction at a
+    // time. Every write to memory drops the blocks decoded from what it changed, so
+    // self-modifying code still works.
+    pub fn set_block_engine(&mut self, enabled: bool) {
+        self.blocks = if enabled { Some(BlockCache::new(self.memory.len())) } else { None };
+    }
+
+    pub(super) fn clear_blocks(&mut self) {
+        if self.blocks.is_some() {
+            self.set_block_engine(true);
+        }
+    }
+
+    // run_frame's instructions, up to budget of them, stopping where run_frame would
+    pub(super) fn run_blocks(&mut self, budget: usize) -> Result<(), CpuError> {
+        let mut remaining = budget;
+        while remaining > 0 && !self.waiting_for_display && !self.exited {
+            if self.is_waiting_for_key() {
+                // FX0A waiting for a key
+                self.execute_cycle()?;
+                remaining -= 1;
+                continue;
+            }
+
+            let block = self.block_at(self.pc);
+            let count = block.ops.len().min(remaining);
+            self.run_ops(&block, count)?;
+            remaining -= count;
+
+            // none of the ops can stop the frame, so the instruction after them runs straight away
+            if count == block.ops.len() && block.ends_in_interpreter && remaining > 0 {
+                self.execute_cycle()?;
+                remaining -= 1;
+            }
+        }
+        Ok(())
+    }
+
+    // the cached block starting at start, decoding it first if it is not there
+    fn block_at(&mut self, start: u16) -> Rc<Block> {
+        if let Some(block) = self.blocks.as_ref().and_then(|blocks| blocks.blocks.get(&start)) {
+            return Rc::clone(block);
+        }
+        let block = Rc::new(self.compile(start));
+        if let Some(blocks) = &mut self.blocks {
+            blocks.insert(Rc::clone(&block));
+        }
+        block
+    }
+
+    fn compile(&self, start: u16) -> Block {
+        let mut ops = Vec::new();
+        let mut addr = start as usize;
+        let ends_in_interpreter = loop {
+            if ops.len() == MAX_BLOCK_LEN {
+                break false;
+            }
+            // running off the end of memory is the interpreter's fault to report
+            let opcode = match self.memory.get(addr..addr + 2) {
+                Some(word) => (word[0] as u16) << 8 | word[1] as u16,
+                None => break true,
+            };
+            let instruction = Instruction::decode_for(opcode, self.platform);
+            let op = match instruction {
+                Instruction::Load { x, byte } => MicroOp::Load { x: x as usize, byte },
+                Instruction::AddByte { x, byte } => MicroOp::AddByte { x: x as usize, byte },
+                Instruction::Move { x, y } => MicroOp::Move { x: x as usize, y: y as usize },
+                Instruction::LoadI(nnn) => MicroOp::LoadI(nnn),
+                // anything that moves the pc somewhere other than the next instruction, or can stop
+                // the frame, is left to the interpreter
+                Instruction::Return
+                | Instruction::Exit
+                | Instruction::Jump(_)
+                | Instruction::Call(_)
+                | Instruction::SkipIfEqual { .. }
+                | Instruction::SkipIfNotEqual { .. }
+                | Instruction::SkipIfRegistersEqual { .. }
+                | Instruction::SkipIfRegistersNotEqual { .. }
+                | Instruction::JumpOffset(_)
+                | Instruction::Draw { .. }
+                | Instruction::SkipIfKey(_)
+                | Instruction::SkipIfNotKey(_)
+                | Instruction::LoadILong
+                | Instruction::WaitKey(_)
+                | Instruction::Unknown(_) => break true,
+                _ => MicroOp::Handler(instruction),
+            };
+            ops.push(op);
+            addr += OP_SIZE as usize;
+
+            // the write may have changed the instructions after it
+            if let Instruction::SaveRange { .. } | Instruction::Bcd(_) | Instruction::Store(_) = instruction {
+                break false;
+            }
+        };
+        let end = if ends_in_interpreter { (addr + OP_SIZE as usize).min(self.memory.len()) } else { addr };
+        Block { start, ops, ends_in_interpreter, source: (start as usize).min(end)..end }
+    }
+
+    // run the first count ops of the block, leaving the pc after them
+    fn run_ops(&mut self, block: &Block, count: usize) -> Result<(), CpuError> {
+        for (index, op) in block.ops[..count].iter().enumerate() {
+            match *op {
+                MicroOp::Load { x, byte } => self.v[x] = byte,
+                MicroOp::AddByte { x, byte } => self.v[x] = self.v[x].wrapping_add(byte),
+                MicroOp::Move { x, y } => self.v[x] = self.v[y],
+                MicroOp::LoadI(nnn) => self.i = nnn,
+                MicroOp::Handler(instruction) => {
+                    // the handler sees the pc as the interpreter would have left it
+                    self.pc = block.start.wrapping_add(index as u16 * OP_SIZE);
+                    if let Err(fault) = self.dispatch(instruction) {
+                        return Err(fault.at(self.pc, instruction.encode()));
+                    }
+                }
+            }
+        }
+        self.pc = block.start.wrapping_add(count as u16 * OP_SIZE);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::platform::Platform;
+    use crate::random::{RandomSource, XorShiftRandom};
+
+    // run the rom on the interpreter and the block engine side by side, checking they agree after
+    // every frame
+    fn assert_engines_agree(platform: Platform, rom: &[u8], frames: usize, instructions_per_frame: usize) {
+        let mut interpreter = Cpu::with_platform(platform);
+        let mut engine = Cpu::with_platform(platform);
+        engine.set_block_engine(true);
+        for cpu in [&mut interpreter, &mut engine].iter_mut() {
+            cpu.seed_random(7);
+            cpu.load_rom_bytes(rom).unwrap();
+        }
+
+        for frame in 0..frames {
+            for cpu in [&mut interpreter, &mut engine].iter_mut() {
+                cpu.keyboard.reset();
+                cpu.keyboard.press_key((frame / 3 % 16) as u8);
+            }
+            let expected = interpreter.run_frame(instructions_per_frame).map_err(|e| e.to_string());
+            let actual = engine.run_frame(instructions_per_frame).map_err(|e| e.to_string());
+            assert_eq!(expected, actual, "frame {} of {:02X?}", frame, rom);
+            assert!(interpreter.save_state() == engine.save_state(), "frame {} of {:02X?}", frame, rom);
+            if expected.is_err() {
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn blocks_match_the_interpreter() {
+        // a counting loop with a draw, a call and a skip
+        let rom = [
+            0x60, 0x00, // LD V0, 0
+            0x61, 0x05, // LD V1, 5
+            0xA2, 0x20, // LD I, 0x220
+            0x70, 0x01, // ADD V0, 1
+            0x81, 0x04, // ADD V1, V0
+            0x82, 0x10, // LD V2, V1
+            0xD0, 0x15, // DRW V0, V1, 5
+            0x22, 0x14, // CALL 0x214
+            0x30, 0x40, // SE V0, 0x40
+            0x12, 0x06, // JP 0x206
+            0x00, 0xFD, // EXIT
+            0xF2, 0x1E, // ADD I, V2
+            0x00, 0xEE, // RET
+        ];
+        for &instructions_per_frame in [1, 3, 7, 100].iter() {
+            assert_engines_agree(Platform::SuperChip, &rom, 200, instructions_per_frame);
+        }
+    }
+
+    #[test]
+    fn writes_drop_the_blocks_they_change() {
+        // FX55 rewrites the ADD at 0x20A, then FX33 rewrites it again, in the middle of a block
+        let rom = [
+            0x60, 0x70, // LD V0, 0x70
+            0x61, 0x03, // LD V1, 3
+            0xA2, 0x0A, // LD I, 0x20A
+            0xF1, 0x55, // LD [I], V1
+            0x6F, 0x00, // LD VF, 0
+            0x72, 0x01, // ADD V2, 1   becomes ADD V0, 3 then 0x0106
+            0x60, 0x7F, // LD V0, 0x7F
+            0xF0, 0x33, // LD B, V0
+            0x12, 0x0A, // JP 0x20A
+        ];
+        assert_engines_agree(Platform::Chip8, &rom, 5, 10);
+
+        let mut cpu = Cpu::new();
+        cpu.set_block_engine(true);
+        cpu.load_rom_bytes(&rom).unwrap();
+        cpu.run_frame(6).unwrap();
+        assert_eq!(cpu.v[0], 0x73);
+        assert_eq!(cpu.v[2], 0);
+    }
+
+    #[test]
+    fn faults_are_reported_at_their_instruction() {
+        // the FX55 in the middle of the block runs off the end of memory
+        let rom = [0x60, 0x01, 0xAF, 0xFF, 0xF3, 0x55, 0x61, 0x01];
+        assert_engines_agree(Platform::Chip8, &rom, 1, 10);
+
+        let mut cpu = Cpu::new();
+        cpu.set_block_engine(true);
+        cpu.load_rom_bytes(&rom).unwrap();
+        let error = cpu.run_frame(10).unwrap_err().to_string();
+        assert!(error.contains("0x204"), "{}", error);
+        assert_eq!(cpu.pc(), 0x204);
+    }
+
+    #[test]
+    fn random_programs_match_the_interpreter() {
+        let mut random = XorShiftRandom::new(0xB10C);
+        let mut byte = || random.next_byte(&[]) as u16;
+        for program in 0..300 {
+            // mostly straight-line code, with a jump back to the start so it keeps going
+            let mut rom = Vec::new();
+            for _ in 0..48 {
+                let op = match byte() % 8 {
+                    0 => 0x6000 | (byte() & 0xF) << 8 | byte(),
+                    1 => 0x7000 | (byte() & 0xF) << 8 | byte(),
+                    2 => 0x8000 | byte() << 4 | (byte() % 8),
+                    3 => 0xA200 | byte(),
+                    4 => 0xF000 | (byte() & 0xF) << 8 | [0x1E, 0x33, 0x55, 0x65, 0x07, 0x15][byte() as usize % 6],
+                    5 => 0x3000 | (byte() & 0xF) << 8 | (byte() % 4),
+                    6 => 0xD000 | byte() << 4 | (byte() % 16),
+                    _ => byte() << 8 | byte(),
+                };
+                rom.extend_from_slice(&op.to_be_bytes());
+            }
+            rom.extend_from_slice(&[0x12, 0x00]);
+
+            let platform = [Platform::Chip8, Platform::SuperChip, Platform::XoChip][program % 3];
+            let instructions_per_frame = [1, 5, 13, 40][program % 4];
+            assert_engines_agree(platform, &rom, 30, instructions_per_frame);
+        }
+    }
+}
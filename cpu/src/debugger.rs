@@ -0,0 +1,771 @@
+use crate::cpu::Cpu;
+use crate::disasm::disassemble;
+use crate::error::CpuError;
+use crate::instruction::Instruction;
+use crate::parse;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::ops::Range;
+use std::str::FromStr;
+
+// Runs a Cpu under control: breakpoints, stepping, run to cursor, memory and register watchpoints
+// and breaking on classes of opcode. A frontend calls run_frame in place of Cpu::run_frame and
+// keeps drawing the display while the debugger is paused. command takes the same text commands
+// as the debugger prompt, see HELP.
+
+// why execution stopped
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Break {
+    // the next instruction is at a breakpoint
+    Breakpoint(u16),
+
+    // the next instruction matches a pattern given to break_on_opcode
+    Opcode { pc: u16, opcode: u16 },
+
+    // the instruction at pc read or wrote a watched address
+    Memory { pc: u16, address: u16, write: bool },
+
+    // the instruction at pc changed a watched register
+    Register { pc: u16, register: usize, old: u8, new: u8 },
+
+    // a step, step over, step out or run to cursor finished at this address
+    Stepped(u16),
+}
+
+impl fmt::Display for Break {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Break::Breakpoint(pc) => write!(f, "breakpoint at {:#05X}", pc),
+            Break::Opcode { pc, opcode } => write!(f, "opcode {:04X} at {:#05X}", opcode, pc),
+            Break::Memory { pc, address, write } => {
+                let access = if *write { "wrote" } else { "read" };
+                write!(f, "{:#05X} {} watched address {:#05X}", pc, access, address)
+            }
+            Break::Register { pc, register, old, new } => {
+                write!(f, "{:#05X} changed V{:X} from {:#04X} to {:#04X}", pc, register, old, new)
+            }
+            Break::Stepped(pc) => write!(f, "stopped at {:#05X}", pc),
+        }
+    }
+}
+
+// A class of opcodes, written like the opcode tables: hex digits must match, X, Y, N and K match
+// anything, e.g. FX0A for every key wait or DXYN for every sprite draw.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpcodePattern {
+    mask: u16,
+    value: u16,
+}
+
+impl OpcodePattern {
+    pub fn matches(self, opcode: u16) -> bool {
+        opcode & self.mask == self.value
+    }
+}
+
+impl FromStr for OpcodePattern {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<OpcodePattern, String> {
+        if text.chars().count() != 4 {
+            return Err(format!("opcode pattern {} is not 4 digits long", text));
+        }
+        let mut pattern = OpcodePattern { mask: 0, value: 0 };
+        for c in text.chars() {
+            pattern.mask <<= 4;
+            pattern.value <<= 4;
+            match c {
+                'X' | 'x' | 'Y' | 'y' | 'N' | 'n' | 'K' | 'k' => {}
+                _ => {
+                    let digit = c
+                        .to_digit(16)
+                        .ok_or_else(|| format!("opcode pattern {} has an invalid digit {}", text, c))?;
+                    pattern.mask |= 0xF;
+                    pattern.value |= digit as u16;
+                }
+            }
+        }
+        Ok(pattern)
+    }
+}
+
+impl fmt::Display for OpcodePattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (shift, wildcard) in [(12, 'X'), (8, 'X'), (4, 'Y'), (0, 'N')].iter() {
+            if self.mask >> shift & 0xF == 0 {
+                write!(f, "{}", wildcard)?;
+            } else {
+                write!(f, "{:X}", self.value >> shift & 0xF)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// where a step over, step out or run to cursor stops
+enum Target {
+    // at pc, and if depth is set only with that many subroutines in progress
+    Address { pc: u16, depth: Option<usize> },
+
+    // once fewer than this many subroutines are in progress
+    Return(usize),
+}
+
+pub struct Debugger {
+    breakpoints: BTreeSet<u16>,
+    read_watches: BTreeSet<u16>,
+    write_watches: BTreeSet<u16>,
+    register_watches: BTreeSet<usize>,
+    opcode_breaks: Vec<OpcodePattern>,
+    paused: bool,
+
+    // set by resume so the instruction execution stopped at does not stop it again
+    resuming: bool,
+
+    target: Option<Target>,
+}
+
+impl Default for Debugger {
+    fn default() -> Debugger {
+        Debugger::new()
+    }
+}
+
+impl Debugger {
+    // starts paused, so breakpoints can be set before the program runs
+    pub fn new() -> Debugger {
+        Debugger {
+            breakpoints: BTreeSet::new(),
+            read_watches: BTreeSet::new(),
+            write_watches: BTreeSet::new(),
+            register_watches: BTreeSet::new(),
+            opcode_breaks: Vec::new(),
+            paused: true,
+            resuming: false,
+            target: None,
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn pause(&mut self) {
+        self.paused = true;
+        self.target = None;
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+        self.resuming = true;
+    }
+
+    // returns false if there already was a breakpoint at addr
+    pub fn add_breakpoint(&mut self, addr: u16) -> bool {
+        self.breakpoints.insert(addr)
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u16) -> bool {
+        self.breakpoints.remove(&addr)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = &u16> {
+        self.breakpoints.iter()
+    }
+
+    // stop after an instruction reads and/or writes addr
+    pub fn watch_memory(&mut self, addr: u16, read: bool, write: bool) {
+        if read {
+            self.read_watches.insert(addr);
+        }
+        if write {
+            self.write_watches.insert(addr);
+        }
+    }
+
+    pub fn unwatch_memory(&mut self, addr: u16) -> bool {
+        let read = self.read_watches.remove(&addr);
+        self.write_watches.remove(&addr) || read
+    }
+
+    // stop after an instruction changes Vx
+    pub fn watch_register(&mut self, x: usize) {
+        self.register_watches.insert(x);
+    }
+
+    pub fn unwatch_register(&mut self, x: usize) -> bool {
+        self.register_watches.remove(&x)
+    }
+
+    // stop before any instruction matching pattern
+    pub fn break_on_opcode(&mut self, pattern: OpcodePattern) {
+        if !self.opcode_breaks.contains(&pattern) {
+            self.opcode_breaks.push(pattern);
+        }
+    }
+
+    pub fn remove_opcode_break(&mut self, pattern: OpcodePattern) -> bool {
+        let before = self.opcode_breaks.len();
+        self.opcode_breaks.retain(|p| *p != pattern);
+        self.opcode_breaks.len() != before
+    }
+
+    // Use in place of Cpu::run_frame. Does nothing while paused, otherwise runs like
+    // Cpu::run_frame until something makes the debugger stop, which pauses it.
+    pub fn run_frame(&mut self, cpu: &mut Cpu, instructions_per_frame: usize) -> Result<Option<Break>, CpuError> {
+        if self.paused {
+            return Ok(None);
+        }
+        for _ in 0..instructions_per_frame {
+            if cpu.has_exited() || cpu.is_waiting_for_display() {
+                break;
+            }
+            if let Some(hit) = self.check(cpu) {
+                self.stop();
+                return Ok(Some(hit));
+            }
+            match self.execute(cpu) {
+                Ok(None) => {}
+                Ok(Some(hit)) => {
+                    self.stop();
+                    return Ok(Some(hit));
+                }
+                Err(e) => {
+                    self.stop();
+                    return Err(e);
+                }
+            }
+        }
+        cpu.tick_timers();
+        Ok(None)
+    }
+
+    // Run one instruction and pause. A sprite waiting on the display is let through by ticking
+    // the timers, as the frame it was waiting for would have.
+    pub fn step(&mut self, cpu: &mut Cpu) -> Result<Break, CpuError> {
+        self.stop();
+        if cpu.is_waiting_for_display() {
+            cpu.tick_timers();
+        }
+        let hit = self.execute(cpu)?;
+        Ok(hit.unwrap_or_else(|| Break::Stepped(cpu.pc())))
+    }
+
+    // step, but run a whole subroutine when the next instruction calls one
+    pub fn step_over(&mut self, cpu: &mut Cpu) -> Result<Option<Break>, CpuError> {
+        let pc = cpu.pc();
+        match cpu.opcode_at(pc) {
+            Some(opcode) if matches!(Instruction::decode(opcode), Instruction::Call(_)) && !cpu.is_waiting_for_key() => {
+                let depth = Some(cpu.call_stack().len());
+                self.target = Some(Target::Address { pc: pc.wrapping_add(2), depth });
+                self.resume();
+                Ok(None)
+            }
+            _ => self.step(cpu).map(Some),
+        }
+    }
+
+    // run until the current subroutine returns, false if there is none
+    pub fn step_out(&mut self, cpu: &Cpu) -> bool {
+        let depth = cpu.call_stack().len();
+        if depth == 0 {
+            return false;
+        }
+        self.target = Some(Target::Return(depth));
+        self.resume();
+        true
+    }
+
+    // run until execution reaches addr
+    pub fn run_to(&mut self, addr: u16) {
+        self.target = Some(Target::Address { pc: addr, depth: None });
+        self.resume();
+    }
+
+    fn stop(&mut self) {
+        self.paused = true;
+        self.resuming = false;
+        self.target = None;
+    }
+
+    // anything that stops execution before the next instruction runs
+    fn check(&mut self, cpu: &Cpu) -> Option<Break> {
+        // nothing runs while FX0A waits, so there is nothing to stop before
+        if cpu.is_waiting_for_key() || std::mem::replace(&mut self.resuming, false) {
+            return None;
+        }
+        let pc = cpu.pc();
+        let depth = cpu.call_stack().len();
+        let reached = match self.target {
+            Some(Target::Address { pc: target, depth: target_depth }) => {
+                pc == target && target_depth.is_none_or(|target_depth| depth == target_depth)
+            }
+            Some(Target::Return(target_depth)) => depth < target_depth,
+            None => false,
+        };
+        if reached {
+            return Some(Break::Stepped(pc));
+        }
+        if self.breakpoints.contains(&pc) {
+            return Some(Break::Breakpoint(pc));
+        }
+        let opcode = cpu.opcode_at(pc)?;
+        if self.opcode_breaks.iter().any(|pattern| pattern.matches(opcode)) {
+            return Some(Break::Opcode { pc, opcode });
+        }
+        None
+    }
+
+    // run one instruction, and report it if it touched anything watched
+    fn execute(&mut self, cpu: &mut Cpu) -> Result<Option<Break>, CpuError> {
+        let pc = cpu.pc();
+        let (reads, writes) = match cpu.opcode_at(pc) {
+            Some(opcode) if !cpu.is_waiting_for_key() => memory_accesses(cpu, opcode),
+            _ => (0..0, 0..0),
+        };
+        let before = *cpu.registers();
+
+        cpu.execute_cycle()?;
+
+        let watched = |watches: &BTreeSet<u16>, range: &Range<usize>| {
+            watches.iter().find(|address| range.contains(&(**address as usize))).copied()
+        };
+        if let Some(address) = watched(&self.write_watches, &writes) {
+            return Ok(Some(Break::Memory { pc, address, write: true }));
+        }
+        if let Some(address) = watched(&self.read_watches, &reads) {
+            return Ok(Some(Break::Memory { pc, address, write: false }));
+        }
+        for &register in &self.register_watches {
+            let (old, new) = (before[register], cpu.registers()[register]);
+            if old != new {
+                return Ok(Some(Break::Register { pc, register, old, new }));
+            }
+        }
+        Ok(None)
+    }
+
+    // Runs one debugger prompt command and returns what it prints. Commands that start the
+    // program running return straight away, run_frame reports where it stops.
+    pub fn command(&mut self, cpu: &mut Cpu, line: &str) -> String {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match self.run_command(cpu, &words) {
+            Ok(output) => output,
+            Err(message) => format!("{}\n", message),
+        }
+    }
+
+    fn run_command(&mut self, cpu: &mut Cpu, words: &[&str]) -> Result<String, String> {
+        let (command, args) = match words.split_first() {
+            Some((command, args)) => (*command, args),
+            None => return Ok(String::new()),
+        };
+        let arg = |index: usize| args.get(index).copied().ok_or_else(|| format!("{} needs more arguments, see help", command));
+
+        let output = match command {
+            "b" | "break" => {
+                let addr = parse_address(arg(0)?)?;
+                self.add_breakpoint(addr);
+                format!("breakpoint at {:#05X}\n", addr)
+            }
+            "d" | "delete" => {
+                let target = arg(0)?;
+                let removed = if let Some(x) = parse::register(target) {
+                    self.unwatch_register(usize::from(x))
+                } else if let Ok(pattern) = target.parse::<OpcodePattern>() {
+                    self.remove_opcode_break(pattern)
+                } else {
+                    let addr = parse_address(target)?;
+                    let breakpoint = self.remove_breakpoint(addr);
+                    self.unwatch_memory(addr) || breakpoint
+                };
+                if removed {
+                    format!("deleted {}\n", target)
+                } else {
+                    format!("nothing set at {}\n", target)
+                }
+            }
+            "w" | "watch" => {
+                let target = arg(0)?;
+                if let Some(x) = parse::register(target) {
+                    self.watch_register(usize::from(x));
+                    format!("watching V{:X}\n", x)
+                } else {
+                    let addr = parse_address(target)?;
+                    let (read, write) = match args.get(1).copied().unwrap_or("w") {
+                        "r" => (true, false),
+                        "w" => (false, true),
+                        "rw" => (true, true),
+                        access => return Err(format!("unknown access {}, use r, w or rw", access)),
+                    };
+                    self.watch_memory(addr, read, write);
+                    format!("watching {:#05X}\n", addr)
+                }
+            }
+            "o" | "opcode" => {
+                let pattern = arg(0)?.parse::<OpcodePattern>()?;
+                self.break_on_opcode(pattern);
+                format!("breaking on {}\n", pattern)
+            }
+            "c" | "continue" => {
+                self.resume();
+                String::from("running\n")
+            }
+            "p" | "pause" => {
+                self.pause();
+                format!("paused\n{}", current_instruction(cpu))
+            }
+            "s" | "step" => {
+                let count = match args.first() {
+                    Some(count) => count
+                        .parse::<usize>()
+                        .ok()
+                        .filter(|count| (1..=MAX_STEPS).contains(count))
+                        .ok_or_else(|| format!("step count should be from 1 to {}, not {}", MAX_STEPS, count))?,
+                    None => 1,
+                };
+                if cpu.has_exited() {
+                    return Err(String::from("the program has exited"));
+                }
+                let mut output = String::new();
+                for _ in 0..count {
+                    match self.step(cpu) {
+                        Ok(Break::Stepped(_)) => {}
+                        Ok(hit) => {
+                            output += &format!("{}\n", hit);
+                            break;
+                        }
+                        Err(e) => return Err(e.to_string()),
+                    }
+                    // nothing more happens until a frame passes
+                    if cpu.has_exited() {
+                        output += "the program exited\n";
+                        break;
+                    }
+                    if cpu.is_waiting_for_key() {
+                        output += "waiting for a key\n";
+                        break;
+                    }
+                }
+                output + &current_instruction(cpu)
+            }
+            "n" | "next" => match self.step_over(cpu) {
+                Ok(Some(Break::Stepped(_))) => current_instruction(cpu),
+                Ok(Some(hit)) => format!("{}\n{}", hit, current_instruction(cpu)),
+                Ok(None) => String::from("running\n"),
+                Err(e) => return Err(e.to_string()),
+            },
+            "f" | "finish" => {
+                if !self.step_out(cpu) {
+                    return Err(String::from("not in a subroutine"));
+                }
+                String::from("running\n")
+            }
+            "u" | "until" => {
+                let addr = parse_address(arg(0)?)?;
+                self.run_to(addr);
+                format!("running to {:#05X}\n", addr)
+            }
+            "r" | "regs" => registers(cpu),
+            "x" | "mem" => {
+                let addr = parse_address(arg(0)?)? as usize;
+                let len = args.get(1).map_or(Ok(64), |len| len.parse::<usize>().map_err(|e| e.to_string()))?;
+                let memory = cpu.memory();
+                let end = addr.saturating_add(len).min(memory.len());
+                let mut output = String::new();
+                for (row, bytes) in memory[addr.min(end)..end].chunks(16).enumerate() {
+                    let bytes: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+                    output += &format!("{:03X}: {}\n", addr + row * 16, bytes.join(" "));
+                }
+                output
+            }
+            "l" | "list" => {
+                let addr = args.first().map_or(Ok(cpu.pc()), |addr| parse_address(addr))?;
+                self.list(cpu, addr, 10)
+            }
+            "i" | "info" => self.info(),
+            "h" | "help" => String::from(HELP),
+            _ => return Err(format!("unknown command {}, see help", command)),
+        };
+        Ok(output)
+    }
+
+    // disassemble count instructions from addr, marking the pc and breakpoints
+    fn list(&self, cpu: &Cpu, mut addr: u16, count: usize) -> String {
+        let mut output = String::new();
+        for _ in 0..count {
+            let (opcode, text) = match (cpu.opcode_at(addr), instruction_text(cpu, addr)) {
+                (Some(opcode), Some(text)) => (opcode, text),
+                _ => break,
+            };
+            let marker = if addr == cpu.pc() { '>' } else { ' ' };
+            let breakpoint = if self.breakpoints.contains(&addr) { '*' } else { ' ' };
+            output += &format!("{}{} {:03X}: {:04X}  {}\n", marker, breakpoint, addr, opcode, text);
+            addr = addr.wrapping_add(cpu.instruction_size(addr));
+        }
+        output
+    }
+
+    fn info(&self) -> String {
+        let addresses = |set: &BTreeSet<u16>| set.iter().map(|addr| format!("{:#05X}", addr)).collect::<Vec<_>>().join(" ");
+        let registers: Vec<String> = self.register_watches.iter().map(|x| format!("V{:X}", x)).collect();
+        let opcodes: Vec<String> = self.opcode_breaks.iter().map(|pattern| pattern.to_string()).collect();
+        format!(
+            "breakpoints: {}\nread watches: {}\nwrite watches: {}\nregister watches: {}\nopcode breaks: {}\n",
+            addresses(&self.breakpoints),
+            addresses(&self.read_watches),
+            addresses(&self.write_watches),
+            registers.join(" "),
+            opcodes.join(" "),
+        )
+    }
+}
+
+// the most instructions one step command runs, the window is frozen until they finish
+const MAX_STEPS: usize = 100_000;
+
+pub const HELP: &str = "\
+b, break ADDR        stop before the instruction at ADDR
+w, watch ADDR [r|w|rw]
+                     stop after an instruction reads or writes ADDR, writes by default
+w, watch VX          stop after an instruction changes VX
+o, opcode PATTERN    stop before instructions like PATTERN, e.g. FX0A or DXYN
+d, delete WHAT       remove a breakpoint, watch or opcode break
+c, continue          run until something stops execution
+p, pause             stop running
+s, step [N]          run N instructions, 1 by default and 100000 at most
+n, next              step, running whole subroutines
+f, finish            run until the current subroutine returns
+u, until ADDR        run until execution reaches ADDR
+r, regs              show the registers, timers and call stack
+x, mem ADDR [LEN]    show LEN bytes of memory, 64 by default
+l, list [ADDR]       disassemble from ADDR, the pc by default
+i, info              show breakpoints and watches
+";
+
+// the instruction at the pc, for showing where execution stopped
+pub fn current_instruction(cpu: &Cpu) -> String {
+    match cpu.opcode_at(cpu.pc()) {
+        Some(opcode) => format!("{:03X}: {}\n", cpu.pc(), disassemble(opcode, cpu.platform())),
+        None => format!("{:03X}: outside memory\n", cpu.pc()),
+    }
+}
+
+// the instruction at addr as the disassembler prints it, reading both words of a long load
+pub fn instruction_text(cpu: &Cpu, addr: u16) -> Option<String> {
+    let opcode = cpu.opcode_at(addr)?;
+    if cpu.instruction_size(addr) == 4 {
+        let long = cpu.opcode_at(addr.wrapping_add(2)).unwrap_or(0);
+        Some(format!("LD I, LONG {:#06X}", long).replace("0X", "0x"))
+    } else {
+        Some(disassemble(opcode, cpu.platform()))
+    }
+}
+
+fn registers(cpu: &Cpu) -> String {
+    let v = cpu.registers();
+    let mut output = format!(
+        "pc {:#05X}  i {:#05X}  dt {}  st {}\n",
+        cpu.pc(),
+        cpu.i(),
+        cpu.delay_timer(),
+        cpu.sound_timer()
+    );
+    for row in v.chunks(8).enumerate() {
+        let (row, values) = row;
+        let values: Vec<String> = values
+            .iter()
+            .enumerate()
+            .map(|(x, value)| format!("V{:X} {:02X}", row * 8 + x, value))
+            .collect();
+        output += &format!("{}\n", values.join("  "));
+    }
+    let stack: Vec<String> = cpu.call_stack().iter().map(|addr| format!("{:#05X}", addr)).collect();
+    output + &format!("stack {}\n", stack.join(" "))
+}
+
+fn parse_address(text: &str) -> Result<u16, String> {
+    parse::number(text).ok_or_else(|| format!("invalid address {}", text))
+}
+
+// the memory the instruction at the pc is about to read and write, as (reads, writes)
+pub fn memory_accesses(cpu: &Cpu, opcode: u16) -> (Range<usize>, Range<usize>) {
+    let i = cpu.i() as usize;
+    let none = 0..0;
+    match Instruction::decode_for(opcode, cpu.platform()) {
+        Instruction::Draw { n, .. } => {
+            let bytes = if n == 0 && cpu.platform().has_super_chip_opcodes() { 32 } else { n as usize };
+            let planes = cpu.planes().count_ones() as usize;
+            (i..i + bytes * planes, none)
+        }
+        Instruction::SaveRange { x, y } => (none, i..i + x.max(y) as usize - x.min(y) as usize + 1),
+        Instruction::LoadRange { x, y } => (i..i + x.max(y) as usize - x.min(y) as usize + 1, none),
+        Instruction::Bcd(_) => (none, i..i + 3),
+        Instruction::Store(x) => (none, i..i + x as usize + 1),
+        Instruction::Restore(x) => (i..i + x as usize + 1, none),
+        Instruction::Audio => (i..i + 16, none),
+        // the address is the word after the opcode
+        Instruction::LoadILong => {
+            let pc = cpu.pc() as usize;
+            (pc + 2..pc + 4, none)
+        }
+        _ => (none.clone(), none),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::platform::Platform;
+
+    fn chip(rom: &[u8]) -> Cpu {
+        let mut chip = Cpu::new();
+        chip.load_rom_bytes(rom).unwrap();
+        chip
+    }
+
+    #[test]
+    fn opcode_patterns() {
+        let pattern: OpcodePattern = "FX0A".parse().unwrap();
+        assert!(pattern.matches(0xF30A));
+        assert!(!pattern.matches(0xF307));
+        assert_eq!(pattern.to_string(), "FX0A");
+        assert!("DXYN".parse::<OpcodePattern>().unwrap().matches(0xD125));
+        assert!("FX0".parse::<OpcodePattern>().is_err());
+        assert!("FXGA".parse::<OpcodePattern>().is_err());
+    }
+
+    #[test]
+    fn breakpoints_stop_and_resume() {
+        // ADD V0, 1; JP 0x200
+        let mut chip = chip(&[0x70, 0x01, 0x12, 0x00]);
+        let mut debugger = Debugger::new();
+        assert_eq!(debugger.run_frame(&mut chip, 10).unwrap(), None, "nothing runs while paused");
+        assert_eq!(chip.registers()[0], 0);
+
+        debugger.add_breakpoint(0x202);
+        debugger.resume();
+        assert_eq!(debugger.run_frame(&mut chip, 10).unwrap(), Some(Break::Breakpoint(0x202)));
+        assert!(debugger.is_paused());
+        assert_eq!(chip.registers()[0], 1);
+
+        debugger.resume();
+        assert_eq!(debugger.run_frame(&mut chip, 10).unwrap(), Some(Break::Breakpoint(0x202)), "went round the loop once");
+        assert_eq!(chip.registers()[0], 2);
+    }
+
+    #[test]
+    fn step_over_and_out() {
+        // 200: CALL 0x206; 202: ADD V1, 1; 204: JP 0x204; 206: ADD V0, 1; 208: RET
+        let rom = [0x22, 0x06, 0x71, 0x01, 0x12, 0x04, 0x70, 0x01, 0x00, 0xEE];
+        let mut chip = chip(&rom);
+        let mut debugger = Debugger::new();
+
+        assert_eq!(debugger.step_over(&mut chip).unwrap(), None, "a call runs instead of stepping");
+        assert_eq!(debugger.run_frame(&mut chip, 10).unwrap(), Some(Break::Stepped(0x202)));
+        assert_eq!(chip.registers()[0], 1, "the subroutine ran");
+
+        chip = Cpu::new();
+        chip.load_rom_bytes(&rom).unwrap();
+        assert_eq!(debugger.step(&mut chip).unwrap(), Break::Stepped(0x206), "step goes into the call");
+        assert!(debugger.step_out(&chip));
+        assert_eq!(debugger.run_frame(&mut chip, 10).unwrap(), Some(Break::Stepped(0x202)));
+        assert!(!debugger.step_out(&chip), "back at the top level");
+
+        debugger.run_to(0x204);
+        assert_eq!(debugger.run_frame(&mut chip, 10).unwrap(), Some(Break::Stepped(0x204)));
+        assert_eq!(chip.registers()[1], 1);
+    }
+
+    #[test]
+    fn watchpoints() {
+        // LD I, 0x300; LD V0, 123; LD B, V0; LD V1, [I]; JP 0x208
+        let rom = [0xA3, 0x00, 0x60, 0x7B, 0xF0, 0x33, 0xF1, 0x65, 0x12, 0x08];
+        let mut chip = chip(&rom);
+        let mut debugger = Debugger::new();
+        debugger.watch_memory(0x302, false, true);
+        debugger.watch_memory(0x301, true, false);
+        debugger.watch_register(1);
+        debugger.resume();
+
+        let hit = debugger.run_frame(&mut chip, 10).unwrap();
+        assert_eq!(hit, Some(Break::Memory { pc: 0x204, address: 0x302, write: true }), "BCD wrote the ones");
+        debugger.resume();
+        let hit = debugger.run_frame(&mut chip, 10).unwrap();
+        assert_eq!(hit, Some(Break::Memory { pc: 0x206, address: 0x301, write: false }), "load read the tens");
+        debugger.unwatch_memory(0x301);
+
+        // the load also changed V1, which shows once the read watch is out of the way
+        chip = Cpu::new();
+        chip.load_rom_bytes(&rom).unwrap();
+        debugger.resume();
+        let hit = debugger.run_frame(&mut chip, 10).unwrap();
+        assert_eq!(hit, Some(Break::Memory { pc: 0x204, address: 0x302, write: true }));
+        debugger.resume();
+        let hit = debugger.run_frame(&mut chip, 10).unwrap();
+        assert_eq!(hit, Some(Break::Register { pc: 0x206, register: 1, old: 0, new: 2 }));
+    }
+
+    #[test]
+    fn xo_chip_watchpoints() {
+        // LD I, 0x300; AUDIO; LD I, LONG 0x400; JP 0x208
+        let rom = [0xA3, 0x00, 0xF0, 0x02, 0xF0, 0x00, 0x04, 0x00, 0x12, 0x08];
+        let mut chip = Cpu::with_platform(Platform::XoChip);
+        chip.load_rom_bytes(&rom).unwrap();
+        let mut debugger = Debugger::new();
+        debugger.watch_memory(0x30F, true, false);
+        debugger.watch_memory(0x207, true, false);
+        debugger.resume();
+
+        let hit = debugger.run_frame(&mut chip, 10).unwrap();
+        assert_eq!(hit, Some(Break::Memory { pc: 0x202, address: 0x30F, write: false }), "AUDIO read the pattern");
+        debugger.resume();
+        let hit = debugger.run_frame(&mut chip, 10).unwrap();
+        assert_eq!(hit, Some(Break::Memory { pc: 0x204, address: 0x207, write: false }), "LD I, LONG read its address");
+        assert_eq!(chip.i(), 0x400);
+    }
+
+    #[test]
+    fn break_on_opcode_class() {
+        // CLS; LD V0, 1; LD V1, K
+        let mut chip = chip(&[0x00, 0xE0, 0x60, 0x01, 0xF1, 0x0A]);
+        let mut debugger = Debugger::new();
+        debugger.break_on_opcode("FX0A".parse().unwrap());
+        debugger.resume();
+        assert_eq!(debugger.run_frame(&mut chip, 10).unwrap(), Some(Break::Opcode { pc: 0x204, opcode: 0xF10A }));
+    }
+
+    #[test]
+    fn commands() {
+        let mut chip = chip(&[0x60, 0x05, 0x22, 0x06, 0x12, 0x04, 0x00, 0xEE]);
+        let mut debugger = Debugger::new();
+
+        assert_eq!(debugger.command(&mut chip, "break 0x204"), "breakpoint at 0x204\n");
+        assert_eq!(debugger.command(&mut chip, "s"), "202: CALL 0x206\n");
+        assert!(debugger.command(&mut chip, "regs").contains("V0 05"));
+        assert_eq!(debugger.command(&mut chip, "n"), "running\n");
+        assert_eq!(debugger.run_frame(&mut chip, 10).unwrap(), Some(Break::Stepped(0x204)));
+        assert!(debugger.command(&mut chip, "list 0x200").contains(">* 204: 1204  JP 0x204\n"));
+        assert_eq!(debugger.command(&mut chip, "x 0x200 4"), "200: 60 05 22 06\n");
+        assert!(debugger.command(&mut chip, "x 0xFF0 18446744073709551615").starts_with("FF0: 00"), "stops at the end of memory");
+        assert_eq!(debugger.command(&mut chip, "w v3"), "watching V3\n");
+        assert!(debugger.command(&mut chip, "info").contains("register watches: V3\n"));
+        assert_eq!(debugger.command(&mut chip, "bogus"), "unknown command bogus, see help\n");
+        assert_eq!(debugger.command(&mut chip, "f"), "not in a subroutine\n");
+        assert_eq!(debugger.command(&mut chip, "s 100000000000"), "step count should be from 1 to 100000, not 100000000000\n");
+        assert_eq!(debugger.command(&mut chip, "s 0"), "step count should be from 1 to 100000, not 0\n");
+    }
+
+    #[test]
+    fn steps_stop_at_key_waits_and_exits() {
+        let mut debugger = Debugger::new();
+        let mut chip = chip(&[0xF0, 0x0A]);
+        assert!(debugger.command(&mut chip, "s 100000").starts_with("waiting for a key\n"));
+        assert!(chip.is_waiting_for_key());
+
+        let mut chip = Cpu::with_platform(Platform::SuperChip);
+        chip.load_rom_bytes(&[0x00, 0xFD]).unwrap();
+        assert!(debugger.command(&mut chip, "s 100000").starts_with("the program exited\n"));
+        assert_eq!(debugger.command(&mut chip, "s"), "the program has exited\n");
+    }
+}
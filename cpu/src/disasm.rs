@@ -0,0 +1,211 @@
+use crate::instruction::Instruction;
+use crate::platform::Platform;
+use std::collections::BTreeSet;
+use std::fmt;
+
+// Turns opcodes back into the mnemonics used in the opcode comments in cpu.rs. A disassembled ROM
+// is valid assembler source: the address and raw bytes of each instruction go in a comment, and
+// jump and call targets inside the ROM get labels.
+
+// render one instruction, with addresses as numbers
+pub fn disassemble(opcode: u16, platform: Platform) -> String {
+    mnemonic(opcode, platform, &|addr| format!("{:#05X}", addr).replace("0X", "0x"))
+}
+
+fn mnemonic(opcode: u16, platform: Platform, address: &dyn Fn(u16) -> String) -> String {
+    use Instruction::*;
+    match Instruction::decode_for(opcode, platform) {
+        ScrollDown(n) => format!("SCD {}", n),
+        ScrollUp(n) => format!("SCU {}", n),
+        Clear => String::from("CLS"),
+        Return => String::from("RET"),
+        ScrollRight => String::from("SCR"),
+        ScrollLeft => String::from("SCL"),
+        Exit => String::from("EXIT"),
+        LowRes => String::from("LOW"),
+        HighRes => String::from("HIGH"),
+        System(addr) => format!("SYS {}", address(addr)),
+        Jump(addr) => format!("JP {}", address(addr)),
+        Call(addr) => format!("CALL {}", address(addr)),
+        SkipIfEqual { x, byte } => format!("SE V{:X}, {:#04X}", x, byte),
+        SkipIfNotEqual { x, byte } => format!("SNE V{:X}, {:#04X}", x, byte),
+        SkipIfRegistersEqual { x, y } => format!("SE V{:X}, V{:X}", x, y),
+        SaveRange { x, y } => format!("SAVE V{:X}, V{:X}", x, y),
+        LoadRange { x, y } => format!("LOAD V{:X}, V{:X}", x, y),
+        Load { x, byte } => format!("LD V{:X}, {:#04X}", x, byte),
+        AddByte { x, byte } => format!("ADD V{:X}, {:#04X}", x, byte),
+        Move { x, y } => format!("LD V{:X}, V{:X}", x, y),
+        Or { x, y } => format!("OR V{:X}, V{:X}", x, y),
+        And { x, y } => format!("AND V{:X}, V{:X}", x, y),
+        Xor { x, y } => format!("XOR V{:X}, V{:X}", x, y),
+        Add { x, y } => format!("ADD V{:X}, V{:X}", x, y),
+        Sub { x, y } => format!("SUB V{:X}, V{:X}", x, y),
+        ShiftRight { x, y } => format!("SHR V{:X}, V{:X}", x, y),
+        SubFrom { x, y } => format!("SUBN V{:X}, V{:X}", x, y),
+        ShiftLeft { x, y } => format!("SHL V{:X}, V{:X}", x, y),
+        SkipIfRegistersNotEqual { x, y } => format!("SNE V{:X}, V{:X}", x, y),
+        LoadI(addr) => format!("LD I, {}", address(addr)),
+        JumpOffset(addr) => format!("JP V0, {}", address(addr)),
+        Random { x, byte } => format!("RND V{:X}, {:#04X}", x, byte),
+        Draw { x, y, n } => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+        SkipIfKey(x) => format!("SKP V{:X}", x),
+        SkipIfNotKey(x) => format!("SKNP V{:X}", x),
+        Plane(n) => format!("PLANE {}", n),
+        Audio => String::from("AUDIO"),
+        ReadDelay(x) => format!("LD V{:X}, DT", x),
+        WaitKey(x) => format!("LD V{:X}, K", x),
+        SetDelay(x) => format!("LD DT, V{:X}", x),
+        SetSound(x) => format!("LD ST, V{:X}", x),
+        AddI(x) => format!("ADD I, V{:X}", x),
+        Font(x) => format!("LD F, V{:X}", x),
+        BigFont(x) => format!("LD HF, V{:X}", x),
+        Bcd(x) => format!("LD B, V{:X}", x),
+        Pitch(x) => format!("PITCH V{:X}", x),
+        Store(x) => format!("LD [I], V{:X}", x),
+        Restore(x) => format!("LD V{:X}, [I]", x),
+        SaveFlags(x) => format!("LD R, V{:X}", x),
+        LoadFlags(x) => format!("LD V{:X}, R", x),
+        // a long load on its own is the last word of the ROM, as listings show it with its address,
+        // and anything else is most likely data mixed in with the code
+        LoadILong | Unknown(_) => format!("DW {:#06X}", opcode),
+    }
+    .replace("0X", "0x")
+}
+
+// one disassembled instruction in a ROM listing
+pub struct Line {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    // set when a jump or call in the ROM targets this instruction
+    pub label: Option<String>,
+    pub text: String,
+}
+
+pub struct Listing {
+    pub lines: Vec<Line>,
+}
+
+fn label_for(address: u16) -> String {
+    format!("L{:03X}", address)
+}
+
+// disassemble a whole ROM loaded at load_address, labelling every jump and call target in it
+pub fn disassemble_rom(rom: &[u8], load_address: u16, platform: Platform) -> Listing {
+    // first pass: split the ROM into instructions and find where they jump to
+    let mut instructions = Vec::new();
+    let mut targets = BTreeSet::new();
+    let mut offset = 0;
+    while offset < rom.len() {
+        let address = load_address.wrapping_add(offset as u16);
+        if offset + 1 == rom.len() {
+            // a ROM with an odd length ends on a lone byte
+            instructions.push((address, rom[offset..].to_vec()));
+            break;
+        }
+        let opcode = (rom[offset] as u16) << 8 | rom[offset + 1] as u16;
+        let long_load = opcode == 0xF000 && platform.has_xo_chip_opcodes() && offset + 4 <= rom.len();
+        let size = if long_load { 4 } else { 2 };
+        match Instruction::decode_for(opcode, platform) {
+            Instruction::Jump(target) | Instruction::Call(target) | Instruction::JumpOffset(target) => {
+                targets.insert(target);
+            }
+            _ => {}
+        }
+        instructions.push((address, rom[offset..offset + size].to_vec()));
+        offset += size;
+    }
+
+    // only targets that land on the start of an instruction can be labelled
+    let starts: BTreeSet<u16> = instructions.iter().map(|(address, _)| *address).collect();
+    let labelled = |address: u16| targets.contains(&address) && starts.contains(&address);
+    let address_text = |address: u16| {
+        if labelled(address) {
+            label_for(address)
+        } else {
+            format!("{:#05X}", address).replace("0X", "0x")
+        }
+    };
+
+    // second pass: render the instructions, pointing jumps and calls at labels
+    let lines = instructions
+        .into_iter()
+        .map(|(address, bytes)| {
+            let text = match bytes.len() {
+                1 => format!("DB {:#04X}", bytes[0]).replace("0X", "0x"),
+                4 => format!("LD I, LONG {:#06X}", (bytes[2] as u16) << 8 | bytes[3] as u16).replace("0X", "0x"),
+                _ => mnemonic((bytes[0] as u16) << 8 | bytes[1] as u16, platform, &address_text),
+            };
+            Line {
+                address,
+                label: if labelled(address) { Some(label_for(address)) } else { None },
+                bytes,
+                text,
+            }
+        })
+        .collect();
+
+    Listing { lines }
+}
+
+impl fmt::Display for Listing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for line in &self.lines {
+            if let Some(label) = &line.label {
+                writeln!(f, "{}:", label)?;
+            }
+            let bytes: Vec<String> = line.bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+            writeln!(f, "    {:<20} ; {:03X}: {}", line.text, line.address, bytes.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mnemonics() {
+        assert_eq!(disassemble(0x631F, Platform::Chip8), "LD V3, 0x1F");
+        assert_eq!(disassemble(0xD015, Platform::Chip8), "DRW V0, V1, 5");
+        assert_eq!(disassemble(0xEA9E, Platform::Chip8), "SKP VA");
+        assert_eq!(disassemble(0xA2F0, Platform::Chip8), "LD I, 0x2F0");
+        assert_eq!(disassemble(0xF565, Platform::Chip8), "LD V5, [I]");
+        assert_eq!(disassemble(0x812E, Platform::Chip8), "SHL V1, V2");
+        assert_eq!(disassemble(0x5121, Platform::Chip8), "DW 0x5121");
+    }
+
+    #[test]
+    fn platform_opcodes() {
+        assert_eq!(disassemble(0x00FF, Platform::Chip8), "SYS 0x0FF");
+        assert_eq!(disassemble(0x00FF, Platform::SuperChip), "HIGH");
+        assert_eq!(disassemble(0x00C4, Platform::SuperChip), "SCD 4");
+        assert_eq!(disassemble(0xF375, Platform::SuperChip), "LD R, V3");
+        assert_eq!(disassemble(0x5232, Platform::SuperChip), "DW 0x5232");
+        assert_eq!(disassemble(0x5232, Platform::XoChip), "SAVE V2, V3");
+        assert_eq!(disassemble(0xF201, Platform::XoChip), "PLANE 2");
+    }
+
+    #[test]
+    fn rom_listing_labels_targets() {
+        // CALL 0x206; JP 0x200; JP 0x300; RET
+        let rom = [0x22, 0x06, 0x12, 0x00, 0x13, 0x00, 0x00, 0xEE];
+        let listing = disassemble_rom(&rom, 0x200, Platform::Chip8);
+
+        let text: Vec<&str> = listing.lines.iter().map(|line| line.text.as_str()).collect();
+        assert_eq!(text, vec!["CALL L206", "JP L200", "JP 0x300", "RET"]);
+        assert_eq!(listing.lines[0].label.as_deref(), Some("L200"));
+        assert_eq!(listing.lines[3].label.as_deref(), Some("L206"));
+        assert!(listing.to_string().contains("L206:\n    RET                  ; 206: 00 EE\n"));
+    }
+
+    #[test]
+    fn rom_listing_long_load_and_odd_length() {
+        let rom = [0xF0, 0x00, 0x12, 0x34, 0x00, 0xE0, 0xAB];
+        let listing = disassemble_rom(&rom, 0x200, Platform::XoChip);
+
+        let text: Vec<&str> = listing.lines.iter().map(|line| line.text.as_str()).collect();
+        assert_eq!(text, vec!["LD I, LONG 0x1234", "CLS", "DB 0xAB"]);
+        assert_eq!(listing.lines[1].address, 0x204);
+    }
+}
@@ -0,0 +1,249 @@
+use crate::cpu::{HIRES_HEIGHT, HIRES_WIDTH};
+
+// The display, packed a bit per pixel. Each row of each plane is a u128, bit x holding the pixel
+// in column x, so a sprite row is drawn with one shift and XOR and its collisions found with one
+// AND. It is always sized for hi-res, in lo-res mode only the top left 64x32 pixels are used.
+// Every row that changes is marked dirty, so a frontend can skip redrawing frames where nothing
+// was drawn.
+
+// XO-CHIP has two planes, the other platforms only draw on the first
+pub const PLANES: usize = 2;
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct Display {
+    planes: [[u128; HIRES_HEIGHT]; PLANES],
+    // a bit per row that changed since the frontend last took them
+    dirty_rows: u64,
+}
+
+impl Default for Display {
+    fn default() -> Display {
+        Display::new()
+    }
+}
+
+// the columns 0..width of a row
+fn row_mask(width: usize) -> u128 {
+    if width >= HIRES_WIDTH {
+        !0
+    } else {
+        (1 << width) - 1
+    }
+}
+
+impl Display {
+    // a blank display, every row dirty so it gets drawn the first time
+    pub fn new() -> Display {
+        Display { planes: [[0; HIRES_HEIGHT]; PLANES], dirty_rows: !0 }
+    }
+
+    // the pixel at (x, y) with a bit for each plane it is lit on, 0 to 3
+    pub fn pixel(&self, x: usize, y: usize) -> u8 {
+        let mut pixel = 0;
+        for (plane, rows) in self.planes.iter().enumerate() {
+            pixel |= ((rows[y] >> x) as u8 & 1) << plane;
+        }
+        pixel
+    }
+
+    // light (x, y) on the planes set in value, and clear it on the rest
+    pub fn set_pixel(&mut self, x: usize, y: usize, value: u8) {
+        for (plane, rows) in self.planes.iter_mut().enumerate() {
+            let row = (rows[y] & !(1 << x)) | ((value as u128 >> plane) & 1) << x;
+            if row != rows[y] {
+                rows[y] = row;
+                self.dirty_rows |= 1 << y;
+            }
+        }
+    }
+
+    // every pixel of the hi-res display a row at a time, as pixel gives them
+    pub fn pixels(&self) -> Vec<u8> {
+        let mut pixels = Vec::with_capacity(HIRES_WIDTH * HIRES_HEIGHT);
+        for y in 0..HIRES_HEIGHT {
+            let (mut first, mut second) = (self.planes[0][y], self.planes[1][y]);
+            for _ in 0..HIRES_WIDTH {
+                pixels.push((first as u8 & 1) | (second as u8 & 1) << 1);
+                first >>= 1;
+                second >>= 1;
+            }
+        }
+        pixels
+    }
+
+    // a display from the pixels of one, in the order pixels gives them
+    pub fn from_pixels(pixels: &[u8]) -> Display {
+        let mut display = Display::new();
+        for (index, pixel) in pixels.iter().enumerate().take(HIRES_WIDTH * HIRES_HEIGHT) {
+            let (x, y) = (index % HIRES_WIDTH, index / HIRES_WIDTH);
+            for (plane, rows) in display.planes.iter_mut().enumerate() {
+                rows[y] |= ((*pixel as u128 >> plane) & 1) << x;
+            }
+        }
+        display
+    }
+
+    // a row of one plane, bit x holding the pixel in column x
+    pub fn row(&self, plane: usize, y: usize) -> u128 {
+        self.planes[plane][y]
+    }
+
+    // clear the planes set in the planes bitmask
+    pub fn clear(&mut self, planes: u8) {
+        for (plane, rows) in self.planes.iter_mut().enumerate() {
+            if planes & (1 << plane) == 0 {
+                continue;
+            }
+            for (y, row) in rows.iter_mut().enumerate() {
+                if *row != 0 {
+                    *row = 0;
+                    self.dirty_rows |= 1 << y;
+                }
+            }
+        }
+    }
+
+    // XOR a sprite row onto a plane at (x, y), returning whether it turned off a lit pixel
+    // bits holds up to 16 pixels from the top bit down, an 8 pixel wide sprite uses the top byte.
+    // Pixels past the right edge of a width wide display wrap around to the left, or are clipped.
+    pub fn draw_row(&mut self, plane: usize, x: usize, y: usize, bits: u16, width: usize, clip: bool) -> bool {
+        // flip the row so its leftmost pixel is in bit 0 like the display's
+        let sprite = bits.reverse_bits() as u128;
+        let shifted = if width >= HIRES_WIDTH {
+            if clip {
+                sprite << x
+            } else {
+                sprite.rotate_left(x as u32)
+            }
+        } else {
+            // x is inside a narrower display, so nothing is shifted out of the u128
+            let wide = sprite << x;
+            let wrapped = if clip { wide } else { wide | wide >> width };
+            wrapped & row_mask(width)
+        };
+
+        let row = &mut self.planes[plane][y];
+        let collision = *row & shifted != 0;
+        *row ^= shifted;
+        if shifted != 0 {
+            self.dirty_rows |= 1 << y;
+        }
+        collision
+    }
+
+    // move the planes set in the planes bitmask of the top left width x height pixels by
+    // (dx, dy), pixels scrolled in are blank
+    pub fn scroll(&mut self, planes: u8, dx: isize, dy: isize, width: usize, height: usize) {
+        let mask = row_mask(width);
+        for (plane, rows) in self.planes.iter_mut().enumerate() {
+            if planes & (1 << plane) == 0 {
+                continue;
+            }
+            let source = *rows;
+            for (y, row) in rows.iter_mut().enumerate().take(height) {
+                let source_y = y as isize - dy;
+                let scrolled = if source_y >= 0 && source_y < height as isize {
+                    let source_row = source[source_y as usize] & mask;
+                    match dx {
+                        dx if dx >= 0 => source_row << dx,
+                        dx => source_row >> -dx,
+                    }
+                } else {
+                    0
+                };
+                let scrolled = (*row & !mask) | (scrolled & mask);
+                if scrolled != *row {
+                    *row = scrolled;
+                    self.dirty_rows |= 1 << y;
+                }
+            }
+        }
+    }
+
+    // whether anything changed since the frontend last took the dirty rows
+    pub fn is_dirty(&self) -> bool {
+        self.dirty_rows != 0
+    }
+
+    // the rows that changed since the last call, a bit per row, and start tracking again
+    pub fn take_dirty_rows(&mut self) -> u64 {
+        std::mem::replace(&mut self.dirty_rows, 0)
+    }
+
+    // have the frontend redraw everything, when the resolution or palette changed
+    pub fn mark_dirty(&mut self) {
+        self.dirty_rows = !0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sprite_rows_xor_and_collide() {
+        let mut display = Display::new();
+        assert!(!display.draw_row(0, 2, 1, 0b1010_0000 << 8, 64, false), "nothing to collide with");
+        assert_eq!((display.pixel(2, 1), display.pixel(3, 1), display.pixel(4, 1)), (1, 0, 1));
+        assert!(display.draw_row(0, 4, 1, 0b1000_0000 << 8, 64, false), "turned off a lit pixel");
+        assert_eq!(display.pixel(4, 1), 0);
+        assert!(!display.draw_row(1, 2, 1, 0b1000_0000 << 8, 64, false), "the other plane is blank");
+        assert_eq!(display.pixel(2, 1), 3, "lit on both planes");
+    }
+
+    #[test]
+    fn sprite_rows_wrap_or_clip_at_the_edge() {
+        let mut display = Display::new();
+        display.draw_row(0, 60, 0, 0xFF00, 64, false);
+        assert_eq!(display.row(0, 0), 0xF000_0000_0000_000F, "wrapped at column 64");
+        display.draw_row(0, 60, 1, 0xFF00, 64, true);
+        assert_eq!(display.row(0, 1), 0xF000_0000_0000_0000, "clipped at column 64");
+        display.draw_row(0, 120, 2, 0xFFFF, 128, false);
+        assert_eq!(display.row(0, 2), 0xFF << 120 | 0xFF, "wrapped at column 128");
+        display.draw_row(0, 120, 3, 0xFFFF, 128, true);
+        assert_eq!(display.row(0, 3), 0xFF << 120, "clipped at column 128");
+    }
+
+    #[test]
+    fn pixels_round_trip() {
+        let mut display = Display::new();
+        display.set_pixel(127, 63, 3);
+        display.set_pixel(5, 2, 2);
+        let pixels = display.pixels();
+        assert_eq!(pixels.len(), HIRES_WIDTH * HIRES_HEIGHT);
+        assert_eq!(pixels[2 * HIRES_WIDTH + 5], 2);
+        assert!(Display::from_pixels(&pixels) == display);
+    }
+
+    #[test]
+    fn only_changes_are_dirty() {
+        let mut display = Display::new();
+        assert!(display.is_dirty(), "a new display needs drawing");
+        display.take_dirty_rows();
+        assert!(!display.is_dirty());
+
+        display.clear(1);
+        assert!(!display.is_dirty(), "clearing a blank display changes nothing");
+        display.draw_row(0, 0, 5, 0x8000, 64, false);
+        display.draw_row(0, 0, 7, 0, 64, false);
+        assert_eq!(display.take_dirty_rows(), 1 << 5, "the empty row changed nothing");
+        display.clear(2);
+        assert!(!display.is_dirty(), "the other plane was already blank");
+        display.clear(1);
+        assert_eq!(display.take_dirty_rows(), 1 << 5);
+    }
+
+    #[test]
+    fn scrolling_stays_inside_the_screen() {
+        let mut display = Display::new();
+        display.set_pixel(62, 0, 1);
+        display.set_pixel(100, 0, 1);
+        display.scroll(1, 4, 1, 64, 32);
+        assert_eq!(display.pixel(62, 0), 0);
+        assert_eq!(display.pixel(66, 1), 0, "scrolled off the lo-res screen");
+        assert_eq!(display.pixel(100, 0), 1, "outside the lo-res screen, left alone");
+        display.set_pixel(3, 3, 1);
+        display.scroll(1, -2, -3, 64, 32);
+        assert_eq!(display.pixel(1, 0), 1);
+    }
+}
@@ -0,0 +1,94 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+// Everything that can go wrong while loading or running a ROM. Faults raised by an instruction
+// carry the address it was fetched from and the opcode, so a frontend can report the crash.
+#[derive(Debug)]
+pub enum CpuError {
+    // CALL with all 16 levels of the stack in use
+    StackOverflow { pc: u16, opcode: u16 },
+
+    // RET with nothing on the stack
+    StackUnderflow { pc: u16, opcode: u16 },
+
+    // the instruction reached past the end of memory
+    MemoryOutOfBounds { pc: u16, opcode: u16, address: usize },
+
+    // the opcode is not part of the instruction set for the current platform
+    UnknownOpcode { pc: u16, opcode: u16 },
+
+    // the ROM does not fit between the load address and the end of memory
+    RomTooLarge { size: usize, max: usize },
+
+    // the ROM could not be read
+    Io(io::Error),
+
+    // a save state was corrupt, truncated or from an unsupported version
+    InvalidSaveState(String),
+}
+
+impl CpuError {
+    // the address of the instruction that faulted, if the error came from running one
+    pub fn pc(&self) -> Option<u16> {
+        match *self {
+            CpuError::StackOverflow { pc, .. }
+            | CpuError::StackUnderflow { pc, .. }
+            | CpuError::MemoryOutOfBounds { pc, .. }
+            | CpuError::UnknownOpcode { pc, .. } => Some(pc),
+            CpuError::RomTooLarge { .. } | CpuError::Io(_) | CpuError::InvalidSaveState(_) => None,
+        }
+    }
+
+    // the instruction that faulted, if the error came from running one
+    pub fn opcode(&self) -> Option<u16> {
+        match *self {
+            CpuError::StackOverflow { opcode, .. }
+            | CpuError::StackUnderflow { opcode, .. }
+            | CpuError::MemoryOutOfBounds { opcode, .. }
+            | CpuError::UnknownOpcode { opcode, .. } => Some(opcode),
+            CpuError::RomTooLarge { .. } | CpuError::Io(_) | CpuError::InvalidSaveState(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CpuError::StackOverflow { pc, opcode } => {
+                write!(f, "stack overflow at {:#05X} ({:04X})", pc, opcode)
+            }
+            CpuError::StackUnderflow { pc, opcode } => {
+                write!(f, "stack underflow at {:#05X} ({:04X})", pc, opcode)
+            }
+            CpuError::MemoryOutOfBounds { pc, opcode, address } => write!(
+                f,
+                "memory access out of bounds at {:#05X} ({:04X}): address {:#05X}",
+                pc, opcode, address
+            ),
+            CpuError::UnknownOpcode { pc, opcode } => {
+                write!(f, "unknown opcode at {:#05X} ({:04X})", pc, opcode)
+            }
+            CpuError::RomTooLarge { size, max } => {
+                write!(f, "rom is {} bytes, but only {} bytes fit in memory", size, max)
+            }
+            CpuError::Io(err) => write!(f, "could not read rom: {}", err),
+            CpuError::InvalidSaveState(reason) => write!(f, "invalid save state: {}", reason),
+        }
+    }
+}
+
+impl Error for CpuError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CpuError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for CpuError {
+    fn from(err: io::Error) -> CpuError {
+        CpuError::Io(err)
+    }
+}
@@ -0,0 +1,424 @@
+use crate::cpu::Cpu;
+use crate::error::CpuError;
+use crate::parse;
+use std::fmt;
+use std::fmt::Write as _;
+use std::str::FromStr;
+
+// Runs a ROM without a window or terminal, for CI and scripted checks: a fixed number of frames
+// or until a stop condition, with key presses fed in on given frames. The display can then be
+// written as ASCII art, a PBM or a PNG, and the registers as JSON.
+
+// something that ends a run early, checked before each instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopCondition {
+    // the pc reaches the address
+    Pc(u16),
+
+    // the byte at the address holds the value
+    Memory { address: u16, value: u8 },
+
+    // this many instructions have run
+    Cycles(u64),
+}
+
+impl StopCondition {
+    fn reached(self, cpu: &Cpu, cycles: u64) -> bool {
+        match self {
+            StopCondition::Pc(pc) => cpu.pc() == pc,
+            StopCondition::Memory { address, value } => cpu.memory().get(address as usize) == Some(&value),
+            StopCondition::Cycles(count) => cycles >= count,
+        }
+    }
+}
+
+// written pc=0x2A4, mem[0x300]=0x05 or cycles=1000
+impl FromStr for StopCondition {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<StopCondition, String> {
+        let invalid = || format!("invalid stop condition '{}', expected pc=ADDR, mem[ADDR]=VALUE or cycles=N", text);
+        let (name, value) = text.split_once('=').ok_or_else(invalid)?;
+        let name = name.trim().to_ascii_lowercase();
+        let value = value.trim();
+        if name == "pc" {
+            return parse::number(value).map(StopCondition::Pc).ok_or_else(invalid);
+        }
+        if name == "cycles" {
+            return parse::number(value).map(StopCondition::Cycles).ok_or_else(invalid);
+        }
+        let address = name.strip_prefix("mem[").and_then(|rest| rest.strip_suffix(']')).ok_or_else(invalid)?;
+        let address = parse::number(address).ok_or_else(invalid)?;
+        let value = parse::number(value).ok_or_else(invalid)?;
+        Ok(StopCondition::Memory { address, value })
+    }
+}
+
+impl fmt::Display for StopCondition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StopCondition::Pc(pc) => write!(f, "pc={:#05X}", pc),
+            StopCondition::Memory { address, value } => write!(f, "mem[{:#05X}]={:#04X}", address, value),
+            StopCondition::Cycles(count) => write!(f, "cycles={}", count),
+        }
+    }
+}
+
+// a key held down from the start of a frame for a number of frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyPress {
+    pub frame: u64,
+    pub key: u8,
+    pub frames: u64,
+}
+
+impl KeyPress {
+    fn is_down(self, frame: u64) -> bool {
+        frame >= self.frame && frame - self.frame < self.frames
+    }
+}
+
+// written FRAME:KEY or FRAME:KEY:FRAMES, the key as a hex digit, held for one frame by default
+impl FromStr for KeyPress {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<KeyPress, String> {
+        let invalid = || format!("invalid key press '{}', expected FRAME:KEY or FRAME:KEY:FRAMES", text);
+        let parts: Vec<&str> = text.split(':').collect();
+        if parts.len() < 2 || parts.len() > 3 {
+            return Err(invalid());
+        }
+        let frame = parse::number(parts[0]).ok_or_else(invalid)?;
+        let key = u8::from_str_radix(parts[1], 16).ok().filter(|key| *key < 16).ok_or_else(invalid)?;
+        let frames = match parts.get(2) {
+            Some(frames) => parse::number(frames).ok_or_else(invalid)?,
+            None => 1,
+        };
+        Ok(KeyPress { frame, key, frames })
+    }
+}
+
+// key presses separated by commas or whitespace, e.g. "30:5 60:5:10", # starts a comment
+pub fn parse_key_script(script: &str) -> Result<Vec<KeyPress>, String> {
+    script
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or(""))
+        .flat_map(|line| line.split(|c: char| c == ',' || c.is_whitespace()))
+        .filter(|press| !press.is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+// why a run ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stopped {
+    // all the frames ran
+    Frames,
+
+    // the program exited with 00FD
+    Exited,
+
+    Condition(StopCondition),
+}
+
+impl fmt::Display for Stopped {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stopped::Frames => write!(f, "frames"),
+            Stopped::Exited => write!(f, "exited"),
+            Stopped::Condition(condition) => write!(f, "{}", condition),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Outcome {
+    // whole frames run, including the timer tick at the end of each
+    pub frames: u64,
+    pub cycles: u64,
+    pub stopped: Stopped,
+}
+
+pub struct Headless {
+    pub frames: u64,
+    pub instructions_per_frame: usize,
+    pub stop: Vec<StopCondition>,
+    pub keys: Vec<KeyPress>,
+}
+
+impl Headless {
+    pub fn new(frames: u64, instructions_per_frame: usize) -> Headless {
+        Headless { frames, instructions_per_frame, stop: Vec::new(), keys: Vec::new() }
+    }
+
+    // Runs like Cpu::run_frame, a frame at a time, but checks the stop conditions before every
+    // instruction. A fault is returned as is, the CPU is left where it crashed.
+    pub fn run(&self, cpu: &mut Cpu) -> Result<Outcome, CpuError> {
+        self.run_from(cpu, 0)
+    }
+
+    // Carries on a run from first_frame up to frames, so a long run can be split up to look at the
+    // CPU along the way while the key presses stay on the same frames. Cycles count from here.
+    pub fn run_from(&self, cpu: &mut Cpu, first_frame: u64) -> Result<Outcome, CpuError> {
+        let mut cycles = 0;
+        for frame in first_frame..self.frames {
+            cpu.keyboard.reset();
+            for press in self.keys.iter().filter(|press| press.is_down(frame)) {
+                cpu.keyboard.press_key(press.key);
+            }
+
+            for _ in 0..self.instructions_per_frame {
+                if cpu.has_exited() {
+                    return Ok(Outcome { frames: frame, cycles, stopped: Stopped::Exited });
+                }
+                if cpu.is_waiting_for_display() {
+                    break;
+                }
+                if let Some(&condition) = self.stop.iter().find(|condition| condition.reached(cpu, cycles)) {
+                    return Ok(Outcome { frames: frame, cycles, stopped: Stopped::Condition(condition) });
+                }
+                cpu.execute_cycle()?;
+                cycles += 1;
+            }
+            cpu.tick_timers();
+        }
+        let stopped = if cpu.has_exited() { Stopped::Exited } else { Stopped::Frames };
+        Ok(Outcome { frames: self.frames, cycles, stopped })
+    }
+}
+
+// a 64 bit FNV-1a hash of the display, its size and the planes of every pixel, for telling
+// whether two runs drew the same thing
+pub fn display_hash(cpu: &Cpu) -> u64 {
+    let mut hash: u64 = 0xCBF2_9CE4_8422_2325;
+    let mut add = |byte: u8| {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01B3);
+    };
+    add(cpu.width() as u8);
+    add(cpu.height() as u8);
+    for y in 0..cpu.height() {
+        for x in 0..cpu.width() {
+            add(cpu.display.pixel(x, y));
+        }
+    }
+    hash
+}
+
+fn is_lit(cpu: &Cpu, x: usize, y: usize) -> bool {
+    cpu.display.pixel(x, y) != 0
+}
+
+// the display as rows of # and ., any plane counting as lit
+pub fn to_ascii(cpu: &Cpu) -> String {
+    let mut text = String::with_capacity((cpu.width() + 1) * cpu.height());
+    for y in 0..cpu.height() {
+        for x in 0..cpu.width() {
+            text.push(if is_lit(cpu, x, y) { '#' } else { '.' });
+        }
+        text.push('\n');
+    }
+    text
+}
+
+// the display as a plain (P1) PBM, where 1 is black, so lit pixels are 0 to keep the image the
+// same way round as on screen
+pub fn to_pbm(cpu: &Cpu) -> String {
+    let mut text = format!("P1\n{} {}\n", cpu.width(), cpu.height());
+    for y in 0..cpu.height() {
+        let row: Vec<&str> = (0..cpu.width()).map(|x| if is_lit(cpu, x, y) { "0" } else { "1" }).collect();
+        text += &row.join(" ");
+        text.push('\n');
+    }
+    text
+}
+
+// The display as an RGB PNG, coloured with the palette for each combination of planes. The
+// image data is stored uncompressed, which keeps this free of dependencies and is still small at
+// these sizes.
+pub fn to_png(cpu: &Cpu, palette: &[u32; 4]) -> Vec<u8> {
+    let (width, height) = (cpu.width(), cpu.height());
+    let mut pixels = Vec::with_capacity((width * 3 + 1) * height);
+    for y in 0..height {
+        // no filter
+        pixels.push(0);
+        for x in 0..width {
+            let colour = palette[cpu.display.pixel(x, y) as usize];
+            pixels.extend_from_slice(&[(colour >> 16) as u8, (colour >> 8) as u8, colour as u8]);
+        }
+    }
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // 8 bit RGB, deflate, adaptive filtering, not interlaced
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    let mut png = vec![0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+    png_chunk(&mut png, b"IHDR", &header);
+    png_chunk(&mut png, b"IDAT", &zlib_stored(&pixels));
+    png_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn png_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+// a zlib stream of uncompressed deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut stream = vec![0x78, 0x01];
+    let mut blocks = data.chunks(0xFFFF).peekable();
+    if blocks.peek().is_none() {
+        stream.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        stream.push(last as u8);
+        let len = block.len() as u16;
+        stream.extend_from_slice(&len.to_le_bytes());
+        stream.extend_from_slice(&(!len).to_le_bytes());
+        stream.extend_from_slice(block);
+    }
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    stream.extend_from_slice(&(b << 16 | a).to_be_bytes());
+    stream
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { crc >> 1 ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+// the machine state at the end of a run as a JSON object, numbers in decimal
+pub fn registers_json(cpu: &Cpu, outcome: &Outcome) -> String {
+    let list = |values: Vec<String>| values.join(", ");
+    let mut json = String::from("{\n");
+    let _ = writeln!(json, "  \"frames\": {},", outcome.frames);
+    let _ = writeln!(json, "  \"cycles\": {},", outcome.cycles);
+    let _ = writeln!(json, "  \"stopped\": \"{}\",", outcome.stopped);
+    let _ = writeln!(json, "  \"pc\": {},", cpu.pc());
+    let _ = writeln!(json, "  \"i\": {},", cpu.i());
+    let _ = writeln!(json, "  \"v\": [{}],", list(cpu.registers().iter().map(u8::to_string).collect()));
+    let _ = writeln!(json, "  \"delay_timer\": {},", cpu.delay_timer());
+    let _ = writeln!(json, "  \"sound_timer\": {},", cpu.sound_timer());
+    let _ = writeln!(json, "  \"stack\": [{}],", list(cpu.call_stack().iter().map(u16::to_string).collect()));
+    let _ = writeln!(json, "  \"hires\": {},", cpu.is_hires());
+    let _ = writeln!(json, "  \"exited\": {}", cpu.has_exited());
+    json.push('}');
+    json.push('\n');
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chip(rom: &[u8]) -> Cpu {
+        let mut chip = Cpu::new();
+        chip.load_rom_bytes(rom).unwrap();
+        chip
+    }
+
+    #[test]
+    fn parses_conditions_and_keys() {
+        assert_eq!("pc=0x2A4".parse(), Ok(StopCondition::Pc(0x2A4)));
+        assert_eq!("mem[0x300]=5".parse(), Ok(StopCondition::Memory { address: 0x300, value: 5 }));
+        assert_eq!("cycles=1000".parse(), Ok(StopCondition::Cycles(1000)));
+        assert!("pc".parse::<StopCondition>().is_err());
+        assert!("mem[0x300]=0x100".parse::<StopCondition>().is_err());
+
+        let keys = parse_key_script("30:5, 60:a:10 # serve\n90:F").unwrap();
+        assert_eq!(
+            keys,
+            vec![
+                KeyPress { frame: 30, key: 5, frames: 1 },
+                KeyPress { frame: 60, key: 0xA, frames: 10 },
+                KeyPress { frame: 90, key: 0xF, frames: 1 },
+            ]
+        );
+        assert!(parse_key_script("30:G").is_err());
+    }
+
+    #[test]
+    fn stops_on_conditions() {
+        // 200: LD V0, 0  202: ADD V0, 1  204: LD I, 0x300  206: LD [I], V0  208: JP 0x202
+        let rom = [0x60, 0x00, 0x70, 0x01, 0xA3, 0x00, 0xF0, 0x55, 0x12, 0x02];
+
+        let mut cpu = chip(&rom);
+        let mut run = Headless::new(100, 10);
+        run.stop.push("mem[0x300]=3".parse().unwrap());
+        let outcome = run.run(&mut cpu).unwrap();
+        assert_eq!(outcome.stopped, Stopped::Condition(StopCondition::Memory { address: 0x300, value: 3 }));
+        assert_eq!(cpu.registers()[0], 3);
+        // the first instruction, two passes through the loop and the third up to the store
+        assert_eq!(outcome.cycles, 12);
+        assert_eq!(outcome.frames, 1);
+
+        let mut cpu = chip(&rom);
+        let mut run = Headless::new(100, 10);
+        run.stop.push(StopCondition::Cycles(25));
+        assert_eq!(run.run(&mut cpu).unwrap().frames, 2);
+
+        let mut cpu = chip(&rom);
+        let outcome = Headless::new(3, 10).run(&mut cpu).unwrap();
+        assert_eq!(outcome, Outcome { frames: 3, cycles: 30, stopped: Stopped::Frames });
+        let outcome = Headless::new(5, 10).run_from(&mut cpu, 3).unwrap();
+        assert_eq!(outcome, Outcome { frames: 5, cycles: 20, stopped: Stopped::Frames });
+    }
+
+    #[test]
+    fn feeds_scripted_keys() {
+        // 200: LD V0, K  202: LD V1, 1  204: JP 0x204
+        let mut cpu = chip(&[0xF0, 0x0A, 0x61, 0x01, 0x12, 0x04]);
+        let mut run = Headless::new(10, 10);
+        run.keys = parse_key_script("4:7").unwrap();
+        run.stop.push(StopCondition::Pc(0x204));
+        let outcome = run.run(&mut cpu).unwrap();
+        assert_eq!(outcome.stopped, Stopped::Condition(StopCondition::Pc(0x204)));
+        assert_eq!(outcome.frames, 4);
+        assert_eq!(cpu.registers()[0], 7);
+    }
+
+    #[test]
+    fn writes_the_display() {
+        // draw the 0 glyph at 0, 0 and loop
+        let mut cpu = chip(&[0x60, 0x00, 0xF0, 0x29, 0xD0, 0x05, 0x12, 0x06]);
+        Headless::new(1, 10).run(&mut cpu).unwrap();
+
+        let ascii = to_ascii(&cpu);
+        assert_eq!(ascii.lines().count(), 32);
+        assert!(ascii.starts_with("####....."));
+        assert!(ascii.lines().nth(1).unwrap().starts_with("#..#....."));
+
+        let blank = display_hash(&chip(&[]));
+        assert_ne!(display_hash(&cpu), blank);
+
+        let pbm = to_pbm(&cpu);
+        assert!(pbm.starts_with("P1\n64 32\n0 0 0 0 1 1"));
+
+        let png = to_png(&cpu, &[0x000000, 0xFFFFFF, 0xFF0000, 0x00FF00]);
+        assert_eq!(&png[1..4], b"PNG");
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+
+        let json = registers_json(&cpu, &Outcome { frames: 1, cycles: 3, stopped: Stopped::Frames });
+        assert!(json.contains("\"pc\": 518,"));
+        assert!(json.contains("\"stopped\": \"frames\","));
+    }
+}
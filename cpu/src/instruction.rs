@@ -0,0 +1,306 @@
+use crate::platform::Platform;
+
+// Every instruction the CPU knows, decoded from its opcode once so the executor, disassembler and
+// assembler all agree on what each opcode means. Registers are indexes into V, addresses the
+// 12 bit nnn of the opcode. Each variant names the opcode and mnemonic it comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    // 00CN SCD nibble
+    ScrollDown(u8),
+    // 00DN SCU nibble
+    ScrollUp(u8),
+    // 00E0 CLS
+    Clear,
+    // 00EE RET
+    Return,
+    // 00FB SCR
+    ScrollRight,
+    // 00FC SCL
+    ScrollLeft,
+    // 00FD EXIT
+    Exit,
+    // 00FE LOW
+    LowRes,
+    // 00FF HIGH
+    HighRes,
+    // 0NNN SYS addr
+    System(u16),
+    // 1NNN JP addr
+    Jump(u16),
+    // 2NNN CALL addr
+    Call(u16),
+    // 3XKK SE Vx, byte
+    SkipIfEqual { x: u8, byte: u8 },
+    // 4XKK SNE Vx, byte
+    SkipIfNotEqual { x: u8, byte: u8 },
+    // 5XY0 SE Vx, Vy
+    SkipIfRegistersEqual { x: u8, y: u8 },
+    // 5XY2 SAVE Vx - Vy
+    SaveRange { x: u8, y: u8 },
+    // 5XY3 LOAD Vx - Vy
+    LoadRange { x: u8, y: u8 },
+    // 6XKK LD Vx, byte
+    Load { x: u8, byte: u8 },
+    // 7XKK ADD Vx, byte
+    AddByte { x: u8, byte: u8 },
+    // 8XY0 LD Vx, Vy
+    Move { x: u8, y: u8 },
+    // 8XY1 OR Vx, Vy
+    Or { x: u8, y: u8 },
+    // 8XY2 AND Vx, Vy
+    And { x: u8, y: u8 },
+    // 8XY3 XOR Vx, Vy
+    Xor { x: u8, y: u8 },
+    // 8XY4 ADD Vx, Vy
+    Add { x: u8, y: u8 },
+    // 8XY5 SUB Vx, Vy
+    Sub { x: u8, y: u8 },
+    // 8XY6 SHR Vx {, Vy}
+    ShiftRight { x: u8, y: u8 },
+    // 8XY7 SUBN Vx, Vy
+    SubFrom { x: u8, y: u8 },
+    // 8XYE SHL Vx {, Vy}
+    ShiftLeft { x: u8, y: u8 },
+    // 9XY0 SNE Vx, Vy
+    SkipIfRegistersNotEqual { x: u8, y: u8 },
+    // ANNN LD I, addr
+    LoadI(u16),
+    // BNNN JP V0, addr
+    JumpOffset(u16),
+    // CXKK RND Vx, byte
+    Random { x: u8, byte: u8 },
+    // DXYN DRW Vx, Vy, nibble
+    Draw { x: u8, y: u8, n: u8 },
+    // EX9E SKP Vx
+    SkipIfKey(u8),
+    // EXA1 SKNP Vx
+    SkipIfNotKey(u8),
+    // F000 NNNN LD I, long addr, the address is the word after the opcode
+    LoadILong,
+    // FN01 PLANE n
+    Plane(u8),
+    // F002 AUDIO
+    Audio,
+    // FX07 LD Vx, DT
+    ReadDelay(u8),
+    // FX0A LD Vx, K
+    WaitKey(u8),
+    // FX15 LD DT, Vx
+    SetDelay(u8),
+    // FX18 LD ST, Vx
+    SetSound(u8),
+    // FX1E ADD I, Vx
+    AddI(u8),
+    // FX29 LD F, Vx
+    Font(u8),
+    // FX30 LD HF, Vx
+    BigFont(u8),
+    // FX33 LD B, Vx
+    Bcd(u8),
+    // FX3A PITCH Vx
+    Pitch(u8),
+    // FX55 LD [I], Vx
+    Store(u8),
+    // FX65 LD Vx, [I]
+    Restore(u8),
+    // FX75 LD R, Vx
+    SaveFlags(u8),
+    // FX85 LD Vx, R
+    LoadFlags(u8),
+    // not an instruction on any platform
+    Unknown(u16),
+}
+
+impl Instruction {
+    // what the opcode means on the platform with the most instructions, XO-CHIP
+    pub fn decode(opcode: u16) -> Instruction {
+        use Instruction::*;
+        let x = (opcode >> 8 & 0xF) as u8;
+        let y = (opcode >> 4 & 0xF) as u8;
+        let n = (opcode & 0xF) as u8;
+        let byte = opcode as u8;
+        let addr = opcode & 0x0FFF;
+
+        match (opcode >> 12, x, y, n) {
+            (0x0, 0x0, 0xC, _) => ScrollDown(n),
+            (0x0, 0x0, 0xD, _) => ScrollUp(n),
+            (0x0, 0x0, 0xE, 0x0) => Clear,
+            (0x0, 0x0, 0xE, 0xE) => Return,
+            (0x0, 0x0, 0xF, 0xB) => ScrollRight,
+            (0x0, 0x0, 0xF, 0xC) => ScrollLeft,
+            (0x0, 0x0, 0xF, 0xD) => Exit,
+            (0x0, 0x0, 0xF, 0xE) => LowRes,
+            (0x0, 0x0, 0xF, 0xF) => HighRes,
+            (0x0, _, _, _) => System(addr),
+            (0x1, _, _, _) => Jump(addr),
+            (0x2, _, _, _) => Call(addr),
+            (0x3, _, _, _) => SkipIfEqual { x, byte },
+            (0x4, _, _, _) => SkipIfNotEqual { x, byte },
+            (0x5, _, _, 0x0) => SkipIfRegistersEqual { x, y },
+            (0x5, _, _, 0x2) => SaveRange { x, y },
+            (0x5, _, _, 0x3) => LoadRange { x, y },
+            (0x6, _, _, _) => Load { x, byte },
+            (0x7, _, _, _) => AddByte { x, byte },
+            (0x8, _, _, 0x0) => Move { x, y },
+            (0x8, _, _, 0x1) => Or { x, y },
+            (0x8, _, _, 0x2) => And { x, y },
+            (0x8, _, _, 0x3) => Xor { x, y },
+            (0x8, _, _, 0x4) => Add { x, y },
+            (0x8, _, _, 0x5) => Sub { x, y },
+            (0x8, _, _, 0x6) => ShiftRight { x, y },
+            (0x8, _, _, 0x7) => SubFrom { x, y },
+            (0x8, _, _, 0xE) => ShiftLeft { x, y },
+            (0x9, _, _, 0x0) => SkipIfRegistersNotEqual { x, y },
+            (0xA, _, _, _) => LoadI(addr),
+            (0xB, _, _, _) => JumpOffset(addr),
+            (0xC, _, _, _) => Random { x, byte },
+            (0xD, _, _, _) => Draw { x, y, n },
+            (0xE, _, 0x9, 0xE) => SkipIfKey(x),
+            (0xE, _, 0xA, 0x1) => SkipIfNotKey(x),
+            (0xF, 0x0, 0x0, 0x0) => LoadILong,
+            (0xF, _, 0x0, 0x1) => Plane(x),
+            (0xF, 0x0, 0x0, 0x2) => Audio,
+            (0xF, _, 0x0, 0x7) => ReadDelay(x),
+            (0xF, _, 0x0, 0xA) => WaitKey(x),
+            (0xF, _, 0x1, 0x5) => SetDelay(x),
+            (0xF, _, 0x1, 0x8) => SetSound(x),
+            (0xF, _, 0x1, 0xE) => AddI(x),
+            (0xF, _, 0x2, 0x9) => Font(x),
+            (0xF, _, 0x3, 0x0) => BigFont(x),
+            (0xF, _, 0x3, 0x3) => Bcd(x),
+            (0xF, _, 0x3, 0xA) => Pitch(x),
+            (0xF, _, 0x5, 0x5) => Store(x),
+            (0xF, _, 0x6, 0x5) => Restore(x),
+            (0xF, _, 0x7, 0x5) => SaveFlags(x),
+            (0xF, _, 0x8, 0x5) => LoadFlags(x),
+            _ => Unknown(opcode),
+        }
+    }
+
+    // What the opcode means on the platform. Opcodes added by a later platform are SYS calls if
+    // they start with 0, like they were on the original, and unknown otherwise.
+    pub fn decode_for(opcode: u16, platform: Platform) -> Instruction {
+        let instruction = Instruction::decode(opcode);
+        let supported = match instruction.platform() {
+            Platform::Chip8 => true,
+            Platform::SuperChip => platform.has_super_chip_opcodes(),
+            Platform::XoChip => platform.has_xo_chip_opcodes(),
+        };
+        if supported {
+            instruction
+        } else if opcode & 0xF000 == 0 {
+            Instruction::System(opcode & 0x0FFF)
+        } else {
+            Instruction::Unknown(opcode)
+        }
+    }
+
+    // the first platform with the instruction
+    pub fn platform(self) -> Platform {
+        use Instruction::*;
+        match self {
+            ScrollDown(_) | ScrollRight | ScrollLeft | Exit | LowRes | HighRes | BigFont(_) | SaveFlags(_)
+            | LoadFlags(_) => Platform::SuperChip,
+            ScrollUp(_) | SaveRange { .. } | LoadRange { .. } | LoadILong | Plane(_) | Audio | Pitch(_) => {
+                Platform::XoChip
+            }
+            _ => Platform::Chip8,
+        }
+    }
+
+    pub fn encode(self) -> u16 {
+        use Instruction::*;
+        let xkk = |x: u8, byte: u8| (x as u16) << 8 | byte as u16;
+        let xy = |x: u8, y: u8| (x as u16) << 8 | (y as u16) << 4;
+        let fx = |x: u8, low: u16| 0xF000 | (x as u16) << 8 | low;
+
+        match self {
+            ScrollDown(n) => 0x00C0 | n as u16,
+            ScrollUp(n) => 0x00D0 | n as u16,
+            Clear => 0x00E0,
+            Return => 0x00EE,
+            ScrollRight => 0x00FB,
+            ScrollLeft => 0x00FC,
+            Exit => 0x00FD,
+            LowRes => 0x00FE,
+            HighRes => 0x00FF,
+            System(addr) => addr & 0x0FFF,
+            Jump(addr) => 0x1000 | addr & 0x0FFF,
+            Call(addr) => 0x2000 | addr & 0x0FFF,
+            SkipIfEqual { x, byte } => 0x3000 | xkk(x, byte),
+            SkipIfNotEqual { x, byte } => 0x4000 | xkk(x, byte),
+            SkipIfRegistersEqual { x, y } => 0x5000 | xy(x, y),
+            SaveRange { x, y } => 0x5002 | xy(x, y),
+            LoadRange { x, y } => 0x5003 | xy(x, y),
+            Load { x, byte } => 0x6000 | xkk(x, byte),
+            AddByte { x, byte } => 0x7000 | xkk(x, byte),
+            Move { x, y } => 0x8000 | xy(x, y),
+            Or { x, y } => 0x8001 | xy(x, y),
+            And { x, y } => 0x8002 | xy(x, y),
+            Xor { x, y } => 0x8003 | xy(x, y),
+            Add { x, y } => 0x8004 | xy(x, y),
+            Sub { x, y } => 0x8005 | xy(x, y),
+            ShiftRight { x, y } => 0x8006 | xy(x, y),
+            SubFrom { x, y } => 0x8007 | xy(x, y),
+            ShiftLeft { x, y } => 0x800E | xy(x, y),
+            SkipIfRegistersNotEqual { x, y } => 0x9000 | xy(x, y),
+            LoadI(addr) => 0xA000 | addr & 0x0FFF,
+            JumpOffset(addr) => 0xB000 | addr & 0x0FFF,
+            Random { x, byte } => 0xC000 | xkk(x, byte),
+            Draw { x, y, n } => 0xD000 | xy(x, y) | (n & 0xF) as u16,
+            SkipIfKey(x) => 0xE09E | (x as u16) << 8,
+            SkipIfNotKey(x) => 0xE0A1 | (x as u16) << 8,
+            LoadILong => 0xF000,
+            Plane(n) => fx(n, 0x01),
+            Audio => 0xF002,
+            ReadDelay(x) => fx(x, 0x07),
+            WaitKey(x) => fx(x, 0x0A),
+            SetDelay(x) => fx(x, 0x15),
+            SetSound(x) => fx(x, 0x18),
+            AddI(x) => fx(x, 0x1E),
+            Font(x) => fx(x, 0x29),
+            BigFont(x) => fx(x, 0x30),
+            Bcd(x) => fx(x, 0x33),
+            Pitch(x) => fx(x, 0x3A),
+            Store(x) => fx(x, 0x55),
+            Restore(x) => fx(x, 0x65),
+            SaveFlags(x) => fx(x, 0x75),
+            LoadFlags(x) => fx(x, 0x85),
+            Unknown(opcode) => opcode,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_opcode_round_trips() {
+        for opcode in 0..=0xFFFF {
+            assert_eq!(Instruction::decode(opcode).encode(), opcode, "{:04X}", opcode);
+        }
+    }
+
+    #[test]
+    fn decodes_fields() {
+        assert_eq!(Instruction::decode(0xD125), Instruction::Draw { x: 1, y: 2, n: 5 });
+        assert_eq!(Instruction::decode(0x2ABC), Instruction::Call(0xABC));
+        assert_eq!(Instruction::decode(0x7F01), Instruction::AddByte { x: 0xF, byte: 1 });
+        assert_eq!(Instruction::decode(0x8AB7), Instruction::SubFrom { x: 0xA, y: 0xB });
+        assert_eq!(Instruction::decode(0xF30A), Instruction::WaitKey(3));
+        assert_eq!(Instruction::decode(0x5121), Instruction::Unknown(0x5121));
+        assert_eq!(Instruction::decode(0xF102), Instruction::Unknown(0xF102));
+    }
+
+    #[test]
+    fn platforms_limit_instructions() {
+        assert_eq!(Instruction::decode_for(0x00FF, Platform::Chip8), Instruction::System(0x0FF));
+        assert_eq!(Instruction::decode_for(0x00FF, Platform::SuperChip), Instruction::HighRes);
+        assert_eq!(Instruction::decode_for(0x00D2, Platform::SuperChip), Instruction::System(0x0D2));
+        assert_eq!(Instruction::decode_for(0x00D2, Platform::XoChip), Instruction::ScrollUp(2));
+        assert_eq!(Instruction::decode_for(0x5232, Platform::SuperChip), Instruction::Unknown(0x5232));
+        assert_eq!(Instruction::decode_for(0xF000, Platform::XoChip), Instruction::LoadILong);
+        assert_eq!(Instruction::decode_for(0xF375, Platform::Chip8), Instruction::Unknown(0xF375));
+    }
+}
@@ -3,6 +3,12 @@ pub struct Keyboard {
 	pub keys: [bool; 16],
 }
 
+impl Default for Keyboard {
+	fn default() -> Keyboard {
+		Keyboard::new()
+	}
+}
+
 impl Keyboard {
 	pub fn new() -> Keyboard {
 		Keyboard {
@@ -14,16 +20,30 @@ impl Keyboard {
 		self.keys = [false; 16];
 	}
 
+	// only the low nibble picks the key, like the original interpreters, so EX9E and EXA1 with a
+	// register above 0xF test a key instead of reading past the keypad
 	pub fn press_key(&mut self, key: u8) {
-		self.keys[key as usize] = true;
+		self.keys[(key & 0x0F) as usize] = true;
 	}
 
 	pub fn un_press_key(&mut self, key: u8) {
-		self.keys[key as usize] = false;
+		self.keys[(key & 0x0F) as usize] = false;
 	}
 
 	pub fn key_is_pressed(&self, key: u8) -> bool {
-		self.keys[key as usize]
+		self.keys[(key & 0x0F) as usize]
+	}
+
+	// press the keys that went down and release the ones that came up since the last call,
+	// for frontends that can see every key held at once
+	pub fn set_held(&mut self, held: [bool; 16]) {
+		for (key, down) in held.iter().enumerate() {
+			if *down && !self.keys[key] {
+				self.press_key(key as u8);
+			} else if !*down && self.keys[key] {
+				self.un_press_key(key as u8);
+			}
+		}
 	}
 }
 
@@ -34,4 +54,19 @@ mod tests {
 	fn it_works() {
 		assert_eq!(2 + 2, 4);
 	}
+
+	#[test]
+	fn held_keys_are_tracked_independently() {
+		let mut keyboard = super::Keyboard::new();
+		let mut held = [false; 16];
+		held[0x5] = true;
+		held[0x8] = true;
+		keyboard.set_held(held);
+		assert!(keyboard.key_is_pressed(0x5) && keyboard.key_is_pressed(0x8), "both keys are down");
+
+		held[0x5] = false;
+		keyboard.set_held(held);
+		assert!(!keyboard.key_is_pressed(0x5), "released");
+		assert!(keyboard.key_is_pressed(0x8), "still held");
+	}
 }
@@ -1,3 +1,17 @@
 pub mod cpu;
+pub mod display;
+pub mod error;
 pub mod keyboard;
 pub mod font;
+pub mod quirks;
+pub mod platform;
+pub mod random;
+pub mod savestate;
+pub mod rewind;
+pub mod disasm;
+pub mod asm;
+pub mod octo;
+pub mod debugger;
+pub mod headless;
+pub mod instruction;
+pub mod parse;
@@ -0,0 +1,987 @@
+use crate::asm::AsmError;
+use crate::cpu::MEMORY_START_INDEX;
+use crate::parse;
+use crate::platform::Platform;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+// A compiler for Octo, the structured assembly language most new CHIP-8 games are written in. It
+// follows the Octo manual (https://github.com/JohnEarnest/Octo/blob/gh-pages/docs/Manual.md):
+//
+//     : main                      # labels, execution starts at main
+//         v0 := 0                 # register operations, vA and :alias names
+//         loop
+//             v0 += 1
+//             if v0 == 10 then v0 := 0
+//             while v0 != 5       # leaves the loop when v0 is 5
+//         again
+//         if v1 > v2 begin        # comparisons beyond == and != use vF
+//             sprite v0 v1 5
+//         else
+//             draw-box            # calls to labels, or macros
+//         end
+//
+// plus :alias, :const, :calc (integer arithmetic, evaluated right to left like Octo), :macro,
+// :next, :org, :unpack, :byte and bare numbers for data. Instructions from a later platform are
+// rejected unless that platform is the target.
+
+// compile Octo source into a ROM loaded at 0x200
+pub fn compile(source: &str, platform: Platform) -> Result<Vec<u8>, AsmError> {
+    Compiler::new("<source>", source, platform).run()
+}
+
+pub fn compile_file<P: AsRef<Path>>(path: P, platform: Platform) -> Result<Vec<u8>, AsmError> {
+    let path = path.as_ref();
+    let name = path.display().to_string();
+    let source = fs::read_to_string(path).map_err(|e| AsmError {
+        file: name.clone(),
+        line: 0,
+        column: 0,
+        message: e.to_string(),
+    })?;
+    Compiler::new(&name, &source, platform).run()
+}
+
+// macros calling macros are fine, a macro calling itself forever is not
+const MAX_EXPANSIONS: usize = 10_000;
+
+const KEYWORDS: [&str; 45] = [
+    "clear", "return", ";", "bcd", "save", "load", "saveflags", "loadflags", "sprite", "jump", "jump0",
+    "native", "hires", "lores", "exit", "scroll-down", "scroll-up", "scroll-left", "scroll-right", "plane",
+    "audio", "delay", "buzzer", "pitch", "i", "if", "then", "begin", "else", "end", "loop", "again", "while",
+    "key", "-key", "random", "hex", "bighex", "long", ":=", "+=", "-=", "==", "!=", "-",
+];
+
+#[derive(Debug, Clone)]
+struct Token {
+    text: String,
+    line: usize,
+    column: usize,
+}
+
+// Octo tokens are separated by whitespace and # starts a comment
+fn tokenize(source: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    for (index, line) in source.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("");
+        let mut start = None;
+        for (offset, c) in line.char_indices().chain(Some((line.len(), ' '))) {
+            if !c.is_whitespace() {
+                start = start.or(Some(offset));
+            } else if let Some(begin) = start.take() {
+                tokens.push(Token {
+                    text: line[begin..offset].to_string(),
+                    line: index + 1,
+                    column: begin + 1,
+                });
+            }
+        }
+    }
+    tokens
+}
+
+// turns a skip into the one that skips in the opposite case
+fn invert(skip: u16) -> u16 {
+    match skip & 0xF000 {
+        0x3000 | 0x4000 => skip ^ 0x7000,
+        0x5000 => skip ^ 0xC000,
+        0x9000 => skip ^ 0xC000,
+        _ if skip & 0xFF == 0x9E => skip & 0xFF00 | 0xA1,
+        _ => skip & 0xFF00 | 0x9E,
+    }
+}
+
+// addresses that are filled in once a forward reference is defined
+enum Patch {
+    // the low 12 bits of the instruction
+    Address,
+    // the 16 bit word after F000
+    Long,
+    // the bytes of the two loads :unpack emits, with the high nibble of v0
+    Unpack(u8),
+}
+
+struct Fixup {
+    address: usize,
+    patch: Patch,
+    token: Token,
+}
+
+// open control structures, with the address of the jump each one still has to fill in
+enum Flow {
+    Begin(usize),
+    Else(usize),
+    Loop { start: usize, exits: Vec<usize> },
+}
+
+struct Macro {
+    args: Vec<String>,
+    body: Vec<Token>,
+}
+
+struct Compiler {
+    file: String,
+    tokens: Vec<Token>,
+    position: usize,
+    platform: Platform,
+    rom: Vec<u8>,
+    here: usize,
+    labels: HashMap<String, usize>,
+    constants: HashMap<String, i64>,
+    aliases: HashMap<String, u16>,
+    macros: HashMap<String, Macro>,
+    fixups: Vec<Fixup>,
+    flow: Vec<(Flow, Token)>,
+    next_label: Option<Token>,
+    expansions: usize,
+}
+
+impl Compiler {
+    fn new(file: &str, source: &str, platform: Platform) -> Compiler {
+        Compiler {
+            file: file.to_string(),
+            tokens: tokenize(source),
+            position: 0,
+            platform,
+            rom: Vec::new(),
+            here: MEMORY_START_INDEX,
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            fixups: Vec::new(),
+            flow: Vec::new(),
+            next_label: None,
+            expansions: 0,
+        }
+    }
+
+    fn run(mut self) -> Result<Vec<u8>, AsmError> {
+        // programs start with a jump to main, which is dropped again if main comes first
+        let main = Token {
+            text: String::from("main"),
+            line: 1,
+            column: 1,
+        };
+        self.fixup(&main, Patch::Address);
+        self.emit_op(0x1000);
+
+        while let Some(token) = self.next() {
+            self.statement(&token)?;
+            if self.here > self.platform.memory_size() {
+                return Err(self.error(&token, String::from("program does not fit in memory")));
+            }
+        }
+
+        if let Some((_, token)) = self.flow.last() {
+            return Err(self.error(token, format!("'{}' is never closed", token.text)));
+        }
+        if let Some(token) = &self.next_label {
+            return Err(self.error(token, String::from(":next is not followed by an instruction")));
+        }
+
+        for fixup in std::mem::take(&mut self.fixups) {
+            let target = match self.labels.get(&fixup.token.text) {
+                Some(target) => *target,
+                None if fixup.token.text == "main" && fixup.address == MEMORY_START_INDEX => {
+                    return Err(self.error(&fixup.token, String::from("the program has no ': main'")));
+                }
+                None => return Err(self.error(&fixup.token, format!("undefined name '{}'", fixup.token.text))),
+            };
+            let at = fixup.address - MEMORY_START_INDEX;
+            match fixup.patch {
+                Patch::Address => {
+                    self.check_address(&fixup.token, target as i64)?;
+                    self.rom[at] |= (target >> 8) as u8;
+                    self.rom[at + 1] = target as u8;
+                }
+                Patch::Long => {
+                    self.rom[at + 2] = (target >> 8) as u8;
+                    self.rom[at + 3] = target as u8;
+                }
+                Patch::Unpack(nibble) => {
+                    self.rom[at + 1] = nibble << 4 | (target >> 8) as u8 & 0xF;
+                    self.rom[at + 3] = target as u8;
+                }
+            }
+        }
+        Ok(self.rom)
+    }
+
+    fn error(&self, token: &Token, message: String) -> AsmError {
+        AsmError {
+            file: self.file.clone(),
+            line: token.line,
+            column: token.column,
+            message,
+        }
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.position).map(|token| token.text.as_str())
+    }
+
+    // the token after `after`, which needs one
+    fn expect(&mut self, after: &Token) -> Result<Token, AsmError> {
+        self.next()
+            .ok_or_else(|| self.error(after, format!("expected something after '{}'", after.text)))
+    }
+
+    fn emit_byte(&mut self, byte: u8) {
+        let at = self.here - MEMORY_START_INDEX;
+        if at >= self.rom.len() {
+            self.rom.resize(at + 1, 0);
+        }
+        self.rom[at] = byte;
+        self.here += 1;
+    }
+
+    fn emit_op(&mut self, opcode: u16) {
+        if let Some(token) = self.next_label.take() {
+            self.labels.insert(token.text, self.here + 1);
+        }
+        self.emit_byte((opcode >> 8) as u8);
+        self.emit_byte(opcode as u8);
+    }
+
+    fn fixup(&mut self, token: &Token, patch: Patch) {
+        self.fixups.push(Fixup {
+            address: self.here,
+            patch,
+            token: token.clone(),
+        });
+    }
+
+    fn require(&self, token: &Token, super_chip: bool) -> Result<(), AsmError> {
+        let (available, name) = if super_chip {
+            (self.platform.has_super_chip_opcodes(), "SUPER-CHIP")
+        } else {
+            (self.platform.has_xo_chip_opcodes(), "XO-CHIP")
+        };
+        if available {
+            Ok(())
+        } else {
+            Err(self.error(token, format!("'{}' needs the {} platform", token.text, name)))
+        }
+    }
+
+    // names can be labels, constants, aliases or macros, but only one of them
+    fn check_name(&self, token: &Token) -> Result<(), AsmError> {
+        let name = token.text.as_str();
+        if parse::register(name).is_some() || KEYWORDS.contains(&name) || name.starts_with(':') || parse::literal(name).is_some() {
+            return Err(self.error(token, format!("'{}' can not be used as a name", name)));
+        }
+        if self.labels.contains_key(name)
+            || self.constants.contains_key(name)
+            || self.aliases.contains_key(name)
+            || self.macros.contains_key(name)
+        {
+            return Err(self.error(token, format!("'{}' is already defined", name)));
+        }
+        Ok(())
+    }
+
+    fn register(&self, token: &Token) -> Option<u16> {
+        parse::register(&token.text).map(u16::from).or_else(|| self.aliases.get(&token.text).copied())
+    }
+
+    fn expect_register(&mut self, after: &Token) -> Result<u16, AsmError> {
+        let token = self.expect(after)?;
+        self.register(&token)
+            .ok_or_else(|| self.error(&token, format!("expected a register, found '{}'", token.text)))
+    }
+
+    // a number, constant or label that is already defined
+    fn value(&self, token: &Token) -> Option<i64> {
+        parse::literal(&token.text)
+            .or_else(|| self.constants.get(&token.text).copied())
+            .or_else(|| self.labels.get(&token.text).map(|address| *address as i64))
+    }
+
+    fn number(&self, token: &Token, min: i64, max: i64) -> Result<u16, AsmError> {
+        let value = self
+            .value(token)
+            .ok_or_else(|| self.error(token, format!("undefined name '{}'", token.text)))?;
+        if value < min || value > max {
+            return Err(self.error(token, format!("{} is out of range", value)));
+        }
+        Ok(value as u16)
+    }
+
+    // bytes may be written as negative numbers
+    fn byte(&self, token: &Token) -> Result<u16, AsmError> {
+        Ok(self.number(token, -128, 255)? & 0xFF)
+    }
+
+    fn check_address(&self, token: &Token, address: i64) -> Result<u16, AsmError> {
+        if !(0..=0xFFF).contains(&address) {
+            return Err(self.error(token, format!("{:#X} is out of reach of a 12 bit address", address)));
+        }
+        Ok(address as u16)
+    }
+
+    // a 12 bit address, labels that come later are filled in at the end
+    fn address(&mut self, token: &Token) -> Result<u16, AsmError> {
+        match self.value(token) {
+            Some(address) => self.check_address(token, address),
+            None => {
+                self.fixup(token, Patch::Address);
+                Ok(0)
+            }
+        }
+    }
+
+    fn define_label(&mut self, token: &Token) -> Result<(), AsmError> {
+        self.check_name(token)?;
+        if token.text == "main" && self.here == MEMORY_START_INDEX + 2 && self.labels.is_empty() {
+            // nothing comes before main, so there is no need to jump to it
+            self.rom.clear();
+            self.fixups.clear();
+            self.here = MEMORY_START_INDEX;
+        }
+        self.labels.insert(token.text.clone(), self.here);
+        Ok(())
+    }
+
+    fn jump_to(&mut self, token: &Token, target: usize) -> Result<(), AsmError> {
+        let target = self.check_address(token, target as i64)?;
+        self.emit_op(0x1000 | target);
+        Ok(())
+    }
+
+    // point the jump at address to the current address
+    fn patch_jump(&mut self, token: &Token, address: usize) -> Result<(), AsmError> {
+        let target = self.check_address(token, self.here as i64)?;
+        let at = address - MEMORY_START_INDEX;
+        self.rom[at] = 0x10 | (target >> 8) as u8;
+        self.rom[at + 1] = target as u8;
+        Ok(())
+    }
+
+    // the tokens up to the } matching an already read {
+    fn block(&mut self, open: &Token) -> Result<Vec<Token>, AsmError> {
+        let mut body = Vec::new();
+        let mut depth = 1;
+        loop {
+            let token = self
+                .next()
+                .ok_or_else(|| self.error(open, String::from("'{' is never closed")))?;
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" => depth -= 1,
+                _ => {}
+            }
+            if depth == 0 {
+                return Ok(body);
+            }
+            body.push(token);
+        }
+    }
+
+    fn statement(&mut self, token: &Token) -> Result<(), AsmError> {
+        match token.text.as_str() {
+            ":" => {
+                let name = self.expect(token)?;
+                self.define_label(&name)?;
+            }
+            ":alias" => {
+                let name = self.expect(token)?;
+                self.check_name(&name)?;
+                let x = self.expect_register(&name)?;
+                self.aliases.insert(name.text, x);
+            }
+            ":const" => {
+                let name = self.expect(token)?;
+                self.check_name(&name)?;
+                let value = self.expect(&name)?;
+                let value = self
+                    .value(&value)
+                    .ok_or_else(|| self.error(&value, format!("undefined name '{}'", value.text)))?;
+                self.constants.insert(name.text, value);
+            }
+            ":calc" => {
+                // :calc may update a constant, macros use that to count
+                let name = self.expect(token)?;
+                if !self.constants.contains_key(&name.text) {
+                    self.check_name(&name)?;
+                }
+                let value = self.calc_block(&name)?;
+                self.constants.insert(name.text, value);
+            }
+            ":byte" => {
+                let value = if self.peek() == Some("{") {
+                    self.calc_block(token)?
+                } else {
+                    let value = self.expect(token)?;
+                    self.byte(&value)? as i64
+                };
+                self.emit_byte(value as u8);
+            }
+            ":org" => {
+                let address = self.expect(token)?;
+                let address = self.number(&address, MEMORY_START_INDEX as i64, 0xFFFF)?;
+                self.here = address as usize;
+            }
+            ":next" => {
+                let name = self.expect(token)?;
+                self.check_name(&name)?;
+                self.next_label = Some(name);
+            }
+            ":unpack" => {
+                let nibble = self.expect(token)?;
+                let nibble = self.number(&nibble, 0, 15)? as u8;
+                let label = self.expect(token)?;
+                match self.value(&label) {
+                    Some(address) => {
+                        let address = self.check_address(&label, address)?;
+                        self.emit_op(0x6000 | (nibble as u16) << 4 | address >> 8);
+                        self.emit_op(0x6100 | address & 0xFF);
+                    }
+                    None => {
+                        self.fixup(&label, Patch::Unpack(nibble));
+                        self.emit_op(0x6000);
+                        self.emit_op(0x6100);
+                    }
+                }
+            }
+            ":macro" => {
+                let name = self.expect(token)?;
+                self.check_name(&name)?;
+                let mut args = Vec::new();
+                loop {
+                    let arg = self.expect(&name)?;
+                    if arg.text == "{" {
+                        let body = self.block(&arg)?;
+                        self.macros.insert(name.text, Macro { args, body });
+                        break;
+                    }
+                    args.push(arg.text);
+                }
+            }
+            ":call" => {
+                let target = self.expect(token)?;
+                let address = self.address(&target)?;
+                self.emit_op(0x2000 | address);
+            }
+            ":breakpoint" => {
+                // breakpoints are for Octo's debugger, the name is all there is to skip
+                self.expect(token)?;
+            }
+            "clear" => self.emit_op(0x00E0),
+            "return" | ";" => self.emit_op(0x00EE),
+            "hires" | "lores" | "exit" | "scroll-right" | "scroll-left" => {
+                self.require(token, true)?;
+                self.emit_op(match token.text.as_str() {
+                    "hires" => 0x00FF,
+                    "lores" => 0x00FE,
+                    "exit" => 0x00FD,
+                    "scroll-right" => 0x00FB,
+                    _ => 0x00FC,
+                });
+            }
+            "scroll-down" | "scroll-up" => {
+                let super_chip = token.text == "scroll-down";
+                self.require(token, super_chip)?;
+                let rows = self.expect(token)?;
+                let rows = self.number(&rows, 0, 15)?;
+                self.emit_op(if super_chip { 0x00C0 } else { 0x00D0 } | rows);
+            }
+            "plane" => {
+                self.require(token, false)?;
+                let planes = self.expect(token)?;
+                let planes = self.number(&planes, 0, 3)?;
+                self.emit_op(0xF001 | planes << 8);
+            }
+            "audio" => {
+                self.require(token, false)?;
+                self.emit_op(0xF002);
+            }
+            "jump" | "jump0" | "native" => {
+                let target = self.expect(token)?;
+                let address = self.address(&target)?;
+                self.emit_op(match token.text.as_str() {
+                    "jump" => 0x1000,
+                    "jump0" => 0xB000,
+                    _ => 0x0000,
+                } | address);
+            }
+            "sprite" => {
+                let x = self.expect_register(token)?;
+                let y = self.expect_register(token)?;
+                let height = self.expect(token)?;
+                let height = self.number(&height, 0, 15)?;
+                if height == 0 {
+                    self.require(token, true)?;
+                }
+                self.emit_op(0xD000 | x << 8 | y << 4 | height);
+            }
+            "bcd" => {
+                let x = self.expect_register(token)?;
+                self.emit_op(0xF033 | x << 8);
+            }
+            "save" | "load" => {
+                let x = self.expect_register(token)?;
+                if self.peek() == Some("-") {
+                    // save vx - vy, a range of registers
+                    self.require(token, false)?;
+                    let dash = self.expect(token)?;
+                    let y = self.expect_register(&dash)?;
+                    let op = if token.text == "save" { 0x5002 } else { 0x5003 };
+                    self.emit_op(op | x << 8 | y << 4);
+                } else {
+                    let op = if token.text == "save" { 0xF055 } else { 0xF065 };
+                    self.emit_op(op | x << 8);
+                }
+            }
+            "saveflags" | "loadflags" => {
+                self.require(token, true)?;
+                let x = self.expect_register(token)?;
+                if x as usize >= self.platform.rpl_flags() {
+                    return Err(self.error(token, format!("only {} flag registers", self.platform.rpl_flags())));
+                }
+                self.emit_op(if token.text == "saveflags" { 0xF075 } else { 0xF085 } | x << 8);
+            }
+            "delay" | "buzzer" | "pitch" => {
+                if token.text == "pitch" {
+                    self.require(token, false)?;
+                }
+                let assign = self.expect(token)?;
+                if assign.text != ":=" {
+                    return Err(self.error(&assign, format!("expected ':=', found '{}'", assign.text)));
+                }
+                let x = self.expect_register(&assign)?;
+                self.emit_op(match token.text.as_str() {
+                    "delay" => 0xF015,
+                    "buzzer" => 0xF018,
+                    _ => 0xF03A,
+                } | x << 8);
+            }
+            "i" => self.i_operation(token)?,
+            "if" => {
+                let (prelude, skip) = self.condition(token)?;
+                let word = self.expect(token)?;
+                for opcode in prelude {
+                    self.emit_op(opcode);
+                }
+                match word.text.as_str() {
+                    "then" => self.emit_op(skip),
+                    "begin" => {
+                        // skip the jump to the else branch when the condition holds
+                        self.emit_op(invert(skip));
+                        self.flow.push((Flow::Begin(self.here), token.clone()));
+                        self.emit_op(0x1000);
+                    }
+                    _ => return Err(self.error(&word, format!("expected 'then' or 'begin', found '{}'", word.text))),
+                }
+            }
+            "else" => match self.flow.pop() {
+                Some((Flow::Begin(jump), begin)) => {
+                    let end_jump = self.here;
+                    self.emit_op(0x1000);
+                    self.patch_jump(token, jump)?;
+                    self.flow.push((Flow::Else(end_jump), begin));
+                }
+                _ => return Err(self.error(token, String::from("'else' without 'begin'"))),
+            },
+            "end" => match self.flow.pop() {
+                Some((Flow::Begin(jump), _)) | Some((Flow::Else(jump), _)) => self.patch_jump(token, jump)?,
+                _ => return Err(self.error(token, String::from("'end' without 'begin'"))),
+            },
+            "loop" => self.flow.push((Flow::Loop { start: self.here, exits: Vec::new() }, token.clone())),
+            "while" => {
+                let (prelude, skip) = self.condition(token)?;
+                for opcode in prelude {
+                    self.emit_op(opcode);
+                }
+                self.emit_op(invert(skip));
+                let exit = self.here;
+                self.emit_op(0x1000);
+                match self.flow.iter_mut().rev().find_map(|(flow, _)| match flow {
+                    Flow::Loop { exits, .. } => Some(exits),
+                    _ => None,
+                }) {
+                    Some(exits) => exits.push(exit),
+                    None => return Err(self.error(token, String::from("'while' outside of a loop"))),
+                }
+            }
+            "again" => match self.flow.pop() {
+                Some((Flow::Loop { start, exits }, _)) => {
+                    self.jump_to(token, start)?;
+                    for exit in exits {
+                        self.patch_jump(token, exit)?;
+                    }
+                }
+                _ => return Err(self.error(token, String::from("'again' without 'loop'"))),
+            },
+            _ => {
+                if let Some(x) = self.register(token) {
+                    return self.register_operation(token, x);
+                }
+                if self.macros.contains_key(&token.text) {
+                    return self.expand(token);
+                }
+                if let Some(value) = parse::literal(&token.text).or_else(|| self.constants.get(&token.text).copied()) {
+                    // bare numbers are data
+                    if !(-128..=255).contains(&value) {
+                        return Err(self.error(token, format!("{} is out of range", value)));
+                    }
+                    self.emit_byte(value as u8);
+                    return Ok(());
+                }
+                // anything else is a call to a label, which may come later
+                if token.text.starts_with(':') || KEYWORDS.contains(&token.text.as_str()) {
+                    return Err(self.error(token, format!("unexpected '{}'", token.text)));
+                }
+                let address = self.address(token)?;
+                self.emit_op(0x2000 | address);
+            }
+        }
+        Ok(())
+    }
+
+    fn register_operation(&mut self, token: &Token, x: u16) -> Result<(), AsmError> {
+        let op = self.expect(token)?;
+        let rhs = self.expect(&op)?;
+        let y = self.register(&rhs);
+        let opcode = match (op.text.as_str(), y) {
+            (":=", _) if rhs.text == "random" => {
+                let mask = self.expect(&rhs)?;
+                0xC000 | x << 8 | self.byte(&mask)?
+            }
+            (":=", _) if rhs.text == "delay" => 0xF007 | x << 8,
+            (":=", _) if rhs.text == "key" => 0xF00A | x << 8,
+            (":=", Some(y)) => 0x8000 | x << 8 | y << 4,
+            (":=", None) => 0x6000 | x << 8 | self.byte(&rhs)?,
+            ("+=", Some(y)) => 0x8004 | x << 8 | y << 4,
+            ("+=", None) => 0x7000 | x << 8 | self.byte(&rhs)?,
+            ("-=", Some(y)) => 0x8005 | x << 8 | y << 4,
+            // there is no subtract immediate, so add the negated byte
+            ("-=", None) => 0x7000 | x << 8 | (0x100 - self.byte(&rhs)?) & 0xFF,
+            ("|=", Some(y)) => 0x8001 | x << 8 | y << 4,
+            ("&=", Some(y)) => 0x8002 | x << 8 | y << 4,
+            ("^=", Some(y)) => 0x8003 | x << 8 | y << 4,
+            (">>=", Some(y)) => 0x8006 | x << 8 | y << 4,
+            ("=-", Some(y)) => 0x8007 | x << 8 | y << 4,
+            ("<<=", Some(y)) => 0x800E | x << 8 | y << 4,
+            ("|=", None) | ("&=", None) | ("^=", None) | (">>=", None) | ("=-", None) | ("<<=", None) => {
+                return Err(self.error(&rhs, format!("expected a register, found '{}'", rhs.text)));
+            }
+            _ => return Err(self.error(&op, format!("unknown operator '{}'", op.text))),
+        };
+        self.emit_op(opcode);
+        Ok(())
+    }
+
+    fn i_operation(&mut self, token: &Token) -> Result<(), AsmError> {
+        let op = self.expect(token)?;
+        let rhs = self.expect(&op)?;
+        match (op.text.as_str(), rhs.text.as_str()) {
+            (":=", "hex") => {
+                let x = self.expect_register(&rhs)?;
+                self.emit_op(0xF029 | x << 8);
+            }
+            (":=", "bighex") => {
+                self.require(&rhs, true)?;
+                let x = self.expect_register(&rhs)?;
+                self.emit_op(0xF030 | x << 8);
+            }
+            (":=", "long") => {
+                self.require(&rhs, false)?;
+                let target = self.expect(&rhs)?;
+                let address = match self.value(&target) {
+                    Some(_) => self.number(&target, 0, 0xFFFF)?,
+                    None => {
+                        self.fixup(&target, Patch::Long);
+                        0
+                    }
+                };
+                // :next points at the address rather than the F000 before it
+                let next = self.next_label.take();
+                self.emit_op(0xF000);
+                self.next_label = next;
+                self.emit_op(address);
+            }
+            (":=", _) => {
+                let address = self.address(&rhs)?;
+                self.emit_op(0xA000 | address);
+            }
+            ("+=", _) => match self.register(&rhs) {
+                Some(x) => self.emit_op(0xF01E | x << 8),
+                None => return Err(self.error(&rhs, format!("expected a register, found '{}'", rhs.text))),
+            },
+            _ => return Err(self.error(&op, format!("unknown operator '{}'", op.text))),
+        }
+        Ok(())
+    }
+
+    // Parses the condition after if or while. Returns the instructions that set up the test and
+    // a skip that steps over the next instruction unless the condition holds. Comparisons other
+    // than == and != subtract into vF and test the borrow, like Octo does.
+    fn condition(&mut self, token: &Token) -> Result<(Vec<u16>, u16), AsmError> {
+        let x = self.expect_register(token)?;
+        let op = self.expect(token)?;
+        match op.text.as_str() {
+            "key" => return Ok((Vec::new(), 0xE0A1 | x << 8)),
+            "-key" => return Ok((Vec::new(), 0xE09E | x << 8)),
+            "==" | "!=" | "<" | ">" | "<=" | ">=" => {}
+            _ => return Err(self.error(&op, format!("unknown comparison '{}'", op.text))),
+        }
+        let rhs = self.expect(&op)?;
+        let y = self.register(&rhs);
+        let result = match (op.text.as_str(), y) {
+            ("==", Some(y)) => (Vec::new(), 0x9000 | x << 8 | y << 4),
+            ("==", None) => (Vec::new(), 0x4000 | x << 8 | self.byte(&rhs)?),
+            ("!=", Some(y)) => (Vec::new(), 0x5000 | x << 8 | y << 4),
+            ("!=", None) => (Vec::new(), 0x3000 | x << 8 | self.byte(&rhs)?),
+            (cmp, _) => {
+                let load = match y {
+                    Some(y) => 0x8F00 | y << 4,
+                    None => 0x6F00 | self.byte(&rhs)?,
+                };
+                // vF := vx - rhs borrows when vx < rhs, vF := rhs - vx when vx > rhs
+                let (subtract, skip) = match cmp {
+                    "<" => (0x8F07 | x << 4, 0x4F00),
+                    ">=" => (0x8F07 | x << 4, 0x3F00),
+                    ">" => (0x8F05 | x << 4, 0x4F00),
+                    _ => (0x8F05 | x << 4, 0x3F00),
+                };
+                (vec![load, subtract], skip)
+            }
+        };
+        Ok(result)
+    }
+
+    fn expand(&mut self, token: &Token) -> Result<(), AsmError> {
+        self.expansions += 1;
+        if self.expansions > MAX_EXPANSIONS {
+            return Err(self.error(token, format!("macro '{}' expands forever", token.text)));
+        }
+        let arg_count = self.macros[&token.text].args.len();
+        let mut values = Vec::new();
+        for _ in 0..arg_count {
+            values.push(self.expect(token)?);
+        }
+        let definition = &self.macros[&token.text];
+        let body: Vec<Token> = definition
+            .body
+            .iter()
+            .map(|body_token| match definition.args.iter().position(|arg| *arg == body_token.text) {
+                Some(index) => values[index].clone(),
+                None => body_token.clone(),
+            })
+            .collect();
+        self.tokens.splice(self.position..self.position, body);
+        Ok(())
+    }
+
+    fn calc_block(&mut self, after: &Token) -> Result<i64, AsmError> {
+        let open = self.expect(after)?;
+        if open.text != "{" {
+            return Err(self.error(&open, format!("expected '{{', found '{}'", open.text)));
+        }
+        let body = self.block(&open)?;
+        let mut position = 0;
+        let value = self.calc(&body, &mut position, &open)?;
+        match body.get(position) {
+            Some(extra) => Err(self.error(extra, format!("unexpected '{}'", extra.text))),
+            None => Ok(value),
+        }
+    }
+
+    // Octo expressions have no precedence, operators apply right to left: 2 * 3 + 1 is 8
+    fn calc(&self, tokens: &[Token], position: &mut usize, open: &Token) -> Result<i64, AsmError> {
+        let left = self.calc_term(tokens, position, open)?;
+        let op = match tokens.get(*position) {
+            Some(op) if op.text != ")" => op,
+            _ => return Ok(left),
+        };
+        *position += 1;
+        let right = self.calc(tokens, position, open)?;
+        let value = match op.text.as_str() {
+            "+" => left.wrapping_add(right),
+            "-" => left.wrapping_sub(right),
+            "*" => left.wrapping_mul(right),
+            "/" | "%" if right == 0 => return Err(self.error(op, String::from("division by zero"))),
+            "/" => left / right,
+            "%" => left % right,
+            "&" => left & right,
+            "|" => left | right,
+            "^" => left ^ right,
+            "<<" => left << right.clamp(0, 63),
+            ">>" => left >> right.clamp(0, 63),
+            "min" => left.min(right),
+            "max" => left.max(right),
+            "<" => (left < right) as i64,
+            ">" => (left > right) as i64,
+            "<=" => (left <= right) as i64,
+            ">=" => (left >= right) as i64,
+            "==" => (left == right) as i64,
+            "!=" => (left != right) as i64,
+            _ => return Err(self.error(op, format!("unknown operator '{}'", op.text))),
+        };
+        Ok(value)
+    }
+
+    fn calc_term(&self, tokens: &[Token], position: &mut usize, open: &Token) -> Result<i64, AsmError> {
+        let token = tokens
+            .get(*position)
+            .ok_or_else(|| self.error(open, String::from("expression ends early")))?;
+        *position += 1;
+        match token.text.as_str() {
+            "(" => {
+                let value = self.calc(tokens, position, open)?;
+                match tokens.get(*position) {
+                    Some(close) if close.text == ")" => {
+                        *position += 1;
+                        Ok(value)
+                    }
+                    _ => Err(self.error(token, String::from("'(' is never closed"))),
+                }
+            }
+            "-" => Ok(-self.calc_term(tokens, position, open)?),
+            "~" => Ok(!self.calc_term(tokens, position, open)?),
+            "!" => Ok((self.calc_term(tokens, position, open)? == 0) as i64),
+            "HERE" => Ok(self.here as i64),
+            _ => self
+                .value(token)
+                .ok_or_else(|| self.error(token, format!("undefined name '{}'", token.text))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Cpu;
+
+    fn words(rom: &[u8]) -> Vec<u16> {
+        rom.chunks(2).map(|pair| (pair[0] as u16) << 8 | pair[1] as u16).collect()
+    }
+
+    #[test]
+    fn main_first_needs_no_jump() {
+        let rom = compile(": main\n  clear\n  v3 := 0x1F  # comment\n  sprite v0 v1 5\n  i := hex va", Platform::Chip8).unwrap();
+        assert_eq!(words(&rom), vec![0x00E0, 0x631F, 0xD015, 0xFA29]);
+    }
+
+    #[test]
+    fn jumps_to_main_and_resolves_forward_references() {
+        let source = "
+            : data 0xF0 0x90 -1
+            : main
+                i := ball
+                draw
+                jump main
+            : draw ;
+            : ball 0xFF
+        ";
+        let rom = compile(source, Platform::Chip8).unwrap();
+        assert_eq!(&rom[..5], &[0x12, 0x05, 0xF0, 0x90, 0xFF]);
+        assert_eq!(words(&rom[5..13]), vec![0xA20D, 0x220B, 0x1205, 0x00EE]);
+        assert_eq!(&rom[13..], &[0xFF]);
+    }
+
+    #[test]
+    fn control_flow() {
+        let source = "
+            : main
+                loop
+                    v0 += 1
+                    if v0 == 10 then v0 := 0
+                    while v0 != 5
+                    if v1 key begin v2 := 1 else v2 := 2 end
+                again
+        ";
+        let rom = compile(source, Platform::Chip8).unwrap();
+        assert_eq!(
+            words(&rom),
+            vec![
+                0x7001, // 200 v0 += 1
+                0x400A, // 202 skip unless v0 == 10
+                0x6000, // 204 v0 := 0
+                0x4005, // 206 skip the exit while v0 != 5
+                0x1216, // 208 leave the loop
+                0xE19E, // 20A skip the jump to else when v1 is pressed
+                0x1212, // 20C to else
+                0x6201, // 20E v2 := 1
+                0x1214, // 210 to end
+                0x6202, // 212 v2 := 2
+                0x1200, // 214 again
+            ]
+        );
+    }
+
+    #[test]
+    fn comparisons_run_correctly() {
+        // v2 counts the comparisons of 3 against 5 that hold, the program exits if that is 3
+        let source = "
+            : main
+                v0 := 3 v1 := 5 v2 := 0
+                if v0 < v1 then v2 += 1
+                if v0 > v1 then v2 += 0x10
+                if v0 <= 5 then v2 += 1
+                if v0 >= 5 then v2 += 0x10
+                if v1 >= v0 then v2 += 1
+                if v2 == 3 then exit
+                loop again
+        ";
+        let rom = compile(source, Platform::SuperChip).unwrap();
+        let mut chip = Cpu::with_platform(Platform::SuperChip);
+        chip.load_rom_bytes(&rom).unwrap();
+        chip.run_frame(100).unwrap();
+        assert!(chip.has_exited(), "3 < 5, 3 <= 5 and 5 >= 3 held, the others did not");
+    }
+
+    #[test]
+    fn macros_constants_and_calc() {
+        let source = "
+            :const SPEED 2
+            :alias player v5
+            :calc DOUBLE { SPEED * 2 + 1 }
+            :macro bump reg amount { reg += amount }
+            : main
+                bump player SPEED
+                player := DOUBLE
+                :next target v0 := 0
+                :unpack 0xA main
+                :byte { target - 0x200 }
+                :org 0x20C
+                v1 := -1
+        ";
+        let rom = compile(source, Platform::Chip8).unwrap();
+        assert_eq!(
+            words(&rom),
+            vec![0x7502, 0x6506, 0x6000, 0x60A2, 0x6100, 0x0500, 0x61FF]
+        );
+    }
+
+    #[test]
+    fn platform_instructions() {
+        assert!(compile(": main hires", Platform::SuperChip).is_ok());
+        let error = compile(": main\n  hires", Platform::Chip8).unwrap_err();
+        assert_eq!((error.line, error.column), (2, 3));
+        assert_eq!(error.message, "'hires' needs the SUPER-CHIP platform");
+
+        let rom = compile(": main save v1 - v3 plane 3 i := long target : target", Platform::XoChip).unwrap();
+        assert_eq!(words(&rom), vec![0x5132, 0xF301, 0xF000, 0x0208]);
+        assert!(compile(": main plane 3", Platform::SuperChip).is_err());
+    }
+
+    #[test]
+    fn errors() {
+        let error = compile(": main\n  jump nowhere", Platform::Chip8).unwrap_err();
+        assert_eq!((error.line, error.column, error.message.as_str()), (2, 8, "undefined name 'nowhere'"));
+        assert_eq!(compile(": start clear", Platform::Chip8).unwrap_err().message, "the program has no ': main'");
+        assert_eq!(compile(": main loop", Platform::Chip8).unwrap_err().message, "'loop' is never closed");
+        assert_eq!(compile(": main end", Platform::Chip8).unwrap_err().message, "'end' without 'begin'");
+        assert!(compile(": main v0 := 256", Platform::Chip8).is_err());
+        assert!(compile(":macro m { m } : main m", Platform::Chip8).is_err());
+    }
+}
@@ -0,0 +1,80 @@
+// The number and register syntax the assembler, the Octo compiler, the debugger and the command
+// lines share
+
+use std::convert::TryFrom;
+
+// hex with a 0x prefix, or decimal, that fits in T
+pub fn number<T: TryFrom<u64>>(text: &str) -> Option<T> {
+    let value = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+        None => text.parse().ok()?,
+    };
+    T::try_from(value).ok()
+}
+
+// decimal, 0x hexadecimal or 0b binary, optionally negative
+pub fn literal(text: &str) -> Option<i64> {
+    let (sign, digits) = match text.strip_prefix('-') {
+        Some(rest) => (-1, rest),
+        None => (1, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = digits.strip_prefix("0b").or_else(|| digits.strip_prefix("0B")) {
+        i64::from_str_radix(binary, 2).ok()
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse().ok()
+    } else {
+        None
+    };
+    value.map(|value| sign * value)
+}
+
+// v0 to vF, in either case
+pub fn register(text: &str) -> Option<u8> {
+    let mut chars = text.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some('v'), Some(digit), None) | (Some('V'), Some(digit), None) => digit.to_digit(16).map(|d| d as u8),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers() {
+        assert_eq!(number::<u16>("0x600"), Some(0x600));
+        assert_eq!(number::<u16>("0X600"), Some(0x600));
+        assert_eq!(number::<u16>("1536"), Some(0x600));
+        assert_eq!(number::<u16>("0x10000"), None, "too wide");
+        assert_eq!(number::<u8>("255"), Some(255));
+        assert_eq!(number::<u8>("256"), None);
+        assert_eq!(number::<u64>("18446744073709551615"), Some(u64::MAX));
+        assert_eq!(number::<u16>("0b10"), None);
+        assert_eq!(number::<u16>("-1"), None);
+    }
+
+    #[test]
+    fn literals() {
+        assert_eq!(literal("42"), Some(42));
+        assert_eq!(literal("0x2A"), Some(42));
+        assert_eq!(literal("0X2a"), Some(42));
+        assert_eq!(literal("0b101010"), Some(42));
+        assert_eq!(literal("-0x10"), Some(-16));
+        assert_eq!(literal("+5"), None);
+        assert_eq!(literal("x2A"), None);
+        assert_eq!(literal("0x"), None);
+    }
+
+    #[test]
+    fn registers() {
+        assert_eq!(register("v0"), Some(0));
+        assert_eq!(register("VF"), Some(15));
+        assert_eq!(register("va"), Some(10));
+        assert_eq!(register("vg"), None);
+        assert_eq!(register("v10"), None);
+        assert_eq!(register("i"), None);
+    }
+}
@@ -0,0 +1,85 @@
+use crate::quirks::Quirks;
+use std::str::FromStr;
+
+// The machine a ROM was written for. Each platform is a superset of the one before it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Platform {
+    // the original 64x32 CHIP-8 with its 35 instructions
+    #[default]
+    Chip8,
+
+    // SUPER-CHIP 1.1: 128x64 hi-res mode, scrolling, 16x16 sprites, big font and RPL flags
+    SuperChip,
+
+    // XO-CHIP: 64 KiB of memory, two drawing planes, an audio pattern buffer and pitch register
+    XoChip,
+}
+
+impl Platform {
+    // the quirks the interpreters for this platform were known for
+    pub fn quirks(self) -> Quirks {
+        match self {
+            Platform::Chip8 => Quirks::cosmac_vip(),
+            Platform::SuperChip => Quirks::super_chip(),
+            Platform::XoChip => Quirks::modern(),
+        }
+    }
+
+    // a stable number for the platform, used by save states
+    pub fn id(self) -> u8 {
+        match self {
+            Platform::Chip8 => 0,
+            Platform::SuperChip => 1,
+            Platform::XoChip => 2,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Platform> {
+        match id {
+            0 => Some(Platform::Chip8),
+            1 => Some(Platform::SuperChip),
+            2 => Some(Platform::XoChip),
+            _ => None,
+        }
+    }
+
+    pub fn has_super_chip_opcodes(self) -> bool {
+        match self {
+            Platform::Chip8 => false,
+            Platform::SuperChip | Platform::XoChip => true,
+        }
+    }
+
+    pub fn has_xo_chip_opcodes(self) -> bool {
+        self == Platform::XoChip
+    }
+
+    // bytes of addressable memory
+    pub fn memory_size(self) -> usize {
+        match self {
+            Platform::Chip8 | Platform::SuperChip => 0x1000,
+            Platform::XoChip => 0x10000,
+        }
+    }
+
+    // number of RPL flag registers FX75/FX85 can reach
+    pub fn rpl_flags(self) -> usize {
+        match self {
+            Platform::Chip8 | Platform::SuperChip => 8,
+            Platform::XoChip => 16,
+        }
+    }
+}
+
+impl FromStr for Platform {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Platform, String> {
+        match name.to_ascii_lowercase().as_str() {
+            "chip8" | "chip-8" => Ok(Platform::Chip8),
+            "schip" | "superchip" | "super-chip" => Ok(Platform::SuperChip),
+            "xochip" | "xo-chip" => Ok(Platform::XoChip),
+            _ => Err(format!("unknown platform '{}', expected chip8, schip or xochip", name)),
+        }
+    }
+}
@@ -0,0 +1,152 @@
+// The original CHIP-8 interpreter for the COSMAC VIP and the interpreters that followed it
+// (CHIP-48, SUPER-CHIP, Octo) disagree on how a handful of instructions behave. ROMs are
+// written against one of those interpreters, so the CPU needs to be told which one to act like.
+
+// the default (every quirk off) is how this emulator behaved before it had quirks, and what
+// Cpu::new uses so existing ROMs keep running the same way. Cpu::with_platform uses the quirks the
+// platform's interpreters had instead, so even for CHIP-8 the two start out different.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Quirks {
+    // 8XY6 / 8XYE: copy Vy into Vx before shifting, instead of shifting Vx in place
+    pub shift_uses_vy: bool,
+
+    // FX55 / FX65: leave I pointing one past the last register saved or loaded
+    pub load_store_increments_i: bool,
+
+    // BXNN: jump to XNN + Vx, instead of NNN + V0
+    pub jump_uses_vx: bool,
+
+    // 8XY1 / 8XY2 / 8XY3: reset VF to 0 after the logic operation
+    pub vf_reset: bool,
+
+    // DXYN: clip sprites at the edge of the screen, instead of wrapping them around
+    pub clip_sprites: bool,
+
+    // DXYN: wait for the next display refresh (timer tick) before running more instructions
+    pub display_wait: bool,
+
+    // FX0A: only finish once the pressed key is let go, instead of as soon as it goes down
+    pub key_release: bool,
+
+    // 00FE / 00FF: leave the display as it was when switching resolution, instead of clearing it
+    pub resolution_keeps_display: bool,
+
+    // 00CN / 00FB / 00FC: in lo-res mode scroll by hi-res pixels, half as far, like SUPER-CHIP 1.1
+    // scrolling its 128x64 screen. An odd number of pixels rounds down.
+    pub lores_scroll_halved: bool,
+}
+
+// the quirks by name, as settings files and the command line spell them
+pub const QUIRK_NAMES: [&str; 9] = [
+    "shift_uses_vy",
+    "load_store_increments_i",
+    "jump_uses_vx",
+    "vf_reset",
+    "clip_sprites",
+    "display_wait",
+    "key_release",
+    "resolution_keeps_display",
+    "lores_scroll_halved",
+];
+
+impl Quirks {
+    // turn a quirk on or off by its name
+    pub fn set(&mut self, name: &str, on: bool) -> Result<(), String> {
+        let quirk = match name {
+            "shift_uses_vy" => &mut self.shift_uses_vy,
+            "load_store_increments_i" => &mut self.load_store_increments_i,
+            "jump_uses_vx" => &mut self.jump_uses_vx,
+            "vf_reset" => &mut self.vf_reset,
+            "clip_sprites" => &mut self.clip_sprites,
+            "display_wait" => &mut self.display_wait,
+            "key_release" => &mut self.key_release,
+            "resolution_keeps_display" => &mut self.resolution_keeps_display,
+            "lores_scroll_halved" => &mut self.lores_scroll_halved,
+            _ => return Err(format!("unknown quirk {}, the quirks are {}", name, QUIRK_NAMES.join(", "))),
+        };
+        *quirk = on;
+        Ok(())
+    }
+
+    // pack the quirks into a u16, one bit each in declaration order
+    pub fn to_bits(self) -> u16 {
+        self.shift_uses_vy as u16
+            | (self.load_store_increments_i as u16) << 1
+            | (self.jump_uses_vx as u16) << 2
+            | (self.vf_reset as u16) << 3
+            | (self.clip_sprites as u16) << 4
+            | (self.display_wait as u16) << 5
+            | (self.key_release as u16) << 6
+            | (self.resolution_keeps_display as u16) << 7
+            | (self.lores_scroll_halved as u16) << 8
+    }
+
+    pub fn from_bits(bits: u16) -> Quirks {
+        Quirks {
+            shift_uses_vy: bits & 1 != 0,
+            load_store_increments_i: bits & 1 << 1 != 0,
+            jump_uses_vx: bits & 1 << 2 != 0,
+            vf_reset: bits & 1 << 3 != 0,
+            clip_sprites: bits & 1 << 4 != 0,
+            display_wait: bits & 1 << 5 != 0,
+            key_release: bits & 1 << 6 != 0,
+            resolution_keeps_display: bits & 1 << 7 != 0,
+            lores_scroll_halved: bits & 1 << 8 != 0,
+        }
+    }
+
+    // the original interpreter on the RCA COSMAC VIP
+    pub fn cosmac_vip() -> Quirks {
+        Quirks {
+            shift_uses_vy: true,
+            load_store_increments_i: true,
+            jump_uses_vx: false,
+            vf_reset: true,
+            clip_sprites: true,
+            display_wait: true,
+            key_release: true,
+            resolution_keeps_display: false,
+            lores_scroll_halved: false,
+        }
+    }
+
+    // CHIP-48 on the HP-48 calculators
+    pub fn chip48() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            load_store_increments_i: false,
+            jump_uses_vx: true,
+            vf_reset: false,
+            clip_sprites: true,
+            display_wait: false,
+            key_release: false,
+            resolution_keeps_display: false,
+            lores_scroll_halved: false,
+        }
+    }
+
+    // SUPER-CHIP 1.1, which kept the CHIP-48 behaviour. Its screen was always 128x64, so switching
+    // resolution left the picture alone and lo-res scrolls moved by hi-res pixels.
+    pub fn super_chip() -> Quirks {
+        Quirks {
+            resolution_keeps_display: true,
+            lores_scroll_halved: true,
+            ..Quirks::chip48()
+        }
+    }
+
+    // Octo and the XO-CHIP extension
+    pub fn modern() -> Quirks {
+        Quirks {
+            shift_uses_vy: true,
+            load_store_increments_i: true,
+            jump_uses_vx: false,
+            vf_reset: false,
+            clip_sprites: false,
+            display_wait: false,
+            key_release: false,
+            resolution_keeps_display: false,
+            lores_scroll_halved: false,
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use crate::error::CpuError;
+
+// Where CXKK gets its random numbers from. Swapping the source, or seeding it, makes a run
+// reproducible: the same ROM, input and seed always produce the same display.
+pub trait RandomSource {
+    // the next random byte, memory is the machine's memory for generators that read from it
+    fn next_byte(&mut self, memory: &[u8]) -> u8;
+
+    // called on every 60Hz timer tick, for generators that advance with time
+    fn tick(&mut self) {}
+
+    // which generator this is, saved along with its state so a state is only restored into the
+    // same kind of generator. 0 is for generators with no state to save.
+    fn id(&self) -> u8 {
+        0
+    }
+
+    // the generator's internal state, so save states replay the same numbers
+    fn save(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn restore(&mut self, state: &[u8]) -> Result<(), CpuError> {
+        if !state.is_empty() {
+            return Err(invalid_state());
+        }
+        Ok(())
+    }
+}
+
+fn invalid_state() -> CpuError {
+    CpuError::InvalidSaveState(String::from("random state is the wrong size"))
+}
+
+pub const XOR_SHIFT_ID: u8 = 1;
+pub const VIP_ID: u8 = 2;
+
+// the seed used when none is given
+pub const DEFAULT_SEED: u64 = 0x2545_F491_4F6C_DD1D;
+
+// xorshift64*, small and fast with a 64 bit state
+pub struct XorShiftRandom {
+    state: u64,
+}
+
+impl XorShiftRandom {
+    pub fn new(seed: u64) -> XorShiftRandom {
+        // an all zero state would only ever produce zeros
+        let state = if seed == 0 { DEFAULT_SEED } else { seed };
+        XorShiftRandom { state }
+    }
+}
+
+impl Default for XorShiftRandom {
+    fn default() -> XorShiftRandom {
+        XorShiftRandom::new(DEFAULT_SEED)
+    }
+}
+
+impl RandomSource for XorShiftRandom {
+    fn next_byte(&mut self, _memory: &[u8]) -> u8 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        // the high bits of the multiplied state are the most random
+        (self.state.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 56) as u8
+    }
+
+    fn id(&self) -> u8 {
+        XOR_SHIFT_ID
+    }
+
+    fn save(&self) -> Vec<u8> {
+        self.state.to_le_bytes().to_vec()
+    }
+
+    fn restore(&mut self, state: &[u8]) -> Result<(), CpuError> {
+        let mut bytes = [0; 8];
+        if state.len() != bytes.len() {
+            return Err(invalid_state());
+        }
+        bytes.copy_from_slice(state);
+        self.state = u64::from_le_bytes(bytes);
+        Ok(())
+    }
+}
+
+// The generator from the original COSMAC VIP interpreter. It kept a 16 bit seed, advanced the low
+// byte on every display interrupt, and for each random number added the byte of the interpreter's
+// own code at 0x100 + low byte to the high byte. The interpreter is not part of this emulator, so
+// the numbers only match the VIP bit for bit when its image is loaded into 0x000 - 0x1FF.
+pub struct VipRandom {
+    low: u8,
+    high: u8,
+}
+
+// the interpreter page the VIP read its random bytes from
+const VIP_RANDOM_PAGE: usize = 0x100;
+
+impl VipRandom {
+    pub fn new(seed: u16) -> VipRandom {
+        VipRandom {
+            low: seed as u8,
+            high: (seed >> 8) as u8,
+        }
+    }
+}
+
+impl Default for VipRandom {
+    fn default() -> VipRandom {
+        VipRandom::new(0)
+    }
+}
+
+impl RandomSource for VipRandom {
+    fn next_byte(&mut self, memory: &[u8]) -> u8 {
+        let page_byte = memory.get(VIP_RANDOM_PAGE + self.low as usize).copied().unwrap_or(0);
+        self.high = self.high.wrapping_add(page_byte);
+        self.high
+    }
+
+    fn tick(&mut self) {
+        self.low = self.low.wrapping_add(1);
+    }
+
+    fn id(&self) -> u8 {
+        VIP_ID
+    }
+
+    fn save(&self) -> Vec<u8> {
+        vec![self.low, self.high]
+    }
+
+    fn restore(&mut self, state: &[u8]) -> Result<(), CpuError> {
+        match *state {
+            [low, high] => {
+                self.low = low;
+                self.high = high;
+                Ok(())
+            }
+            _ => Err(invalid_state()),
+        }
+    }
+}
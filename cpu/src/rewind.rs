@@ -0,0 +1,232 @@
+use crate::cpu::Cpu;
+use crate::error::CpuError;
+use std::collections::VecDeque;
+
+// Keeps the last few seconds of snapshots so play can be stepped backwards. Only the newest
+// snapshot is stored in full, every older one is kept as the difference to the snapshot after
+// it. Most of memory and the display stay the same from frame to frame, so the differences are
+// mostly zeros and run length encode down to a few bytes.
+pub struct RewindBuffer {
+    // bytes the snapshots may use before the oldest ones are dropped
+    budget: usize,
+
+    // the newest snapshot
+    current: Vec<u8>,
+
+    // each delta turns a snapshot into the one taken before it, newest at the back
+    deltas: VecDeque<Vec<u8>>,
+
+    // bytes used by current and deltas
+    used: usize,
+
+    // reused between frames so taking a snapshot does not allocate
+    scratch: Vec<u8>,
+}
+
+impl RewindBuffer {
+    pub fn new(budget: usize) -> RewindBuffer {
+        RewindBuffer {
+            budget,
+            current: Vec::new(),
+            deltas: VecDeque::new(),
+            used: 0,
+            scratch: Vec::new(),
+        }
+    }
+
+    // record the machine as it is now, call once per frame
+    pub fn push(&mut self, cpu: &Cpu) {
+        cpu.snapshot(&mut self.scratch);
+        if !self.current.is_empty() {
+            let delta = encode_delta(&self.scratch, &self.current);
+            self.used += delta.len();
+            self.deltas.push_back(delta);
+        }
+        std::mem::swap(&mut self.current, &mut self.scratch);
+        self.used = self.used + self.current.len() - self.scratch.len();
+
+        while self.used > self.budget {
+            match self.deltas.pop_front() {
+                Some(oldest) => self.used -= oldest.len(),
+                None => break,
+            }
+        }
+    }
+
+    // step the machine back to the snapshot before the newest one
+    // returns false once there is nothing older left
+    pub fn rewind(&mut self, cpu: &mut Cpu) -> Result<bool, CpuError> {
+        let delta = match self.deltas.pop_back() {
+            Some(delta) => delta,
+            None => return Ok(false),
+        };
+        self.used -= delta.len() + self.current.len();
+        self.current = apply_delta(&self.current, &delta);
+        self.used += self.current.len();
+        cpu.load_state(&self.current)?;
+        Ok(true)
+    }
+
+    // how many steps back are available
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    pub fn memory_used(&self) -> usize {
+        self.used
+    }
+
+    pub fn clear(&mut self) {
+        self.current.clear();
+        self.deltas.clear();
+        self.used = 0;
+    }
+}
+
+// Deltas are the XOR of two snapshots, stored as pairs of (zero run, literal run) lengths, each
+// followed by the literal bytes. A delta starting with FULL holds the older snapshot as is, for
+// when the snapshots are different sizes, e.g. after switching platform.
+const XOR: u8 = 0;
+const FULL: u8 = 1;
+
+fn encode_delta(newer: &[u8], older: &[u8]) -> Vec<u8> {
+    if newer.len() != older.len() {
+        let mut delta = vec![FULL];
+        delta.extend_from_slice(older);
+        return delta;
+    }
+
+    let mut delta = vec![XOR];
+    let mut position = 0;
+    while position < newer.len() {
+        let zeros = newer[position..]
+            .iter()
+            .zip(&older[position..])
+            .take_while(|(a, b)| a == b)
+            .count();
+        position += zeros;
+        let literals = newer[position..]
+            .iter()
+            .zip(&older[position..])
+            .take_while(|(a, b)| a != b)
+            .count();
+        write_length(&mut delta, zeros);
+        write_length(&mut delta, literals);
+        for offset in position..position + literals {
+            delta.push(newer[offset] ^ older[offset]);
+        }
+        position += literals;
+    }
+    delta
+}
+
+fn apply_delta(newer: &[u8], delta: &[u8]) -> Vec<u8> {
+    if delta.first() == Some(&FULL) {
+        return delta[1..].to_vec();
+    }
+
+    let mut older = newer.to_vec();
+    let mut position = 0;
+    let mut cursor = 1;
+    while cursor < delta.len() {
+        position += read_length(delta, &mut cursor);
+        let literals = read_length(delta, &mut cursor);
+        for byte in &delta[cursor..cursor + literals] {
+            older[position] ^= byte;
+            position += 1;
+        }
+        cursor += literals;
+    }
+    older
+}
+
+// lengths are LEB128 varints, runs are usually short
+fn write_length(out: &mut Vec<u8>, mut length: usize) {
+    loop {
+        let byte = (length & 0x7F) as u8;
+        length >>= 7;
+        if length == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn read_length(bytes: &[u8], cursor: &mut usize) -> usize {
+    let mut length = 0;
+    let mut shift = 0;
+    loop {
+        let byte = bytes[*cursor];
+        *cursor += 1;
+        length |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return length;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delta_round_trip() {
+        let older: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+        let mut newer = older.clone();
+        newer[3] ^= 0xFF;
+        newer[500] = 7;
+        newer[999] = 0;
+
+        let delta = encode_delta(&newer, &older);
+        assert!(delta.len() < 20, "unchanged bytes compress away");
+        assert_eq!(apply_delta(&newer, &delta), older, "delta restores the older snapshot");
+
+        let shorter = &older[..10];
+        assert_eq!(apply_delta(&newer, &encode_delta(&newer, shorter)), shorter);
+    }
+
+    #[test]
+    fn rewind_steps_back_frame_by_frame() {
+        let mut chip = Cpu::new();
+        // ADD V0, 1; JP 0x200
+        chip.load_rom_bytes(&[0x70, 0x01, 0x12, 0x00]).unwrap();
+        let mut rewind = RewindBuffer::new(1 << 20);
+
+        let mut history = Vec::new();
+        for _ in 0..10 {
+            rewind.push(&chip);
+            history.push(chip.save_state());
+            chip.run_frame(2).unwrap();
+        }
+        rewind.push(&chip);
+        assert_eq!(rewind.len(), 10);
+
+        while let Some(expected) = history.pop() {
+            assert!(rewind.rewind(&mut chip).unwrap(), "a step back was available");
+            assert_eq!(chip.save_state(), expected, "restored the previous frame");
+        }
+        assert!(!rewind.rewind(&mut chip).unwrap(), "nothing older than the first frame");
+    }
+
+    #[test]
+    fn rewind_respects_budget() {
+        let mut chip = Cpu::new();
+        chip.load_rom_bytes(&[0x70, 0x01, 0x12, 0x00]).unwrap();
+        let snapshot_size = chip.save_state().len();
+        let mut rewind = RewindBuffer::new(snapshot_size + 200);
+
+        for _ in 0..1000 {
+            rewind.push(&chip);
+            chip.run_frame(2).unwrap();
+        }
+        assert!(rewind.memory_used() <= snapshot_size + 200, "stayed within the budget");
+        assert!(rewind.len() > 10, "small deltas let many frames fit");
+        assert!(rewind.len() < 999, "the oldest frames were dropped");
+    }
+}
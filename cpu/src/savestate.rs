@@ -0,0 +1,177 @@
+use crate::error::CpuError;
+use crate::random;
+
+// from FX0A's wait to the random state in version 3: the wait, the keys before and now, waiting for
+// the display, exited, the RPL flags, the audio pattern, pitch, the load address and entry point
+const RANDOM_AFTER_KEY_WAIT: usize = 3 + 32 + 2 + 16 + 16 + 1 + 2 + 2;
+
+// Save states are a magic header, a little endian u16 format version, then the machine state.
+// When the format changes the version goes up and a migration is added below, so states saved
+// by older builds keep loading.
+pub const MAGIC: &[u8; 8] = b"RCHIPSAV";
+pub const VERSION: u16 = 4;
+
+// split a save state into its version and body, upgrading older bodies to the current version
+pub fn open(state: &[u8]) -> Result<Vec<u8>, CpuError> {
+    if state.len() < MAGIC.len() + 2 || &state[..MAGIC.len()] != MAGIC {
+        return Err(CpuError::InvalidSaveState(String::from("not a save state")));
+    }
+    let version = u16::from_le_bytes([state[MAGIC.len()], state[MAGIC.len() + 1]]);
+    if version == 0 || version > VERSION {
+        return Err(CpuError::InvalidSaveState(format!(
+            "save state version {} is not supported, the newest is {}",
+            version, VERSION
+        )));
+    }
+
+    migrate(version, state[MAGIC.len() + 2..].to_vec())
+}
+
+// upgrade a body saved by an older version, one version at a time
+fn migrate(version: u16, body: Vec<u8>) -> Result<Vec<u8>, CpuError> {
+    match version {
+        VERSION => Ok(body),
+        // version 2 added the key FX0A is waiting to be let go, after the register it goes in
+        1 => {
+            let mut body = body;
+            let offset = key_wait_offset(&body, 1)?;
+            body.insert(offset + 2, 0);
+            migrate(2, body)
+        }
+        // version 3 made the quirks a u16, after the platform, for the quirks past the eighth
+        2 => {
+            let mut body = body;
+            if body.len() < 2 {
+                return Err(CpuError::InvalidSaveState(String::from("save state is truncated")));
+            }
+            body.insert(2, 0);
+            migrate(3, body)
+        }
+        // version 4 added which random generator saved its state, before the state. The sizes of
+        // their states are the only way to tell them apart in older versions.
+        3 => {
+            let mut body = body;
+            let offset = key_wait_offset(&body, 2)? + RANDOM_AFTER_KEY_WAIT;
+            let mut state = StateReader::new(&body);
+            state.bytes(offset)?;
+            let id = match state.block()?.len() {
+                8 => random::XOR_SHIFT_ID,
+                2 => random::VIP_ID,
+                _ => 0,
+            };
+            body.insert(offset, id);
+            migrate(4, body)
+        }
+        _ => Err(CpuError::InvalidSaveState(format!("no migration from version {}", version))),
+    }
+}
+
+// where FX0A's wait starts in a body, after the registers, memory and display. The quirks took
+// a byte before version 3 and two since.
+fn key_wait_offset(body: &[u8], quirk_bytes: usize) -> Result<usize, CpuError> {
+    let mut state = StateReader::new(body);
+    // platform, quirks, I, pc, V0-VF, the stack, sp and the timers
+    state.bytes(1 + quirk_bytes + 2 + 2 + 16 + 32 + 3)?;
+    state.block()?;
+    // hi-res and the selected planes
+    state.bytes(2)?;
+    state.block()?;
+    Ok(state.position)
+}
+
+// builds a save state, fields are written in the order the reader expects them
+pub struct StateWriter {
+    bytes: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter::reusing(Vec::new())
+    }
+
+    // write into an existing buffer, keeping its allocation
+    pub fn reusing(mut bytes: Vec<u8>) -> StateWriter {
+        bytes.clear();
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        StateWriter { bytes }
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.bytes.push(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, values: &[u8]) {
+        self.bytes.extend_from_slice(values);
+    }
+
+    // a length prefixed run of bytes, for fields whose size can change
+    pub fn block(&mut self, values: &[u8]) {
+        self.bytes.extend_from_slice(&(values.len() as u32).to_le_bytes());
+        self.bytes.extend_from_slice(values);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+impl Default for StateWriter {
+    fn default() -> StateWriter {
+        StateWriter::new()
+    }
+}
+
+// reads the fields of a save state body back in order
+pub struct StateReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> StateReader<'a> {
+        StateReader { bytes, position: 0 }
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], CpuError> {
+        if self.position + len > self.bytes.len() {
+            return Err(CpuError::InvalidSaveState(String::from("save state is truncated")));
+        }
+        let values = &self.bytes[self.position..self.position + len];
+        self.position += len;
+        Ok(values)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, CpuError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, CpuError> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, CpuError> {
+        let value = self.bytes(2)?;
+        Ok(u16::from_le_bytes([value[0], value[1]]))
+    }
+
+    pub fn block(&mut self) -> Result<&'a [u8], CpuError> {
+        let len = self.bytes(4)?;
+        let len = u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize;
+        self.bytes(len)
+    }
+
+    // copy exactly target.len() bytes into target
+    pub fn fill(&mut self, target: &mut [u8]) -> Result<(), CpuError> {
+        target.copy_from_slice(self.bytes(target.len())?);
+        Ok(())
+    }
+}
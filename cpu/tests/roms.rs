@@ -0,0 +1,141 @@
+use cpu::cpu::Cpu;
+use cpu::headless::{display_hash, Headless, KeyPress};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Runs every rom in roms/ for a while with a fixed seed and the same key presses, hashing the
+// display at a few checkpoints, and compares the hashes with roms.golden. When a change is meant
+// to alter what a rom draws, regenerate the file with
+//
+//     REGENERATE_GOLDENS=1 cargo test -p cpu --test roms
+//
+// and check that the roms whose lines changed still play properly.
+
+// frames to hash the display at, the last one ends the run
+const CHECKPOINTS: [u64; 4] = [30, 120, 300, 600];
+
+// as the frontends run
+const INSTRUCTIONS_PER_FRAME: usize = 10;
+
+const SEED: u64 = 0x5EED;
+
+fn roms_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("../roms")
+}
+
+fn golden_path() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/roms.golden")
+}
+
+// every key in turn, held for 5 frames every 20, enough to get most games past their title
+// screens and moving
+fn key_script() -> Vec<KeyPress> {
+    (0..CHECKPOINTS[CHECKPOINTS.len() - 1] / 20)
+        .map(|n| KeyPress { frame: n * 20 + 10, key: (n % 16) as u8, frames: 5 })
+        .collect()
+}
+
+// a line for each checkpoint: the rom, the frame and the display hash, or the fault it crashed on
+fn checkpoints(name: &str, rom: &[u8], decode_cache: bool) -> Vec<String> {
+    let mut cpu = Cpu::new();
+    cpu.set_decode_cache(decode_cache);
+    cpu.seed_random(SEED);
+    cpu.load_rom_bytes(rom).unwrap();
+
+    let mut lines = Vec::new();
+    let mut frame = 0;
+    for &checkpoint in CHECKPOINTS.iter() {
+        let mut run = Headless::new(checkpoint, INSTRUCTIONS_PER_FRAME);
+        run.keys = key_script();
+        if let Err(e) = run.run_from(&mut cpu, frame) {
+            lines.push(format!("{} {} crashed: {}", name, checkpoint, e));
+            break;
+        }
+        lines.push(format!("{} {} {:016x}", name, checkpoint, display_hash(&cpu)));
+        frame = checkpoint;
+    }
+    lines
+}
+
+fn run_all(decode_cache: bool) -> String {
+    let mut roms: Vec<PathBuf> = fs::read_dir(roms_dir())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.is_file())
+        .collect();
+    roms.sort();
+
+    let mut golden = String::from("# rom, frame, display hash - see cpu/tests/roms.rs to regenerate\n");
+    for path in roms {
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        for line in checkpoints(&name, &fs::read(&path).unwrap(), decode_cache) {
+            golden += &line;
+            golden.push('\n');
+        }
+    }
+    golden
+}
+
+#[test]
+fn bundled_roms_match_goldens() {
+    let actual = run_all(false);
+    if env::var_os("REGENERATE_GOLDENS").is_some() {
+        fs::write(golden_path(), &actual).unwrap();
+        return;
+    }
+
+    let expected = fs::read_to_string(golden_path()).expect("no roms.golden, regenerate it");
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+    let missing: Vec<&&str> = expected.iter().filter(|line| !actual.contains(line)).collect();
+    let unexpected: Vec<&&str> = actual.iter().filter(|line| !expected.contains(line)).collect();
+    assert!(
+        missing.is_empty() && unexpected.is_empty(),
+        "the roms no longer match roms.golden\nexpected:\n{}\ngot:\n{}",
+        missing.iter().map(|line| format!("  {}\n", line)).collect::<String>(),
+        unexpected.iter().map(|line| format!("  {}\n", line)).collect::<String>()
+    );
+}
+
+#[test]
+fn runs_are_repeatable() {
+    let rom = fs::read(roms_dir().join("BRIX")).unwrap();
+    assert_eq!(checkpoints("BRIX", &rom, false), checkpoints("BRIX", &rom, false));
+}
+
+#[test]
+fn decode_cache_changes_nothing() {
+    assert_eq!(run_all(true), run_all(false));
+}
+
+// the whole state after every frame, run through run_frame like the frontends do
+fn frame_states(rom: &[u8], block_engine: bool) -> Vec<Vec<u8>> {
+    let mut cpu = Cpu::new();
+    cpu.set_block_engine(block_engine);
+    cpu.seed_random(SEED);
+    cpu.load_rom_bytes(rom).unwrap();
+
+    let keys = key_script();
+    let mut states = Vec::new();
+    for frame in 0..CHECKPOINTS[CHECKPOINTS.len() - 1] {
+        cpu.keyboard.reset();
+        for press in keys.iter().filter(|press| press.frame <= frame && frame < press.frame + press.frames) {
+            cpu.keyboard.press_key(press.key);
+        }
+        if cpu.run_frame(INSTRUCTIONS_PER_FRAME).is_err() {
+            break;
+        }
+        states.push(cpu.save_state());
+    }
+    states
+}
+
+#[test]
+fn block_engine_changes_nothing() {
+    for entry in fs::read_dir(roms_dir()).unwrap() {
+        let path = entry.unwrap().path();
+        let rom = fs::read(&path).unwrap();
+        assert!(frame_states(&rom, true) == frame_states(&rom, false), "{} differs", path.display());
+    }
+}
@@ -0,0 +1,396 @@
+use crate::config::Settings;
+use crate::keymap::PRESETS;
+use cpu::parse;
+use cpu::platform::Platform;
+use cpu::quirks::{Quirks, QUIRK_NAMES};
+
+// The command line: a command, the ROM (or source) it works on, then options in any order. With
+// no command the ROM is played, so `rusty_chip roms/PONG` still works, as do the platform and load
+// address after the ROM from before there were options for them.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+    Run,
+    Debug,
+    Info,
+    Disasm,
+    Asm,
+    Headless,
+    Help,
+}
+
+#[derive(Debug)]
+pub struct Args {
+    pub command: Command,
+
+    // the ROM, or for asm the source and output
+    pub files: Vec<String>,
+
+    // what the command line chose, which wins over the settings file
+    pub settings: Settings,
+
+    pub seed: Option<u64>,
+    pub load_address: Option<u16>,
+    pub config: Option<String>,
+    pub terminal: bool,
+
+    // --frames, --until, --keys, --keys-file, --screen and --registers for headless, in order
+    pub headless: Vec<(String, String)>,
+}
+
+const OPTIONS: [&str; 11] = [
+    "--platform",
+    "--quirks",
+    "--ipf",
+    "--scale",
+    "--rewind-mb",
+    "--palette",
+    "--keymap",
+    "--bind",
+    "--seed",
+    "--load-address",
+    "--config",
+];
+
+const HEADLESS_OPTIONS: [&str; 6] = ["--frames", "--until", "--keys", "--keys-file", "--screen", "--registers"];
+
+pub fn usage() -> String {
+    format!(
+        "usage: rusty_chip [COMMAND] ROM [OPTIONS]
+
+commands:
+  run ROM               play a rom in a window, what happens without a command
+  debug ROM             play a rom paused, with a debugger prompt on the terminal
+  info ROM              print a rom's size, platform, settings and hash for the settings file
+  disasm ROM            print a listing of a rom
+  asm SOURCE [OUTPUT]   assemble a rom, by default next to the source, compiling .8o with Octo
+  headless ROM          run a rom without a window, then print the display
+  help                  print this
+
+options:
+  --platform NAME       chip8, schip or xochip
+  --quirks LIST         quirks to turn on, or off with no- in front: vf_reset,no-display_wait
+                        the quirks are {}
+  --ipf N               instructions per frame, 10 by default
+  --scale N             window pixels per lo-res pixel, 10 by default
+  --rewind-mb N         megabytes of rewind history, 32 by default
+  --palette COLOURS     four #rrggbb colours, for neither plane, the first, the second and both
+  --keymap NAME         a keymap file, or one of the presets {}
+  --bind LINE           bind more keys on top of the keymap: --bind \"5 = up w\"
+  --seed N              the random seed, different every run unless given (0 for headless)
+  --load-address ADDR   where the rom is loaded, 0x200 by default
+  --config PATH         read settings from PATH instead of the config directory
+  --terminal            play or debug in the terminal instead of a window
+
+headless options:
+  --frames N            how many frames to run, 600 by default
+  --until CONDITION     stop early: pc=0x2A4, mem[0x300]=5 or cycles=1000
+  --keys SCRIPT         hold keys on frames: \"30:5 60:5:10\"
+  --keys-file PATH      the same from a file
+  --screen PATH         write the display as .png, .pbm or ASCII art, - prints it
+  --registers PATH      write the registers as JSON, - prints them
+",
+        QUIRK_NAMES.join(", "),
+        PRESETS.join(", ")
+    )
+}
+
+fn parse_number(name: &str, text: &str, range: std::ops::RangeInclusive<usize>) -> Result<usize, String> {
+    match text.parse() {
+        Ok(number) if range.contains(&number) => Ok(number),
+        _ => Err(format!("{} should be a number from {} to {}, not {}", name, range.start(), range.end(), text)),
+    }
+}
+
+// "#000000,#ffffff,#ff6600,#662200", commas or spaces between them and the # optional
+fn parse_palette(text: &str) -> Result<[u32; 4], String> {
+    let invalid = || format!("--palette should be four #rrggbb colours, not {}", text);
+    let colours: Vec<&str> = text.split(|c: char| c == ',' || c.is_whitespace()).filter(|c| !c.is_empty()).collect();
+    if colours.len() != 4 {
+        return Err(invalid());
+    }
+    let mut palette = [0; 4];
+    for (colour, text) in palette.iter_mut().zip(colours) {
+        let hex = text.strip_prefix('#').unwrap_or(text);
+        if hex.len() != 6 {
+            return Err(invalid());
+        }
+        *colour = u32::from_str_radix(hex, 16).map_err(|_| invalid())?;
+    }
+    Ok(palette)
+}
+
+// "vf_reset,no-display_wait,clip_sprites=false", each quirk turned on or off
+fn parse_quirks(text: &str) -> Result<Vec<(String, bool)>, String> {
+    let mut quirks = Vec::new();
+    for quirk in text.split(',').map(str::trim).filter(|quirk| !quirk.is_empty()) {
+        let (name, on) = match quirk.split_once('=') {
+            Some((name, "true")) | Some((name, "on")) => (name, true),
+            Some((name, "false")) | Some((name, "off")) => (name, false),
+            Some(_) => return Err(format!("--quirks {} should be true or false", quirk)),
+            None => match quirk.strip_prefix("no-") {
+                Some(name) => (name, false),
+                None => (quirk, true),
+            },
+        };
+        // checked here so a typo is reported before anything runs
+        Quirks::default().set(name, on)?;
+        quirks.push((name.to_string(), on));
+    }
+    Ok(quirks)
+}
+
+// the arguments after the program name
+pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Args, String> {
+    let mut args = args.into_iter().peekable();
+    let command = match args.peek().map(String::as_str) {
+        Some("run") => Command::Run,
+        Some("debug") => Command::Debug,
+        Some("info") => Command::Info,
+        Some("disasm") => Command::Disasm,
+        Some("asm") => Command::Asm,
+        Some("headless") => Command::Headless,
+        Some("help") | Some("-h") | Some("--help") | None => Command::Help,
+        // a ROM, played like it always was
+        Some(_) => Command::Run,
+    };
+    if command != Command::Run || args.peek().map(String::as_str) == Some("run") {
+        args.next();
+    }
+
+    let mut parsed = Args {
+        command,
+        files: Vec::new(),
+        settings: Settings::default(),
+        seed: None,
+        load_address: None,
+        config: None,
+        terminal: false,
+        headless: Vec::new(),
+    };
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") && arg != "-h" {
+            parsed.files.push(arg);
+            continue;
+        }
+        // --ipf 12 and --ipf=12 are the same
+        let (name, inline) = match arg.split_once('=') {
+            Some((name, value)) => (name.to_string(), Some(value.to_string())),
+            None => (arg, None),
+        };
+        match name.as_str() {
+            "--help" | "-h" => {
+                parsed.command = Command::Help;
+                continue;
+            }
+            "--terminal" => {
+                parsed.terminal = true;
+                continue;
+            }
+            // from before debug was a command
+            "--debug" if parsed.command == Command::Run => {
+                parsed.command = Command::Debug;
+                continue;
+            }
+            name if !OPTIONS.contains(&name) && !HEADLESS_OPTIONS.contains(&name) => {
+                return Err(format!("unknown option {}", name))
+            }
+            _ => {}
+        }
+        let value = match inline.or_else(|| args.next()) {
+            Some(value) => value,
+            None => return Err(format!("{} needs a value", name)),
+        };
+        match name.as_str() {
+            "--platform" => parsed.settings.platform = Some(value.parse()?),
+            "--quirks" => parsed.settings.quirks.extend(parse_quirks(&value)?),
+            "--ipf" => parsed.settings.speed = Some(parse_number("--ipf", &value, 1..=10_000)?),
+            "--scale" => parsed.settings.scale = Some(parse_number("--scale", &value, 1..=32)?),
+            "--rewind-mb" => parsed.settings.rewind_budget = Some(parse_number("--rewind-mb", &value, 1..=4096)?),
+            "--palette" => parsed.settings.palette = Some(parse_palette(&value)?),
+            "--keymap" => parsed.settings.keymap = Some(value),
+            "--bind" => parsed.settings.bind.push(value),
+            "--seed" => parsed.seed = Some(value.parse().map_err(|_| format!("--seed should be a number, not {}", value))?),
+            "--load-address" => {
+                parsed.load_address = Some(parse::number::<u16>(&value).ok_or_else(|| format!("invalid load address {}", value))?)
+            }
+            "--config" => parsed.config = Some(value),
+            name if HEADLESS_OPTIONS.contains(&name) => {
+                if parsed.command != Command::Headless {
+                    return Err(format!("{} is only for the headless command", name));
+                }
+                parsed.headless.push((name.to_string(), value));
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    if parsed.files.is_empty() && parsed.command != Command::Help {
+        return Err(match parsed.command {
+            Command::Asm => String::from("no source file given"),
+            _ => String::from("no rom given"),
+        });
+    }
+    match parsed.command {
+        Command::Help => {}
+        Command::Asm => legacy_asm(&mut parsed)?,
+        _ => legacy_rom(&mut parsed)?,
+    }
+    Ok(parsed)
+}
+
+// Before there were options for them the platform and load address could follow the ROM, as
+// `ROM [PLATFORM] [ADDRESS]`. A number is the load address and a name the platform, anything else
+// is reported rather than guessed at. The options win over them.
+fn legacy_rom(parsed: &mut Args) -> Result<(), String> {
+    let rest = parsed.files.split_off(1);
+    let mut rest = rest.iter();
+    let mut next = rest.next();
+    if let Some(text) = next.filter(|text| parse::number::<u16>(text).is_none()) {
+        let platform: Platform = text.parse().map_err(|_| {
+            format!("{} after the rom should be a platform (chip8, schip or xochip) or a load address like 0x600", text)
+        })?;
+        parsed.settings.platform = parsed.settings.platform.or(Some(platform));
+        next = rest.next();
+    }
+    if let Some(text) = next {
+        let address = parse::number::<u16>(text).ok_or_else(|| format!("{} after the platform should be a load address like 0x600", text))?;
+        parsed.load_address = parsed.load_address.or(Some(address));
+    }
+    match rest.next() {
+        Some(text) => Err(format!("unexpected argument {}", text)),
+        None => Ok(()),
+    }
+}
+
+// `asm SOURCE [OUTPUT] [ADDRESS]`, where a number after the source is the load address and not an
+// output file, so write `./1536` for an output really named that
+fn legacy_asm(parsed: &mut Args) -> Result<(), String> {
+    let at = match parsed.files.get(1) {
+        Some(text) if parse::number::<u16>(text).is_some() => 1,
+        _ => 2,
+    };
+    if let Some(text) = parsed.files.get(at + 1) {
+        return Err(format!("unexpected argument {}", text));
+    }
+    if let Some(text) = parsed.files.get(at) {
+        let address = parse::number::<u16>(text).ok_or_else(|| format!("{} after the output should be a load address like 0x600", text))?;
+        parsed.load_address = parsed.load_address.or(Some(address));
+        parsed.files.remove(at);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_line(line: &str) -> Result<Args, String> {
+        parse(line.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn commands() {
+        let commands = [
+            ("roms/PONG", Command::Run),
+            ("run roms/PONG", Command::Run),
+            ("debug roms/PONG", Command::Debug),
+            ("roms/PONG --debug", Command::Debug),
+            ("info roms/PONG", Command::Info),
+            ("disasm roms/PONG", Command::Disasm),
+            ("asm pong.asm", Command::Asm),
+            ("headless roms/PONG", Command::Headless),
+            ("help", Command::Help),
+            ("roms/PONG --help", Command::Help),
+            ("", Command::Help),
+        ];
+        for (line, command) in commands.iter() {
+            assert_eq!(parse_line(line).unwrap().command, *command, "{}", line);
+        }
+        assert_eq!(parse_line("asm pong.asm pong.ch8").unwrap().files, vec!["pong.asm", "pong.ch8"]);
+        assert_eq!(parse_line("run roms/PONG").unwrap().files, vec!["roms/PONG"]);
+        assert!(parse_line("hash roms/PONG").unwrap_err().starts_with("roms/PONG after the rom"), "hash is a rom");
+    }
+
+    #[test]
+    fn options() {
+        let args = parse_line(
+            "roms/PONG --ipf 12 --scale=8 --rewind-mb 64 --platform schip --seed 42 --load-address 0x600 --config my.toml --terminal --keymap azerty --palette #000000,#ffffff,#ff6600,#662200",
+        )
+        .unwrap();
+        assert_eq!(args.settings.speed, Some(12));
+        assert_eq!(args.settings.scale, Some(8));
+        assert_eq!(args.settings.rewind_budget, Some(64));
+        assert_eq!(args.settings.platform, Some(Platform::SuperChip));
+        assert_eq!(args.settings.keymap.as_deref(), Some("azerty"));
+        assert_eq!(args.settings.palette, Some([0, 0xFFFFFF, 0xFF6600, 0x662200]));
+        assert_eq!((args.seed, args.load_address), (Some(42), Some(0x600)));
+        assert_eq!(args.config.as_deref(), Some("my.toml"));
+        assert!(args.terminal);
+
+        let args = parse(vec!["roms/PONG", "--bind", "5 = up w", "--bind=6 = down"].into_iter().map(String::from)).unwrap();
+        assert_eq!(args.settings.bind, vec!["5 = up w", "6 = down"]);
+
+        let args = parse_line("headless roms/PONG --frames 60 --screen -").unwrap();
+        assert_eq!(args.headless, vec![(String::from("--frames"), String::from("60")), (String::from("--screen"), String::from("-"))]);
+        assert_eq!(parse_line("roms/PONG --frames 60").unwrap_err(), "--frames is only for the headless command");
+        assert_eq!(parse_line("roms/PONG --ipf").unwrap_err(), "--ipf needs a value");
+        assert_eq!(parse_line("roms/PONG --fast").unwrap_err(), "unknown option --fast");
+    }
+
+    #[test]
+    fn quirks() {
+        let args = parse_line("roms/PONG --quirks vf_reset,no-display_wait --quirks clip_sprites=false,jump_uses_vx=on").unwrap();
+        let quirks: Vec<(&str, bool)> = args.settings.quirks.iter().map(|(name, on)| (name.as_str(), *on)).collect();
+        assert_eq!(quirks, vec![("vf_reset", true), ("display_wait", false), ("clip_sprites", false), ("jump_uses_vx", true)]);
+        assert!(parse_line("roms/PONG --quirks wrap").unwrap_err().contains("unknown quirk"));
+        assert_eq!(parse_line("roms/PONG --quirks vf_reset=maybe").unwrap_err(), "--quirks vf_reset=maybe should be true or false");
+    }
+
+    #[test]
+    fn bad_numbers() {
+        assert_eq!(parse_line("roms/PONG --ipf 0").unwrap_err(), "--ipf should be a number from 1 to 10000, not 0");
+        assert_eq!(parse_line("roms/PONG --ipf fast").unwrap_err(), "--ipf should be a number from 1 to 10000, not fast");
+        assert_eq!(parse_line("roms/PONG --scale 33").unwrap_err(), "--scale should be a number from 1 to 32, not 33");
+        assert_eq!(parse_line("roms/PONG --rewind-mb 0").unwrap_err(), "--rewind-mb should be a number from 1 to 4096, not 0");
+        assert_eq!(parse_line("roms/PONG --seed -1").unwrap_err(), "--seed should be a number, not -1");
+        assert_eq!(parse_line("roms/PONG --load-address 0x10000").unwrap_err(), "invalid load address 0x10000");
+        assert!(parse_line("roms/PONG --palette #000000,#ffffff").is_err());
+        assert!(parse_line("roms/PONG --platform chip9").unwrap_err().contains("unknown platform"));
+    }
+
+    #[test]
+    fn missing_files() {
+        assert_eq!(parse_line("run").unwrap_err(), "no rom given");
+        assert_eq!(parse_line("headless --frames 60").unwrap_err(), "no rom given");
+        assert_eq!(parse_line("asm").unwrap_err(), "no source file given");
+        assert_eq!(parse_line("roms/PONG roms/BRIX").unwrap_err().split(' ').next(), Some("roms/BRIX"));
+    }
+
+    #[test]
+    fn legacy_positionals() {
+        let args = parse_line("roms/PONG 0x600").unwrap();
+        assert_eq!((args.files.len(), args.settings.platform, args.load_address), (1, None, Some(0x600)));
+        let args = parse_line("roms/PONG 1536").unwrap();
+        assert_eq!(args.load_address, Some(0x600));
+        let args = parse_line("disasm roms/PONG schip").unwrap();
+        assert_eq!((args.settings.platform, args.load_address), (Some(Platform::SuperChip), None));
+        let args = parse_line("roms/PONG chip8 0x600").unwrap();
+        assert_eq!((args.files.len(), args.settings.platform, args.load_address), (1, Some(Platform::Chip8), Some(0x600)));
+
+        // the options win
+        let args = parse_line("roms/PONG xochip 0x600 --platform chip8 --load-address 0x200").unwrap();
+        assert_eq!((args.settings.platform, args.load_address), (Some(Platform::Chip8), Some(0x200)));
+
+        assert_eq!(parse_line("roms/PONG 0x600 chip8").unwrap_err(), "unexpected argument chip8");
+        assert!(parse_line("roms/PONG chip8 schip").unwrap_err().starts_with("schip after the platform"));
+        assert!(parse_line("roms/PONG chip8 0x600 0x200").unwrap_err().contains("unexpected argument 0x200"));
+
+        let args = parse_line("asm pong.asm 0x600").unwrap();
+        assert_eq!((args.files, args.load_address), (vec![String::from("pong.asm")], Some(0x600)));
+        let args = parse_line("asm pong.asm pong.ch8 0x600").unwrap();
+        assert_eq!((args.files, args.load_address), (vec![String::from("pong.asm"), String::from("pong.ch8")], Some(0x600)));
+        assert!(parse_line("asm pong.asm pong.ch8 eti").unwrap_err().starts_with("eti after the output"));
+        assert!(parse_line("asm pong.asm 0x600 pong.ch8").unwrap_err().contains("unexpected argument pong.ch8"));
+    }
+}
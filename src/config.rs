@@ -0,0 +1,288 @@
+use cpu::platform::Platform;
+use cpu::quirks::Quirks;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml::value::Table;
+use toml::Value;
+
+// The settings file, config.toml in the user's config directory. The top level holds the
+// defaults for every ROM, and a [rom.HASH] table for a ROM overrides them whenever that ROM is
+// played, so each game can keep the speed and quirks it needs:
+//
+//     speed = 12
+//     palette = ["#000000", "#33ff66", "#ff6600", "#662200"]
+//     keymap = "azerty"
+//
+//     [quirks]
+//     display_wait = false
+//
+//     [rom.9b8e3f4a2c1d0e5f]
+//     name = "PONG"
+//     speed = 7
+//     bind = ["1 = up", "4 = down"]
+//     quirks.vf_reset = true
+
+// a 64 bit FNV-1a hash of a ROM, as 16 hex digits, naming its [rom.HASH] table
+pub fn rom_hash(rom: &[u8]) -> String {
+    let mut hash: u64 = 0xCBF2_9CE4_8422_2325;
+    for byte in rom {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01B3);
+    }
+    format!("{:016x}", hash)
+}
+
+fn kind(value: &Value) -> &'static str {
+    match value {
+        Value::String(_) => "a string",
+        Value::Integer(_) => "an integer",
+        Value::Float(_) => "a float",
+        Value::Boolean(_) => "a boolean",
+        Value::Datetime(_) => "a date",
+        Value::Array(_) => "an array",
+        Value::Table(_) => "a table",
+    }
+}
+
+// what the settings file chose, None where it left the built-in default alone
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Settings {
+    // instructions per frame
+    pub speed: Option<usize>,
+
+    // window pixels per lo-res pixel
+    pub scale: Option<usize>,
+
+    // megabytes of rewind history
+    pub rewind_budget: Option<usize>,
+
+    // colours for neither plane, the first, the second and both
+    pub palette: Option<[u32; 4]>,
+
+    // a keymap preset or file
+    pub keymap: Option<String>,
+
+    // extra keymap lines, bound on top of the keymap
+    pub bind: Vec<String>,
+
+    pub platform: Option<Platform>,
+
+    // quirks turned on or off, the ones from settings laid over others last so they win
+    pub quirks: Vec<(String, bool)>,
+}
+
+impl Settings {
+    // these settings with other's on top
+    pub fn overridden_by(&self, other: &Settings) -> Settings {
+        Settings {
+            speed: other.speed.or(self.speed),
+            scale: other.scale.or(self.scale),
+            rewind_budget: other.rewind_budget.or(self.rewind_budget),
+            palette: other.palette.or(self.palette),
+            keymap: other.keymap.clone().or_else(|| self.keymap.clone()),
+            bind: self.bind.iter().chain(other.bind.iter()).cloned().collect(),
+            platform: other.platform.or(self.platform),
+            quirks: self.quirks.iter().chain(other.quirks.iter()).cloned().collect(),
+        }
+    }
+
+    // the quirks with the ones the settings changed
+    pub fn apply_quirks(&self, mut quirks: Quirks) -> Quirks {
+        for (name, on) in self.quirks.iter() {
+            // names were checked when the settings were read
+            let _ = quirks.set(name, *on);
+        }
+        quirks
+    }
+
+    fn read(&mut self, table: &Table) -> Result<(), String> {
+        for (name, value) in table.iter() {
+            self.set(name, value)?;
+        }
+        Ok(())
+    }
+
+    fn set(&mut self, name: &str, value: &Value) -> Result<(), String> {
+        let invalid = |expected: &str| format!("{} should be {}, not {}", name, expected, kind(value));
+        match (name, value) {
+            ("speed", Value::Integer(speed)) if (1..=10_000).contains(speed) => self.speed = Some(*speed as usize),
+            ("speed", _) => return Err(invalid("an integer from 1 to 10000")),
+            ("scale", Value::Integer(scale)) if (1..=32).contains(scale) => self.scale = Some(*scale as usize),
+            ("scale", _) => return Err(invalid("an integer from 1 to 32")),
+            ("rewind_budget", Value::Integer(megabytes)) if (1..=4096).contains(megabytes) => {
+                self.rewind_budget = Some(*megabytes as usize)
+            }
+            ("rewind_budget", _) => return Err(invalid("an integer from 1 to 4096")),
+            ("palette", Value::Array(colours)) if colours.len() == 4 => {
+                let mut palette = [0; 4];
+                for (colour, value) in palette.iter_mut().zip(colours.iter()) {
+                    *colour = parse_colour(value).ok_or_else(|| invalid("four \"#rrggbb\" colours"))?;
+                }
+                self.palette = Some(palette);
+            }
+            ("palette", _) => return Err(invalid("four \"#rrggbb\" colours")),
+            ("keymap", Value::String(keymap)) => self.keymap = Some(keymap.clone()),
+            ("keymap", _) => return Err(invalid("a string")),
+            ("bind", Value::Array(lines)) => {
+                for line in lines {
+                    match line {
+                        Value::String(line) => self.bind.push(line.clone()),
+                        _ => return Err(invalid("an array of strings")),
+                    }
+                }
+            }
+            ("bind", _) => return Err(invalid("an array of strings")),
+            ("platform", Value::String(platform)) => self.platform = Some(platform.parse()?),
+            ("platform", _) => return Err(invalid("a string")),
+            ("quirks", Value::Table(quirks)) => {
+                for (quirk, on) in quirks.iter() {
+                    match on {
+                        Value::Boolean(on) => {
+                            Quirks::default().set(quirk, *on)?;
+                            self.quirks.push((quirk.clone(), *on));
+                        }
+                        _ => return Err(format!("quirks.{} should be a boolean, not {}", quirk, kind(on))),
+                    }
+                }
+            }
+            ("quirks", _) => return Err(invalid("a table of booleans")),
+            // only there to say which ROM a table is for
+            ("name", _) => {}
+            _ => return Err(format!("unknown setting {}", name)),
+        }
+        Ok(())
+    }
+}
+
+// "#rrggbb", or the colour as an integer like 0xrrggbb
+fn parse_colour(value: &Value) -> Option<u32> {
+    match value {
+        Value::String(colour) => {
+            let hex = colour.strip_prefix('#')?;
+            if hex.len() != 6 {
+                return None;
+            }
+            u32::from_str_radix(hex, 16).ok()
+        }
+        Value::Integer(colour) => u32::try_from(*colour).ok().filter(|colour| *colour <= 0xFF_FFFF),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Config {
+    // for every ROM
+    pub defaults: Settings,
+
+    // by ROM hash
+    pub roms: HashMap<String, Settings>,
+}
+
+impl Config {
+    pub fn parse(text: &str) -> Result<Config, String> {
+        let document = match text.parse::<Value>().map_err(|e| e.to_string())? {
+            Value::Table(document) => document,
+            _ => return Err(String::from("expected a table")),
+        };
+        let mut config = Config::default();
+        for (key, value) in document.iter() {
+            match (key.as_str(), value) {
+                ("rom", Value::Table(roms)) => {
+                    for (hash, table) in roms.iter() {
+                        let table = match table {
+                            Value::Table(table) => table,
+                            _ => return Err(format!("rom.{} should be a table, not {}", hash, kind(table))),
+                        };
+                        let settings = config.roms.entry(hash.to_lowercase()).or_default();
+                        settings.read(table).map_err(|e| format!("[rom.{}] {}", hash, e))?;
+                    }
+                }
+                ("rom", _) => return Err(format!("rom should be [rom.HASH] tables, not {}", kind(value))),
+                _ => config.defaults.set(key, value)?,
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Config, String> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Config::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    // the settings for a ROM, its own table over the defaults
+    pub fn settings_for(&self, rom: &[u8]) -> Settings {
+        match self.roms.get(&rom_hash(rom)) {
+            Some(settings) => self.defaults.overridden_by(settings),
+            None => self.defaults.clone(),
+        }
+    }
+}
+
+// where the settings file lives: rusty_chip/config.toml in the XDG config directory, Application
+// Support on macOS or AppData on Windows
+pub fn default_path() -> Option<PathBuf> {
+    let dir = if cfg!(windows) {
+        env::var_os("APPDATA").map(PathBuf::from)
+    } else if cfg!(target_os = "macos") {
+        env::var_os("HOME").map(|home| PathBuf::from(home).join("Library/Application Support"))
+    } else {
+        env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+    };
+    dir.map(|dir| dir.join("rusty_chip").join("config.toml"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn toml_errors_give_the_line() {
+        assert!(Config::parse("\nname = \"PONG").unwrap_err().contains("line 2"));
+        assert!(Config::parse("speed = 1\nspeed = 2").unwrap_err().contains("duplicate key"));
+        assert!(Config::parse("speed = 1 2").is_err());
+        assert!(Config::parse("speed = 1.5").unwrap_err().contains("not a float"));
+    }
+
+    #[test]
+    fn rom_tables_override_the_defaults() {
+        let pong = b"PONG";
+        let config = Config::parse(&format!(
+            "speed = 12\nkeymap = \"azerty\"\nbind = [\"5 = up\"]\n[quirks]\nvf_reset = false\n\n[rom.{}]\nname = \"PONG\"\nspeed = 7\nbind = [\"1 = j\"]\nquirks.vf_reset = true\npalette = [\"#000000\", \"#33FF66\", 0xff6600, 0]\n",
+            rom_hash(pong)
+        ))
+        .unwrap();
+
+        let settings = config.settings_for(pong);
+        assert_eq!(settings.speed, Some(7));
+        assert_eq!(settings.keymap.as_deref(), Some("azerty"), "not overridden");
+        assert_eq!(settings.bind, vec!["5 = up", "1 = j"]);
+        assert_eq!(settings.palette, Some([0, 0x33FF66, 0xFF6600, 0]));
+        assert!(settings.apply_quirks(Quirks::default()).vf_reset, "the ROM's quirk came last");
+
+        let other = config.settings_for(b"BRIX");
+        assert_eq!((other.speed, other.palette), (Some(12), None));
+        assert!(!other.apply_quirks(Quirks::cosmac_vip()).vf_reset);
+    }
+
+    #[test]
+    fn settings_are_checked() {
+        assert_eq!(Config::parse("sped = 3").unwrap_err(), "unknown setting sped");
+        assert!(Config::parse("speed = \"fast\"").unwrap_err().contains("speed should be"));
+        assert!(Config::parse("speed = 0").is_err());
+        assert_eq!(Config::parse("rewind_budget = 64").unwrap().defaults.rewind_budget, Some(64));
+        assert!(Config::parse("rewind_budget = 0").unwrap_err().contains("rewind_budget should be"));
+        assert!(Config::parse("palette = [\"#000\"]").is_err());
+        assert!(Config::parse("quirks.wrap = true").unwrap_err().contains("unknown quirk"));
+        assert!(Config::parse("quirks.vf_reset = 1").unwrap_err().contains("should be a boolean"));
+        assert!(Config::parse("platform = \"chip9\"").is_err());
+        assert!(Config::parse("[rom.ABC]\nspeed = 0").unwrap_err().starts_with("[rom.ABC] speed"));
+        assert_eq!(Config::parse("[rom.ABC]\nplatform = \"schip\"").unwrap().roms["abc"].platform, Some(Platform::SuperChip));
+    }
+}
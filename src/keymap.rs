@@ -0,0 +1,263 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+// Which host keys press which of the 16 hex keys. A host key presses one hex key, a hex key can
+// have any number of host keys. Host keys are named the same way whatever the frontend, so a
+// keymap works in the window and the terminal alike: a printable key is its character in lower
+// case (q, 1, ;), any other key a word (up, space, enter, numpad4).
+//
+// Keymap files have a line per hex key, its host keys after an equals sign, and may start from
+// one of the presets:
+//
+//     preset = azerty
+//     # arrow keys for the paddles
+//     5 = up
+//     8 = down
+
+// the COSMAC VIP keypad, row by row, which every layout preset lays over a 4x4 block of keys
+const VIP_KEYPAD: [[u8; 4]; 4] = [[0x1, 0x2, 0x3, 0xC], [0x4, 0x5, 0x6, 0xD], [0x7, 0x8, 0x9, 0xE], [0xA, 0x0, 0xB, 0xF]];
+
+pub const PRESETS: [&str; 5] = ["qwerty", "azerty", "dvorak", "vip", "arrows"];
+
+// words for the keys that also have a character, so either name can be used
+const ALIASES: [(&str, &str); 13] = [
+    ("comma", ","),
+    ("period", "."),
+    ("semicolon", ";"),
+    ("apostrophe", "'"),
+    ("slash", "/"),
+    ("backslash", "\\"),
+    ("minus", "-"),
+    ("equal", "="),
+    ("leftbracket", "["),
+    ("rightbracket", "]"),
+    ("backquote", "`"),
+    ("return", "enter"),
+    ("spacebar", "space"),
+];
+
+// what the frontends use a host key for, when a keymap can not bind it
+pub fn reserved(host: &str) -> Option<String> {
+    match host {
+        "escape" => Some(String::from("quits")),
+        "backspace" => Some(String::from("rewinds the game")),
+        _ => {
+            let slot = host.strip_prefix('f').and_then(|slot| slot.parse::<u8>().ok()).filter(|slot| (1..=9).contains(slot))?;
+            Some(format!("loads save slot {}, and saves it with shift", slot))
+        }
+    }
+}
+
+// the name a host key goes by in a keymap, from what a frontend or a keymap file calls it
+// "Q" and "q" are both q, "Key1" is 1 and "semicolon" is ;
+pub fn host_key_name(name: &str) -> String {
+    let name = name.trim().to_lowercase();
+    let digit = name.strip_prefix("key").filter(|rest| rest.len() == 1 && rest.chars().all(|c| c.is_ascii_digit()));
+    if let Some(digit) = digit {
+        return digit.to_string();
+    }
+    match ALIASES.iter().find(|(alias, _)| *alias == name) {
+        Some((_, key)) => key.to_string(),
+        None => name,
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Keymap {
+    bindings: BTreeMap<String, u8>,
+}
+
+impl Keymap {
+    // no keys bound at all
+    pub fn new() -> Keymap {
+        Keymap::default()
+    }
+
+    pub fn preset(name: &str) -> Option<Keymap> {
+        let mut keymap = Keymap::new();
+        match name.to_ascii_lowercase().as_str() {
+            // the keypad's layout, on the keys in the same place on each keyboard layout
+            "qwerty" => keymap.bind_grid(["1234", "qwer", "asdf", "zxcv"]),
+            "azerty" => keymap.bind_grid(["1234", "azer", "qsdf", "wxcv"]),
+            "dvorak" => keymap.bind_grid(["1234", "',.p", "aoeu", ";qjk"]),
+            // each hex key on the key printed with the same digit
+            "vip" => {
+                for key in 0..16u8 {
+                    keymap.bind(&format!("{:x}", key), key);
+                }
+            }
+            // the QWERTY layout, with the arrow keys and space standing in for W, A, S, D and E the
+            // way most games lay out their controls
+            "arrows" => {
+                keymap = Keymap::preset("qwerty")?;
+                for (host, key) in [("up", 0x5), ("left", 0x7), ("down", 0x8), ("right", 0x9), ("space", 0x6)].iter() {
+                    keymap.bind(host, *key);
+                }
+            }
+            _ => return None,
+        }
+        Some(keymap)
+    }
+
+    fn bind_grid(&mut self, rows: [&str; 4]) {
+        for (row, keys) in rows.iter().zip(VIP_KEYPAD.iter()) {
+            for (host, key) in row.chars().zip(keys.iter()) {
+                self.bind(&host.to_string(), *key);
+            }
+        }
+    }
+
+    // host presses key from now on, instead of whatever it pressed before
+    pub fn bind(&mut self, host: &str, key: u8) {
+        self.bindings.insert(host_key_name(host), key & 0x0F);
+    }
+
+    // a keymap file, or a preset when there is no file by that name
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Keymap, String> {
+        let path = path.as_ref();
+        if !path.exists() {
+            if let Some(keymap) = path.to_str().and_then(Keymap::preset) {
+                return Ok(keymap);
+            }
+        }
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("{}: {} (the presets are {})", path.display(), e, PRESETS.join(", ")))?;
+        text.parse().map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    // a line of a keymap file, binding each host key after the equals sign, e.g. "5 = up w"
+    pub fn apply(&mut self, line: &str) -> Result<(), String> {
+        let (key, hosts) = line.split_once('=').ok_or_else(|| format!("expected KEY = HOST_KEYS, got {}", line))?;
+        let key = key.trim();
+        if key.eq_ignore_ascii_case("preset") {
+            let name = hosts.trim();
+            *self = Keymap::preset(name)
+                .ok_or_else(|| format!("unknown preset {}, the presets are {}", name, PRESETS.join(", ")))?;
+            return Ok(());
+        }
+        let key = match u8::from_str_radix(key, 16) {
+            Ok(value) if key.len() == 1 => value,
+            _ => return Err(format!("invalid hex key {}", key)),
+        };
+        let hosts: Vec<&str> = hosts.split_whitespace().collect();
+        if hosts.is_empty() {
+            return Err(format!("no host keys for hex key {:X}", key));
+        }
+        for host in hosts {
+            if let Some(used) = reserved(&host_key_name(host)) {
+                return Err(format!("{} {}, so it can not be bound", host, used));
+            }
+            self.bind(host, key);
+        }
+        Ok(())
+    }
+
+    // the hex key a host key presses, named however the frontend names it
+    pub fn key_for(&self, host: &str) -> Option<u8> {
+        self.bindings.get(&host_key_name(host)).copied()
+    }
+
+    // the host keys bound to a hex key
+    pub fn host_keys(&self, key: u8) -> impl Iterator<Item = &str> {
+        self.bindings.iter().filter(move |(_, bound)| **bound == key).map(|(host, _)| host.as_str())
+    }
+}
+
+impl FromStr for Keymap {
+    type Err = String;
+
+    // a keymap file, # starts a comment
+    fn from_str(text: &str) -> Result<Keymap, String> {
+        let mut keymap = Keymap::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if !line.is_empty() {
+                keymap.apply(line).map_err(|e| format!("line {}: {}", number + 1, e))?;
+            }
+        }
+        Ok(keymap)
+    }
+}
+
+// the keymap as a keymap file, a line per hex key with host keys
+impl fmt::Display for Keymap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for key in 0..16 {
+            let hosts: Vec<&str> = self.host_keys(key).collect();
+            if !hosts.is_empty() {
+                writeln!(f, "{:X} = {}", key, hosts.join(" "))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presets_lay_out_the_keypad() {
+        let qwerty = Keymap::preset("qwerty").unwrap();
+        assert_eq!((qwerty.key_for("4"), qwerty.key_for("Q"), qwerty.key_for("x"), qwerty.key_for("v")), (Some(0xC), Some(0x4), Some(0x0), Some(0xF)));
+        let azerty = Keymap::preset("azerty").unwrap();
+        assert_eq!((azerty.key_for("a"), azerty.key_for("w"), azerty.key_for("q")), (Some(0x4), Some(0xA), Some(0x7)));
+        let dvorak = Keymap::preset("dvorak").unwrap();
+        assert_eq!((dvorak.key_for("'"), dvorak.key_for("semicolon")), (Some(0x4), Some(0xA)));
+        let vip = Keymap::preset("vip").unwrap();
+        assert_eq!((vip.key_for("Key0"), vip.key_for("c")), (Some(0x0), Some(0xC)));
+        for name in PRESETS.iter() {
+            let keymap = Keymap::preset(name).unwrap();
+            assert!((0..16).all(|key| keymap.host_keys(key).next().is_some()), "{} binds every key", name);
+        }
+    }
+
+    #[test]
+    fn several_host_keys_press_one_key() {
+        let arrows = Keymap::preset("arrows").unwrap();
+        assert_eq!(arrows.key_for("Up"), Some(0x5));
+        assert_eq!(arrows.key_for("w"), Some(0x5));
+        assert_eq!(arrows.host_keys(0x5).collect::<Vec<_>>(), vec!["up", "w"]);
+    }
+
+    #[test]
+    fn files_start_from_a_preset() {
+        let keymap: Keymap = "preset = qwerty\n# the paddles\n1 = up\n4 = down j\n".parse().unwrap();
+        assert_eq!(keymap.key_for("up"), Some(0x1));
+        assert_eq!((keymap.key_for("down"), keymap.key_for("j"), keymap.key_for("q")), (Some(0x4), Some(0x4), Some(0x4)));
+
+        let keymap: Keymap = "a = space".parse().unwrap();
+        assert_eq!(keymap.key_for("space"), Some(0xA));
+        assert_eq!(keymap.key_for("1"), None, "no preset, nothing else bound");
+
+        assert!("10 = up".parse::<Keymap>().unwrap_err().contains("line 1"));
+        assert!("5 =".parse::<Keymap>().is_err());
+        assert!("preset = colemak".parse::<Keymap>().is_err());
+        assert!("5 up".parse::<Keymap>().is_err());
+    }
+
+    #[test]
+    fn hotkeys_can_not_be_bound() {
+        let mut keymap = Keymap::preset("qwerty").unwrap();
+        assert_eq!(keymap.apply("5 = backspace").unwrap_err(), "backspace rewinds the game, so it can not be bound");
+        assert_eq!(keymap.apply("5 = up F3").unwrap_err(), "F3 loads save slot 3, and saves it with shift, so it can not be bound");
+        assert!("1 = escape".parse::<Keymap>().unwrap_err().contains("escape quits"));
+        assert!(keymap.apply("5 = f10").is_ok(), "only F1 - F9 are save slots");
+        for name in PRESETS.iter() {
+            let keymap = Keymap::preset(name).unwrap();
+            assert!((0..16).flat_map(|key| keymap.host_keys(key)).all(|host| reserved(host).is_none()), "{}", name);
+        }
+    }
+
+    #[test]
+    fn rebinding_a_host_key_moves_it() {
+        let mut keymap = Keymap::preset("qwerty").unwrap();
+        keymap.apply("0 = q").unwrap();
+        assert_eq!(keymap.key_for("q"), Some(0x0));
+        assert_eq!(keymap.host_keys(0x4).count(), 0, "4 lost its only key");
+        assert_eq!(keymap.to_string().parse::<Keymap>().unwrap(), keymap, "written back out the same");
+    }
+}
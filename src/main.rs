@@ -1,42 +1,487 @@
 extern crate minifb;
+mod cli;
+mod config;
+mod keymap;
+mod terminal;
+mod tui;
+
 use minifb::{Key, KeyRepeat, Window, WindowOptions};
 // I hate this, but I would have to restructure the workspace into a lib crate ¯\_(ツ)_/¯
-use cpu::cpu::Cpu;
+use cli::{Args, Command};
+use config::{Config, Settings};
+use cpu::asm::{assemble_file, AsmError};
+use cpu::cpu::{Cpu, LORES_HEIGHT, LORES_WIDTH, MEMORY_START_INDEX};
+use cpu::error::CpuError;
+use cpu::octo::compile_file;
+use cpu::debugger::{current_instruction, Debugger};
+use cpu::disasm::disassemble_rom;
+use cpu::instruction::Instruction;
+use keymap::Keymap;
+use cpu::headless::{parse_key_script, registers_json, to_ascii, to_pbm, to_png, Headless};
+use cpu::platform::Platform;
+use cpu::quirks::QUIRK_NAMES;
+use cpu::rewind::RewindBuffer;
 use std::env;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
 use std::{
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-//const EXECUTION_RATE: f32 = 0.06; // 60 hertz
+// the timers and display run at 60 hertz
+const FRAME_DURATION: Duration = Duration::from_micros(1_000_000 / 60);
+// how many instructions the CPU runs each frame, 10 is roughly the 500-600Hz of the originals
+const INSTRUCTIONS_PER_FRAME: usize = 10;
+// window pixels per lo-res pixel, 640x320
+const WINDOW_SCALE: usize = 10;
+
+// colours for each combination of XO-CHIP planes: neither, first, second, both
+// CHIP-8 and SUPER-CHIP only ever draw on the first plane, so they stay white on black
+const PALETTE: [u32; 4] = [0x000000, 0xffffff, 0xff6600, 0x662200];
+// once the CPU crashes the last frame stays up, tinted red
+const CRASH_PALETTE: [u32; 4] = [0x200000, 0xff4040, 0xc03030, 0x802020];
+
+// holding backspace plays the game backwards
+const REWIND_KEY: Key = Key::Backspace;
+// megabytes the rewind history may use unless the settings say otherwise, a few minutes of play
+// for most games
+const REWIND_BUDGET_MB: usize = 32;
+
+// F1 - F9 load save slots 1 - 9, holding shift saves to them instead
+const SAVE_SLOT_KEYS: [Key; 9] = [
+    Key::F1, Key::F2, Key::F3, Key::F4, Key::F5, Key::F6, Key::F7, Key::F8, Key::F9,
+];
+
+// what keymaps call each of the window's keys
+const WINDOW_KEYS: [(Key, &str); 106] = [
+    (Key::Key0, "0"), (Key::Key1, "1"), (Key::Key2, "2"), (Key::Key3, "3"), (Key::Key4, "4"),
+    (Key::Key5, "5"), (Key::Key6, "6"), (Key::Key7, "7"), (Key::Key8, "8"), (Key::Key9, "9"),
+    (Key::A, "a"), (Key::B, "b"), (Key::C, "c"), (Key::D, "d"), (Key::E, "e"), (Key::F, "f"), (Key::G, "g"),
+    (Key::H, "h"), (Key::I, "i"), (Key::J, "j"), (Key::K, "k"), (Key::L, "l"), (Key::M, "m"), (Key::N, "n"),
+    (Key::O, "o"), (Key::P, "p"), (Key::Q, "q"), (Key::R, "r"), (Key::S, "s"), (Key::T, "t"), (Key::U, "u"),
+    (Key::V, "v"), (Key::W, "w"), (Key::X, "x"), (Key::Y, "y"), (Key::Z, "z"),
+    (Key::F1, "f1"), (Key::F2, "f2"), (Key::F3, "f3"), (Key::F4, "f4"), (Key::F5, "f5"), (Key::F6, "f6"),
+    (Key::F7, "f7"), (Key::F8, "f8"), (Key::F9, "f9"), (Key::F10, "f10"), (Key::F11, "f11"), (Key::F12, "f12"),
+    (Key::F13, "f13"), (Key::F14, "f14"), (Key::F15, "f15"),
+    (Key::Down, "down"), (Key::Left, "left"), (Key::Right, "right"), (Key::Up, "up"),
+    (Key::Apostrophe, "'"), (Key::Backquote, "`"), (Key::Backslash, "\\"), (Key::Comma, ","), (Key::Equal, "="),
+    (Key::LeftBracket, "["), (Key::Minus, "-"), (Key::Period, "."), (Key::RightBracket, "]"),
+    (Key::Semicolon, ";"), (Key::Slash, "/"),
+    (Key::Backspace, "backspace"), (Key::Delete, "delete"), (Key::End, "end"), (Key::Enter, "enter"),
+    (Key::Escape, "escape"), (Key::Home, "home"), (Key::Insert, "insert"), (Key::Menu, "menu"),
+    (Key::PageDown, "pagedown"), (Key::PageUp, "pageup"), (Key::Pause, "pause"), (Key::Space, "space"),
+    (Key::Tab, "tab"), (Key::NumLock, "numlock"), (Key::CapsLock, "capslock"), (Key::ScrollLock, "scrolllock"),
+    (Key::LeftShift, "leftshift"), (Key::RightShift, "rightshift"), (Key::LeftCtrl, "leftctrl"),
+    (Key::RightCtrl, "rightctrl"),
+    (Key::NumPad0, "numpad0"), (Key::NumPad1, "numpad1"), (Key::NumPad2, "numpad2"), (Key::NumPad3, "numpad3"),
+    (Key::NumPad4, "numpad4"), (Key::NumPad5, "numpad5"), (Key::NumPad6, "numpad6"), (Key::NumPad7, "numpad7"),
+    (Key::NumPad8, "numpad8"), (Key::NumPad9, "numpad9"), (Key::NumPadDot, "numpaddot"),
+    (Key::NumPadSlash, "numpadslash"), (Key::NumPadAsterisk, "numpadasterisk"),
+    (Key::NumPadMinus, "numpadminus"), (Key::NumPadPlus, "numpadplus"), (Key::NumPadEnter, "numpadenter"),
+    (Key::LeftAlt, "leftalt"), (Key::RightAlt, "rightalt"), (Key::LeftSuper, "leftsuper"),
+    (Key::RightSuper, "rightsuper"),
+];
+
+fn window_key_name(key: Key) -> Option<&'static str> {
+    WINDOW_KEYS.iter().find(|(window_key, _)| *window_key == key).map(|(_, name)| *name)
+}
+
+// save slots are kept next to the rom: roms/PONG.state1
+fn save_slot_path(rom: &str, slot: usize) -> String {
+    format!("{}.state{}", rom, slot)
+}
+
+fn handle_save_slots(window: &Window, cpu: &mut Cpu, rom: &str) {
+    let saving = window.is_key_down(Key::LeftShift) || window.is_key_down(Key::RightShift);
+    for (index, key) in SAVE_SLOT_KEYS.iter().enumerate() {
+        if !window.is_key_pressed(*key, KeyRepeat::No) {
+            continue;
+        }
+        let path = save_slot_path(rom, index + 1);
+        if saving {
+            match fs::write(&path, cpu.save_state()) {
+                Ok(()) => println!("saved slot {} to {}", index + 1, path),
+                Err(e) => eprintln!("could not save slot {}: {}", index + 1, e),
+            }
+        } else {
+            let loaded = match fs::read(&path) {
+                Ok(state) => cpu.load_state(&state).map_err(|e| e.to_string()),
+                Err(e) => Err(e.to_string()),
+            };
+            match loaded {
+                Ok(()) => println!("loaded slot {} from {}", index + 1, path),
+                Err(e) => eprintln!("could not load slot {}: {}", index + 1, e),
+            }
+        }
+    }
+}
+
+// the ROM's bytes, or exit saying what is wrong with the file
+fn read_rom(rom: &str) -> Vec<u8> {
+    let fail = |message: String| -> ! {
+        eprintln!("{}", message);
+        process::exit(1);
+    };
+    match fs::read(rom) {
+        Ok(bytes) if bytes.is_empty() => fail(format!("{}: the file is empty", rom)),
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            // the bundled roms are in roms/, so `cargo run PONG` is an easy mistake to make
+            let bundled = Path::new("roms").join(rom);
+            if bundled.is_file() {
+                fail(format!("{}: no such file, did you mean {}?", rom, bundled.display()));
+            }
+            fail(format!("{}: no such file", rom))
+        }
+        Err(_) if Path::new(rom).is_dir() => fail(format!("{}: is a directory, not a rom", rom)),
+        Err(e) => fail(format!("{}: {}", rom, e)),
+    }
+}
+
+// the settings file's settings for the rom, with the command line's over them
+fn settings(args: &Args, rom: &[u8]) -> Settings {
+    // headless runs leave the settings file alone unless asked, so they are the same everywhere
+    let config = load_config(args.config.as_ref(), args.command != Command::Headless);
+    config.settings_for(rom).overridden_by(&args.settings)
+}
+
+fn load_address(args: &Args) -> u16 {
+    args.load_address.unwrap_or(MEMORY_START_INDEX as u16)
+}
+
+fn platform_name(platform: Platform) -> &'static str {
+    match platform {
+        Platform::Chip8 => "chip8",
+        Platform::SuperChip => "schip",
+        Platform::XoChip => "xochip",
+    }
+}
+
+// a CPU for the platform and quirks the settings pick, loading at the load address
+fn new_cpu(args: &Args, settings: &Settings) -> Cpu {
+    let mut cpu = match settings.platform {
+        Some(platform) => Cpu::with_platform(platform),
+        None => Cpu::new(),
+    };
+    let quirks = settings.apply_quirks(cpu.quirks());
+    cpu.set_quirks(quirks);
+    cpu.set_load_address(load_address(args));
+    cpu
+}
+
+// Octo source is compiled for the platform first: `cargo run game.8o --platform xochip`
+fn program(cpu: &Cpu, rom: &str, bytes: Vec<u8>) -> Vec<u8> {
+    if !rom.ends_with(".8o") {
+        return bytes;
+    }
+    compile_file(rom, cpu.platform()).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    })
+}
+
+fn load_program(cpu: &mut Cpu, rom: &str, program: &[u8]) {
+    match cpu.load_rom_bytes(program) {
+        Ok(()) => {}
+        Err(CpuError::RomTooLarge { size, max }) => {
+            let load_address = cpu.load_address();
+            eprint!("{}: the rom is {} bytes, but only {} bytes fit in memory from {:#05X}", rom, size, max, load_address);
+            // XO-CHIP has 64 KiB of memory for bigger roms
+            let xo_chip_max = Platform::XoChip.memory_size().saturating_sub(load_address as usize);
+            if cpu.platform() != Platform::XoChip && size <= xo_chip_max {
+                eprint!(", it fits with --platform xochip");
+            }
+            eprintln!();
+            process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("{}: {}", rom, e);
+            process::exit(1);
+        }
+    }
+}
+
+// `cargo run disasm roms/{rom}` prints a listing of the rom
+fn disasm(args: &Args) {
+    let rom = &args.files[0];
+    let bytes = read_rom(rom);
+    let platform = settings(args, &bytes).platform.unwrap_or_default();
+    let listing = disassemble_rom(&bytes, load_address(args), platform);
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    if let Err(e) = write!(stdout, "{}", listing).and_then(|()| stdout.flush()) {
+        // `cargo run disasm roms/PONG | head` stops reading early, which is not an error
+        if e.kind() != io::ErrorKind::BrokenPipe {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
+
+// Octo source is compiled like `cargo run game.8o` does, anything else is assembled
+fn build(source: &str, platform: Platform, load_address: u16) -> Result<Vec<u8>, AsmError> {
+    if source.ends_with(".8o") {
+        compile_file(source, platform)
+    } else {
+        assemble_file(source, load_address)
+    }
+}
+
+// `cargo run asm {source} [output]` assembles a rom, by default next to the source
+fn asm(args: &Args) {
+    let source = &args.files[0];
+    let output = args.files.get(1).map_or_else(|| Path::new(source).with_extension("ch8"), PathBuf::from);
+    // there is no rom to look up in the settings file yet, so only its defaults apply
+    let settings = load_config(args.config.as_ref(), true).defaults.overridden_by(&args.settings);
+    let rom = match build(source, settings.platform.unwrap_or_default(), load_address(args)) {
+        Ok(rom) => rom,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    if let Err(e) = fs::write(&output, &rom) {
+        eprintln!("{}: {}", output.display(), e);
+        process::exit(1);
+    }
+    println!("wrote {} bytes to {}", rom.len(), output.display());
+}
+
+// `cargo run info roms/{rom}` prints what a rom will be run with, without running it
+fn info(args: &Args) {
+    let rom = &args.files[0];
+    let bytes = read_rom(rom);
+    let hash = config::rom_hash(&bytes);
+    let settings = settings(args, &bytes);
+    let cpu = new_cpu(args, &settings);
+    let program = program(&cpu, rom, bytes);
+
+    println!("{}", rom);
+    let space = cpu.memory().len().saturating_sub(cpu.load_address() as usize);
+    if program.len() <= space {
+        println!("  size      {} bytes, {} free from {:#05X}", program.len(), space - program.len(), cpu.load_address());
+    } else {
+        println!("  size      {} bytes, {} too many to fit from {:#05X}", program.len(), program.len() - space, cpu.load_address());
+    }
+    println!("  hash      {}, its settings go in [rom.{}]", hash, hash);
+    // only a guess, sprites and other data can look like instructions from a later platform
+    let newest = program
+        .chunks_exact(2)
+        .map(|opcode| Instruction::decode((opcode[0] as u16) << 8 | opcode[1] as u16).platform())
+        .max_by_key(|platform| platform.id())
+        .unwrap_or_default();
+    println!("  opcodes   {} at the newest", platform_name(newest));
+    println!("  platform  {}", platform_name(cpu.platform()));
+    println!("  speed     {} instructions per frame", settings.speed.unwrap_or(INSTRUCTIONS_PER_FRAME));
+    let quirks = cpu.quirks().to_bits();
+    let on: Vec<&str> = QUIRK_NAMES.iter().enumerate().filter(|(bit, _)| quirks & 1 << bit != 0).map(|(_, name)| *name).collect();
+    println!("  quirks    {}", if on.is_empty() { String::from("none") } else { on.join(", ") });
+    println!("  keymap    {}", settings.keymap.as_deref().unwrap_or("qwerty"));
+    for binding in settings.bind.iter() {
+        println!("            {}", binding);
+    }
+}
+
+// `cargo run headless roms/{rom} --frames 600 --screen out.png` runs a rom without a window, for CI
+fn headless(args: &Args) {
+    let fail = |message: String| -> ! {
+        eprintln!("{}", message);
+        process::exit(2);
+    };
+
+    let rom = &args.files[0];
+    let bytes = read_rom(rom);
+    let settings = settings(args, &bytes);
+    let mut run = Headless::new(600, settings.speed.unwrap_or(INSTRUCTIONS_PER_FRAME));
+    let mut screen = None;
+    let mut registers = None;
+    for (name, value) in args.headless.iter() {
+        match name.as_str() {
+            "--frames" => run.frames = value.parse().unwrap_or_else(|_| fail(format!("invalid frame count {}", value))),
+            "--until" => run.stop.push(value.parse().unwrap_or_else(|e| fail(e))),
+            "--keys" => run.keys.extend(parse_key_script(value).unwrap_or_else(|e| fail(e))),
+            "--keys-file" => {
+                let script = fs::read_to_string(value).unwrap_or_else(|e| fail(format!("{}: {}", value, e)));
+                run.keys.extend(parse_key_script(&script).unwrap_or_else(|e| fail(format!("{}: {}", value, e))));
+            }
+            "--screen" => screen = Some(value),
+            "--registers" => registers = Some(value),
+            _ => fail(format!("unknown option {}", name)),
+        }
+    }
+
+    let mut cpu = new_cpu(args, &settings);
+    cpu.set_decode_cache(true);
+    // the same seed every run, so the output can be compared between runs
+    cpu.seed_random(args.seed.unwrap_or(0));
+    let program = program(&cpu, rom, bytes);
+    load_program(&mut cpu, rom, &program);
 
-fn get_chip8_keycode_for(key: Option<Key>) -> Option<u8> {
-    match key {
-        Some(Key::Key1) => Some(0x1),
-        Some(Key::Key2) => Some(0x2),
-        Some(Key::Key3) => Some(0x3),
-        Some(Key::Key4) => Some(0xC),
+    let outcome = match run.run(&mut cpu) {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            eprintln!("{}: {}", rom, e);
+            process::exit(1);
+        }
+    };
 
-        Some(Key::Q) => Some(0x4),
-        Some(Key::W) => Some(0x5),
-        Some(Key::E) => Some(0x6),
-        Some(Key::R) => Some(0xD),
+    // the extension picks the format, - prints ASCII art, and with no outputs asked for the
+    // display is printed
+    let write = |path: &str, contents: &[u8]| {
+        if let Err(e) = fs::write(path, contents) {
+            eprintln!("{}: {}", path, e);
+            process::exit(1);
+        }
+    };
+    let palette = settings.palette.unwrap_or(PALETTE);
+    match screen.map(String::as_str) {
+        Some("-") => print!("{}", to_ascii(&cpu)),
+        Some(path) if path.ends_with(".png") => write(path, &to_png(&cpu, &palette)),
+        Some(path) if path.ends_with(".pbm") => write(path, to_pbm(&cpu).as_bytes()),
+        Some(path) => write(path, to_ascii(&cpu).as_bytes()),
+        None if registers.is_none() => print!("{}", to_ascii(&cpu)),
+        None => {}
+    }
+    match registers.map(String::as_str) {
+        Some("-") => print!("{}", registers_json(&cpu, &outcome)),
+        Some(path) => write(path, registers_json(&cpu, &outcome).as_bytes()),
+        None => {}
+    }
+}
 
-        Some(Key::A) => Some(0x7),
-        Some(Key::S) => Some(0x8),
-        Some(Key::D) => Some(0x9),
-        Some(Key::F) => Some(0xE),
+// read debugger commands on another thread, so the window keeps rendering while we wait for them
+fn spawn_prompt() -> Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            match line {
+                Ok(line) => if sender.send(line).is_err() {
+                    break;
+                },
+                Err(_) => break,
+            }
+        }
+    });
+    receiver
+}
 
-        Some(Key::Z) => Some(0xA),
-        Some(Key::X) => Some(0x0),
-        Some(Key::C) => Some(0xB),
-        Some(Key::V) => Some(0xF),
-        _ => None,
+// the qwerty preset unless another preset or a keymap file was picked, with any extra bindings
+fn keymap(settings: &Settings) -> Keymap {
+    let mut keymap = match &settings.keymap {
+        Some(name) => Keymap::load(name).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(2);
+        }),
+        None => Keymap::preset("qwerty").unwrap_or_default(),
+    };
+    for binding in settings.bind.iter() {
+        if let Err(e) = keymap.apply(binding) {
+            eprintln!("bind {}: {}", binding, e);
+            process::exit(2);
+        }
     }
+    keymap
+}
+
+// the settings file given with --config, or when search is set the one in the config directory
+// if there is one
+fn load_config(path: Option<&String>, search: bool) -> Config {
+    let path = match path {
+        Some(path) => PathBuf::from(path),
+        None => match config::default_path() {
+            Some(path) if search && path.exists() => path,
+            _ => return Config::default(),
+        },
+    };
+    Config::load(&path).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    })
+}
+
+fn print_prompt() {
+    print!("(debug) ");
+    let _ = io::stdout().flush();
 }
 
 fn main() {
-    let width = 640;
-    let height = 320;
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.is_empty() {
+        eprint!("{}", cli::usage());
+        process::exit(2);
+    }
+    let args = cli::parse(args).unwrap_or_else(|e| {
+        eprintln!("{}\nrun `rusty_chip help` for the commands and options", e);
+        process::exit(2);
+    });
+    match args.command {
+        Command::Run | Command::Debug => play(&args),
+        Command::Info => info(&args),
+        Command::Disasm => disasm(&args),
+        Command::Asm => asm(&args),
+        Command::Headless => headless(&args),
+        Command::Help => print!("{}", cli::usage()),
+    }
+}
+
+// `cargo run roms/{rom}` plays a rom in a window, `cargo run debug roms/{rom}` starts it paused with
+// a debugger prompt on the terminal, and --terminal plays in the terminal instead, with debug as
+// well it opens the full screen debugger
+fn play(args: &Args) {
+    let rom = &args.files[0];
+    let debug = args.command == Command::Debug;
+    let in_terminal = args.terminal;
+
+    let bytes = read_rom(rom);
+    // the settings file's defaults, with the ROM's own table and then the command line over them
+    let settings = settings(args, &bytes);
+    let instructions_per_frame = settings.speed.unwrap_or(INSTRUCTIONS_PER_FRAME);
+    let palette = settings.palette.unwrap_or(PALETTE);
+    let keymap = keymap(&settings);
+
+    // my CPU, optionally emulating a later platform and loading somewhere other than 0x200:
+    // `cargo run roms/{rom} --platform schip --load-address 0x600`
+    let mut cpu = new_cpu(args, &settings);
+    cpu.set_decode_cache(true);
+    cpu.set_block_engine(true);
+
+    // games should play out differently each time, tests and replays pick a fixed seed
+    let seed = SystemTime::now().duration_since(UNIX_EPOCH).map(|t| t.as_nanos() as u64).unwrap_or(0);
+    cpu.seed_random(args.seed.unwrap_or(seed));
+
+    let program = program(&cpu, rom, bytes);
+    load_program(&mut cpu, rom, &program);
+
+    if in_terminal && debug {
+        if let Err(e) = tui::run(&mut cpu, rom, instructions_per_frame, FRAME_DURATION, &palette, &keymap) {
+            eprintln!("terminal: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+    if in_terminal {
+        match terminal::run(&mut cpu, rom, instructions_per_frame, FRAME_DURATION, &palette, &keymap) {
+            Ok(None) => {}
+            Ok(Some(e)) => {
+                eprintln!("{}: {}", rom, e);
+                process::exit(1);
+            }
+            Err(e) => {
+                eprintln!("terminal: {}", e);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    // the window fits the lo-res display scale times over
+    let scale = settings.scale.unwrap_or(WINDOW_SCALE);
+    let width = LORES_WIDTH * scale;
+    let height = LORES_HEIGHT * scale;
 
     //ARGB buffer
     let mut buffer: Vec<u32> = vec![0; width * height];
@@ -50,66 +495,156 @@ fn main() {
         panic!("Window creation failed: {:?}", e);
     });
 
+    // set when the CPU faults, the emulation stops but the window stays open
+    let mut crashed = false;
+    // shown in the title while FX0A waits for a key
+    let mut waiting_for_key = false;
 
-    // my CPU
-    let mut cpu =  Cpu::new();
+    let mut rewind = RewindBuffer::new(settings.rewind_budget.unwrap_or(REWIND_BUDGET_MB) * 1024 * 1024);
+    rewind.push(&cpu);
 
-    // TODO: add logic to load game into cpu memory
-    let args: Vec<String> = env::args().collect();
-    let rom = &args[1];
+    let mut debugger = if debug {
+        println!("paused at {}type help for the debugger commands", current_instruction(&cpu));
+        print_prompt();
+        Some((Debugger::new(), spawn_prompt()))
+    } else {
+        None
+    };
 
-    //println!("{} rom loaded", rom);
-    cpu.load_game(rom);
-    
-    let mut last_key_update_time = Instant::now();
-    let mut last_instruction_run_time = Instant::now();
-    let mut last_display_time = Instant::now();
+    let mut last_frame_time = Instant::now();
 
     // begin executing instructions and updating the display
-    while window.is_open() && !window.is_key_down(Key::Escape) {
-        let keys_pressed = window.get_keys_pressed(KeyRepeat::Yes);
-        let key = match keys_pressed {
-            Some(keys) => if !keys.is_empty() {
-                Some(keys[0])
-            } else {
-                None
-            },
-            None => None,
-        };
-
-        let chip8_key = get_chip8_keycode_for(key);
-        if chip8_key.is_some() || Instant::now() - last_key_update_time >= Duration::from_millis(200)
-        {
-            cpu.keyboard.reset();
-            last_key_update_time = Instant::now();
-            if let Some(key) = chip8_key {
-                cpu.keyboard.press_key(key)
+    while window.is_open() && !window.is_key_down(Key::Escape) && !cpu.has_exited() {
+        // every key held down right now, so held keys stay down and both players of a two
+        // player game register
+        let mut held = [false; 16];
+        for key in window.get_keys().unwrap_or_default() {
+            if let Some(key) = window_key_name(key).and_then(|name| keymap.key_for(name)) {
+                held[key as usize] = true;
             }
         }
+        cpu.keyboard.set_held(held);
 
-        if Instant::now() - last_instruction_run_time > Duration::from_millis(2) {
-            cpu.execute_cycle();
-            last_instruction_run_time = Instant::now();
+        if !crashed {
+            handle_save_slots(&window, &mut cpu, rom);
         }
 
-        if Instant::now() - last_display_time > Duration::from_millis(10) {
+        if let Some((debugger, prompt)) = &mut debugger {
+            for line in prompt.try_iter() {
+                print!("{}", debugger.command(&mut cpu, &line));
+                print_prompt();
+            }
+        }
+
+        if Instant::now() - last_frame_time >= FRAME_DURATION {
+            if window.is_key_down(REWIND_KEY) {
+                // step back one frame per frame, rewinding out of a crash resumes the game
+                match rewind.rewind(&mut cpu) {
+                    Ok(true) if crashed => {
+                        crashed = false;
+                        cpu.display.mark_dirty();
+                        window.set_title("Rust Chip8 emulator");
+                    }
+                    Ok(_) => {}
+                    Err(e) => eprintln!("could not rewind: {}", e),
+                }
+            } else if !crashed {
+                let result = match &mut debugger {
+                    Some((debugger, _)) if debugger.is_paused() => Ok(false),
+                    Some((debugger, _)) => debugger.run_frame(&mut cpu, instructions_per_frame).map(|hit| {
+                        if let Some(hit) = hit {
+                            print!("\n{}\n{}", hit, current_instruction(&cpu));
+                            print_prompt();
+                        }
+                        true
+                    }),
+                    None => cpu.run_frame(instructions_per_frame).map(|_| true),
+                };
+                match result {
+                    Ok(ran) => if ran {
+                        rewind.push(&cpu);
+                    },
+                    Err(e) => {
+                        eprintln!("{}: {}", rom, e);
+                        window.set_title(&format!("Rust Chip8 emulator - crashed: {}", e));
+                        cpu.display.mark_dirty();
+                        crashed = true;
+                    }
+                }
+            }
+            if !crashed && cpu.is_waiting_for_key() != waiting_for_key {
+                waiting_for_key = cpu.is_waiting_for_key();
+                window.set_title(if waiting_for_key { "Rust Chip8 emulator - press a key" } else { "Rust Chip8 emulator" });
+            }
+
+            // scale whichever resolution the CPU is in to the window
+            let palette = if crashed { &CRASH_PALETTE } else { &palette };
+            // only the rows that changed since the last frame are drawn again
+            let dirty_rows = cpu.display.take_dirty_rows();
             for y in 0..height {
-                let y_coord = y / 10;
+                let y_coord = y * cpu.height() / height;
+                if dirty_rows & (1 << y_coord) == 0 {
+                    continue;
+                }
                 for x in 0..width {
-                    let x_cord = x / 10;
-                    let pixel = cpu.display[y_coord][x_cord];
-                    let color_pixel = match pixel {
-                        0 => 0x0,
-                        1 => 0xffffff,
-                        _ => {println!("pixel value {}", pixel); 0x0},
-                    };
-                    buffer[(y * width) + x] = color_pixel;
+                    let x_cord = x * cpu.width() / width;
+                    let pixel = cpu.display.pixel(x_cord, y_coord);
+                    buffer[(y * width) + x] = palette[pixel as usize];
                 }
             }
     
             let _ = window.update_with_buffer(&buffer);
-            last_display_time = Instant::now();
+            last_frame_time = Instant::now();
         }
         
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keymap::{host_key_name, reserved, PRESETS};
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn window_keys_have_keymap_names() {
+        let names: BTreeSet<&str> = WINDOW_KEYS.iter().map(|(_, name)| *name).collect();
+        assert_eq!(names.len(), WINDOW_KEYS.len(), "every key has its own name");
+        for name in names.iter() {
+            assert_eq!(host_key_name(name), *name, "{} is how keymaps spell it", name);
+        }
+        assert_eq!(window_key_name(Key::Key1), Some("1"));
+        assert_eq!(window_key_name(Key::Semicolon), Some(";"));
+        assert_eq!(window_key_name(Key::Unknown), None);
+
+        // keymaps can not take the window's own keys
+        for key in SAVE_SLOT_KEYS.iter().chain([REWIND_KEY, Key::Escape].iter()) {
+            assert!(window_key_name(*key).and_then(reserved).is_some(), "{:?} is reserved", key);
+        }
+
+        // every key a preset binds can be pressed in the window
+        for preset in PRESETS.iter() {
+            let keymap = Keymap::preset(preset).unwrap();
+            for key in 0..16 {
+                for host in keymap.host_keys(key) {
+                    assert!(names.contains(host), "{} binds {}, which the window has no key for", preset, host);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn asm_compiles_octo_sources() {
+        let dir = env::temp_dir().join("rusty_chip_asm_octo");
+        fs::create_dir_all(&dir).unwrap();
+        let octo = dir.join("x.8o");
+        let assembler = dir.join("x.asm");
+        fs::write(&octo, ": main\n  v0 := 5\n  hires\n").unwrap();
+        fs::write(&assembler, "LD V0, 5\n").unwrap();
+
+        let octo = octo.to_str().unwrap();
+        assert_eq!(build(octo, Platform::SuperChip, 0x200).unwrap(), vec![0x60, 0x05, 0x00, 0xFF]);
+        assert!(build(octo, Platform::Chip8, 0x200).unwrap_err().message.contains("platform"), "for the platform given");
+        assert_eq!(build(assembler.to_str().unwrap(), Platform::Chip8, 0x200).unwrap(), vec![0x60, 0x05]);
+    }
+}
@@ -0,0 +1,192 @@
+use cpu::cpu::Cpu;
+use cpu::error::CpuError;
+use crate::keymap::Keymap;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+use termion::color::{Bg, Fg, Rgb};
+use termion::event::Key;
+use termion::input::TermRead;
+use termion::raw::IntoRawMode;
+use termion::screen::AlternateScreen;
+use termion::{async_stdin, clear, cursor, style};
+
+// Plays a rom in the terminal, for machines without a display, e.g. over SSH. Each character
+// cell shows two pixels, one above the other, using the upper half block: the foreground colour
+// is the top pixel and the background colour the bottom one. The 64x32 display fits in 64x16
+// cells, hi-res in 128x32.
+
+// terminals only report key presses, so a key counts as held this long after it last arrived.
+// A held key is sent once, then repeated after a delay of 250 to 660ms depending on the terminal,
+// and this has to bridge that gap or the key flickers up and down until the repeats start.
+pub const KEY_HOLD: Duration = Duration::from_millis(700);
+
+// a long sound rings the bell again this often
+const BELL_INTERVAL: Duration = Duration::from_millis(250);
+
+// what a keymap calls a key typed in the terminal, None for keys a keymap cannot bind
+pub fn key_name(key: &Key) -> Option<String> {
+    let name = match key {
+        Key::Char(' ') => "space",
+        Key::Char('\n') => "enter",
+        Key::Char('\t') => "tab",
+        Key::Char(c) => return Some(c.to_string()),
+        Key::Up => "up",
+        Key::Down => "down",
+        Key::Left => "left",
+        Key::Right => "right",
+        Key::Backspace => "backspace",
+        _ => return None,
+    };
+    Some(String::from(name))
+}
+
+// the CHIP-8 key held down. Only the last key pressed repeats, so a different key arriving
+// means the earlier one was let go and it is released straight away rather than after KEY_HOLD.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HeldKey {
+    last: Option<(u8, Instant)>,
+}
+
+impl HeldKey {
+    pub fn press(&mut self, key: u8, now: Instant) {
+        self.last = Some((key, now));
+    }
+
+    pub fn key(&self, now: Instant) -> Option<u8> {
+        self.last.filter(|(_, time)| now.saturating_duration_since(*time) < KEY_HOLD).map(|(key, _)| key)
+    }
+}
+
+fn rgb(colour: u32) -> Rgb {
+    Rgb((colour >> 16) as u8, (colour >> 8) as u8, colour as u8)
+}
+
+// draw the display as rows of half blocks from the top left of the terminal, only changing
+// colour when a cell needs it
+pub fn render(cpu: &Cpu, palette: &[u32; 4]) -> String {
+    let mut frame = String::new();
+    for row in 0..cpu.height() / 2 {
+        let _ = write!(frame, "{}", cursor::Goto(1, row as u16 + 1));
+        let mut colours = None;
+        for x in 0..cpu.width() {
+            let top = palette[cpu.display.pixel(x, row * 2) as usize];
+            let bottom = palette[cpu.display.pixel(x, row * 2 + 1) as usize];
+            if colours != Some((top, bottom)) {
+                let _ = write!(frame, "{}{}", Fg(rgb(top)), Bg(rgb(bottom)));
+                colours = Some((top, bottom));
+            }
+            frame.push('▀');
+        }
+        let _ = write!(frame, "{}{}", style::Reset, clear::UntilNewline);
+    }
+    frame
+}
+
+// runs until escape is pressed or the program exits, returns the fault if the CPU crashed
+pub fn run(
+    cpu: &mut Cpu,
+    title: &str,
+    instructions_per_frame: usize,
+    frame_duration: Duration,
+    palette: &[u32; 4],
+    keymap: &Keymap,
+) -> io::Result<Option<CpuError>> {
+    let mut screen = AlternateScreen::from(io::stdout().into_raw_mode()?);
+    write!(screen, "{}{}", cursor::Hide, clear::All)?;
+
+    let mut keys = async_stdin().keys();
+    let mut held = HeldKey::default();
+    let mut last_frame = String::new();
+    let mut last_size = (0, 0);
+    let mut last_bell: Option<Instant> = None;
+    let mut crash = None;
+
+    while !cpu.has_exited() {
+        let frame_start = Instant::now();
+
+        for key in keys.by_ref() {
+            match key? {
+                Key::Esc | Key::Ctrl('c') => {
+                    write!(screen, "{}", cursor::Show)?;
+                    return Ok(crash);
+                }
+                key => {
+                    if let Some(key) = key_name(&key).and_then(|name| keymap.key_for(&name)) {
+                        held.press(key, frame_start);
+                    }
+                }
+            }
+        }
+        cpu.keyboard.reset();
+        if let Some(key) = held.key(frame_start) {
+            cpu.keyboard.press_key(key);
+        }
+
+        // once the CPU crashes the last frame stays up with the fault under it
+        if crash.is_none() {
+            if let Err(e) = cpu.run_frame(instructions_per_frame) {
+                crash = Some(e);
+            }
+        }
+
+        if cpu.sound_timer() == 0 {
+            last_bell = None;
+        } else if last_bell.is_none_or(|time| frame_start - time >= BELL_INTERVAL) {
+            write!(screen, "\x07")?;
+            last_bell = Some(frame_start);
+        }
+
+        // switching between lo-res and hi-res leaves the old frame around the new one
+        if (cpu.width(), cpu.height()) != last_size {
+            write!(screen, "{}", clear::All)?;
+            last_size = (cpu.width(), cpu.height());
+            last_frame.clear();
+        }
+        // nothing to draw until a sprite, clear or scroll changes the display
+        if cpu.display.take_dirty_rows() != 0 || last_frame.is_empty() {
+            let frame = render(cpu, palette);
+            if frame != last_frame {
+                write!(screen, "{}", frame)?;
+                last_frame = frame;
+            }
+        }
+        let status = match &crash {
+            Some(e) => format!("crashed: {} - escape quits", e),
+            None if cpu.is_waiting_for_key() => format!("{} - press a key - escape quits", title),
+            None => format!("{} - escape quits", title),
+        };
+        let status_row = cpu.height() as u16 / 2 + 1;
+        write!(screen, "{}{}{}", cursor::Goto(1, status_row), status, clear::UntilNewline)?;
+        screen.flush()?;
+
+        if let Some(remaining) = frame_duration.checked_sub(frame_start.elapsed()) {
+            thread::sleep(remaining);
+        }
+    }
+
+    write!(screen, "{}", cursor::Show)?;
+    Ok(crash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_stay_held_until_the_terminal_repeats_them() {
+        let start = Instant::now();
+        let mut held = HeldKey::default();
+        assert_eq!(held.key(start), None);
+
+        held.press(5, start);
+        assert_eq!(held.key(start + Duration::from_millis(300)), Some(5), "before the first repeat");
+        held.press(5, start + Duration::from_millis(500));
+        assert_eq!(held.key(start + Duration::from_millis(1000)), Some(5));
+        assert_eq!(held.key(start + Duration::from_millis(1200)), None, "let go");
+
+        held.press(8, start + Duration::from_millis(1300));
+        assert_eq!(held.key(start + Duration::from_millis(1300)), Some(8), "a different key releases the last");
+    }
+}
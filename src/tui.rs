@@ -0,0 +1,349 @@
+use crate::terminal::{key_name, render, HeldKey};
+use cpu::cpu::Cpu;
+use cpu::debugger::{instruction_text, memory_accesses, Break, Debugger};
+use cpu::error::CpuError;
+use crate::keymap::Keymap;
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::thread;
+use std::time::{Duration, Instant};
+use termion::event::Key;
+use termion::input::TermRead;
+use termion::raw::IntoRawMode;
+use termion::screen::AlternateScreen;
+use termion::{async_stdin, clear, cursor, style};
+
+// The debugger as a full screen terminal view, for picking apart a rom while it runs. The display
+// sits at the top, drawn like the terminal frontend, and under it are three panels: the code
+// around the cursor, the registers and call stack, and memory with the bytes the next instruction
+// touches highlighted. Under those are the last few debugger messages and a command line that
+// takes the same commands as the `--debug` prompt.
+
+const CODE_COLUMN: u16 = 1;
+const CODE_WIDTH: usize = 36;
+const REGISTER_COLUMN: u16 = 39;
+const REGISTER_WIDTH: usize = 19;
+const MEMORY_COLUMN: u16 = 60;
+const PANEL_ROWS: usize = 16;
+const LOG_ROWS: usize = 4;
+
+// bytes in a row of the memory panel
+const MEMORY_ROW: usize = 16;
+
+// instructions shown above the cursor
+const CODE_CONTEXT: u16 = 4;
+
+const KEYS: &str = "F5 run/pause  F7 step  F8 step over  F6 step out  F9 breakpoint  F4 run to cursor  : command";
+
+struct Tui {
+    debugger: Debugger,
+
+    // the selected instruction in the code panel, follows the pc whenever it stops
+    cursor: u16,
+
+    // first address of the memory panel, None keeps it around I
+    memory_start: Option<usize>,
+
+    log: VecDeque<String>,
+
+    // which typed keys press the hex keys
+    keymap: Keymap,
+
+    // the command line while one is being typed
+    command: Option<String>,
+}
+
+impl Tui {
+    fn new(cpu: &Cpu, keymap: Keymap) -> Tui {
+        let mut tui = Tui {
+            debugger: Debugger::new(),
+            cursor: cpu.pc(),
+            memory_start: None,
+            log: VecDeque::new(),
+            keymap,
+            command: None,
+        };
+        tui.log(String::from("paused, F5 runs the program and : takes debugger commands"));
+        tui
+    }
+
+    fn log(&mut self, line: String) {
+        if self.log.len() == LOG_ROWS {
+            self.log.pop_front();
+        }
+        self.log.push_back(line);
+    }
+
+    // show why execution stopped, and move the cursor to where it did
+    fn report(&mut self, cpu: &Cpu, result: Result<Option<Break>, CpuError>) {
+        match result {
+            Ok(None) => return,
+            // the pc marker already shows a step
+            Ok(Some(Break::Stepped(_))) => {}
+            Ok(Some(hit)) => self.log(hit.to_string()),
+            Err(e) => self.log(format!("crashed: {}", e)),
+        }
+        self.cursor = cpu.pc();
+    }
+
+    // handle a key, false to quit
+    fn key(&mut self, cpu: &mut Cpu, key: Key, held: &mut HeldKey, now: Instant) -> bool {
+        if let Some(mut line) = self.command.take() {
+            match key {
+                Key::Char('\n') => {
+                    let output = self.debugger.command(cpu, &line);
+                    self.log(format!(": {}", line));
+                    for output in output.lines() {
+                        self.log(output.to_string());
+                    }
+                    self.cursor = cpu.pc();
+                }
+                Key::Esc => {}
+                Key::Backspace => {
+                    line.pop();
+                    self.command = Some(line);
+                }
+                Key::Char(c) => {
+                    line.push(c);
+                    self.command = Some(line);
+                }
+                _ => self.command = Some(line),
+            }
+            return true;
+        }
+
+        match key {
+            Key::Esc | Key::Ctrl('c') => return false,
+            Key::Char(':') => self.command = Some(String::new()),
+            Key::F(5) if self.debugger.is_paused() => self.debugger.resume(),
+            Key::F(5) => {
+                self.debugger.pause();
+                self.cursor = cpu.pc();
+            }
+            Key::F(7) => {
+                let result = self.debugger.step(cpu).map(Some);
+                self.report(cpu, result);
+            }
+            Key::F(8) => {
+                let result = self.debugger.step_over(cpu);
+                self.report(cpu, result);
+            }
+            Key::F(6) => self.step_out(cpu),
+            Key::F(9) => self.toggle_breakpoint(),
+            Key::F(4) => self.debugger.run_to(self.cursor),
+            Key::Up => self.cursor = self.cursor.saturating_sub(2),
+            Key::Down => self.cursor = self.cursor.wrapping_add(cpu.instruction_size(self.cursor)),
+            Key::PageUp => self.memory_start = Some(self.memory_start(cpu).saturating_sub(PANEL_ROWS * MEMORY_ROW)),
+            Key::PageDown => {
+                let start = self.memory_start(cpu) + PANEL_ROWS * MEMORY_ROW;
+                if start < cpu.memory().len() {
+                    self.memory_start = Some(start);
+                }
+            }
+            Key::Home => self.memory_start = None,
+            key => {
+                if let Some(key) = key_name(&key).and_then(|name| self.keymap.key_for(&name)) {
+                    held.press(key, now);
+                }
+            }
+        }
+        true
+    }
+
+    fn step_out(&mut self, cpu: &Cpu) {
+        if !self.debugger.step_out(cpu) {
+            self.log(String::from("not in a subroutine"));
+        }
+    }
+
+    fn toggle_breakpoint(&mut self) {
+        if !self.debugger.add_breakpoint(self.cursor) {
+            self.debugger.remove_breakpoint(self.cursor);
+        }
+    }
+
+    // I's row, with the one before it for context
+    fn memory_start(&self, cpu: &Cpu) -> usize {
+        self.memory_start.unwrap_or_else(|| (cpu.i() as usize / MEMORY_ROW).saturating_sub(1) * MEMORY_ROW)
+    }
+
+    fn draw(&self, cpu: &Cpu, title: &str, palette: &[u32; 4]) -> String {
+        let mut frame = render(cpu, palette);
+        let status_row = cpu.height() as u16 / 2 + 1;
+        let status = if cpu.has_exited() {
+            String::from("exited")
+        } else if self.debugger.is_paused() {
+            format!("paused at {:03X}", cpu.pc())
+        } else {
+            String::from("running")
+        };
+        let _ = write!(frame, "{}{} - {}{}", cursor::Goto(1, status_row), title, status, clear::UntilNewline);
+
+        let top = status_row + 1;
+        self.draw_code(&mut frame, cpu, top);
+        self.draw_registers(&mut frame, cpu, top);
+        self.draw_memory(&mut frame, cpu, top);
+
+        let log_row = top + PANEL_ROWS as u16 + 1;
+        for row in 0..LOG_ROWS {
+            let line = self.log.get(row).map(String::as_str).unwrap_or("");
+            let _ = write!(frame, "{}{}{}", cursor::Goto(1, log_row + row as u16), line, clear::UntilNewline);
+        }
+        let command_row = log_row + LOG_ROWS as u16;
+        let _ = match &self.command {
+            Some(line) => write!(frame, "{}:{}{}", cursor::Goto(1, command_row), line, clear::UntilNewline),
+            None => write!(frame, "{}{}{}", cursor::Goto(1, command_row), KEYS, clear::UntilNewline),
+        };
+        frame
+    }
+
+    fn draw_code(&self, frame: &mut String, cpu: &Cpu, top: u16) {
+        let breakpoints: Vec<u16> = self.debugger.breakpoints().copied().collect();
+        let mut addr = self.cursor.saturating_sub(2 * CODE_CONTEXT);
+        for row in 0..PANEL_ROWS {
+            let _ = write!(frame, "{}", cursor::Goto(CODE_COLUMN, top + row as u16));
+            let line = match (cpu.opcode_at(addr), instruction_text(cpu, addr)) {
+                (Some(opcode), Some(text)) => {
+                    let marker = if addr == cpu.pc() { '>' } else { ' ' };
+                    let breakpoint = if breakpoints.contains(&addr) { '*' } else { ' ' };
+                    format!("{}{} {:03X}: {:04X}  {}", marker, breakpoint, addr, opcode, text)
+                }
+                _ => String::new(),
+            };
+            let line = format!("{:<width$.width$}", line, width = CODE_WIDTH);
+            if addr == self.cursor {
+                let _ = write!(frame, "{}{}{}", style::Invert, line, style::Reset);
+            } else {
+                frame.push_str(&line);
+            }
+            addr = addr.wrapping_add(cpu.instruction_size(addr));
+        }
+    }
+
+    fn draw_registers(&self, frame: &mut String, cpu: &Cpu, top: u16) {
+        let v = cpu.registers();
+        let stack = cpu.call_stack();
+        let mut lines = vec![
+            format!("PC {:03X}   I {:03X}", cpu.pc(), cpu.i()),
+            format!("DT {:<3}   ST {}", cpu.delay_timer(), cpu.sound_timer()),
+            String::new(),
+        ];
+        for x in 0..8 {
+            lines.push(format!("V{:X} {:02X}    V{:X} {:02X}", x, v[x], x + 8, v[x + 8]));
+        }
+        lines.push(String::new());
+        lines.push(format!("stack ({})", stack.len()));
+        // the innermost calls, as many as fit
+        let room = PANEL_ROWS - lines.len();
+        lines.extend(stack.iter().rev().take(room).map(|addr| format!("  {:03X}", addr)));
+
+        for row in 0..PANEL_ROWS {
+            let line = lines.get(row).map(String::as_str).unwrap_or("");
+            let _ = write!(
+                frame,
+                "{}{:<width$.width$}",
+                cursor::Goto(REGISTER_COLUMN, top + row as u16),
+                line,
+                width = REGISTER_WIDTH
+            );
+        }
+    }
+
+    fn draw_memory(&self, frame: &mut String, cpu: &Cpu, top: u16) {
+        let memory = cpu.memory();
+        let highlighted = highlighted(cpu);
+        let start = self.memory_start(cpu);
+        for row in 0..PANEL_ROWS {
+            let _ = write!(frame, "{}", cursor::Goto(MEMORY_COLUMN, top + row as u16));
+            let row_start = start + row * MEMORY_ROW;
+            if row_start < memory.len() {
+                let _ = write!(frame, "{:03X}:", row_start);
+                let row_end = (row_start + MEMORY_ROW).min(memory.len());
+                for (addr, byte) in (row_start..row_end).zip(&memory[row_start..row_end]) {
+                    if highlighted.contains(&addr) {
+                        let _ = write!(frame, " {}{:02X}{}", style::Invert, byte, style::Reset);
+                    } else {
+                        let _ = write!(frame, " {:02X}", byte);
+                    }
+                }
+            }
+            let _ = write!(frame, "{}", clear::UntilNewline);
+        }
+    }
+}
+
+// the memory the next instruction reads or writes, or just the byte at I when it does neither
+fn highlighted(cpu: &Cpu) -> Range<usize> {
+    let i = cpu.i() as usize;
+    let (reads, writes) = match cpu.opcode_at(cpu.pc()) {
+        Some(opcode) => memory_accesses(cpu, opcode),
+        None => (0..0, 0..0),
+    };
+    match (reads.is_empty(), writes.is_empty()) {
+        (false, _) => reads,
+        (true, false) => writes,
+        (true, true) => i..i + 1,
+    }
+}
+
+// Runs the rom under the debugger until escape is pressed. It starts paused. The panels need a
+// terminal at least 111 columns wide, and 39 rows tall for lo-res games or 55 for hi-res ones.
+pub fn run(
+    cpu: &mut Cpu,
+    title: &str,
+    instructions_per_frame: usize,
+    frame_duration: Duration,
+    palette: &[u32; 4],
+    keymap: &Keymap,
+) -> io::Result<()> {
+    let mut screen = AlternateScreen::from(io::stdout().into_raw_mode()?);
+    write!(screen, "{}{}", cursor::Hide, clear::All)?;
+
+    let mut keys = async_stdin().keys();
+    let mut held = HeldKey::default();
+    let mut tui = Tui::new(cpu, keymap.clone());
+    let mut last_frame = String::new();
+    let mut last_size = (0, 0);
+
+    loop {
+        let frame_start = Instant::now();
+
+        for key in keys.by_ref() {
+            if !tui.key(cpu, key?, &mut held, frame_start) {
+                write!(screen, "{}", cursor::Show)?;
+                return Ok(());
+            }
+        }
+        cpu.keyboard.reset();
+        if let Some(key) = held.key(frame_start) {
+            cpu.keyboard.press_key(key);
+        }
+
+        if !tui.debugger.is_paused() {
+            let result = tui.debugger.run_frame(cpu, instructions_per_frame);
+            tui.report(cpu, result);
+            if !tui.debugger.is_paused() {
+                tui.cursor = cpu.pc();
+            }
+        }
+
+        // the panels sit under the display, so they move when the resolution changes
+        if (cpu.width(), cpu.height()) != last_size {
+            write!(screen, "{}", clear::All)?;
+            last_size = (cpu.width(), cpu.height());
+            last_frame.clear();
+        }
+        let frame = tui.draw(cpu, title, palette);
+        if frame != last_frame {
+            write!(screen, "{}", frame)?;
+            last_frame = frame;
+        }
+        screen.flush()?;
+
+        if let Some(remaining) = frame_duration.checked_sub(frame_start.elapsed()) {
+            thread::sleep(remaining);
+        }
+    }
+}